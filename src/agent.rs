@@ -0,0 +1,251 @@
+use std::env::current_exe;
+use std::process::{Command, exit};
+
+use tokengen::profile::{AADToken, Profile, Renewal};
+
+use crate::crypt;
+
+#[cfg(target_os = "macos")]
+const AGENT_LABEL: &str = "com.0d0a.tokengen.agent";
+
+#[cfg(target_os = "windows")]
+const TASK_NAME: &str = "tokengen-agent";
+
+// The command line the agent runs on each tick
+fn agent_args(profiles: &[&str], interval: u64) -> Vec<String> {
+    let exe = match current_exe() {
+        Ok(p) => p.to_string_lossy().to_string(),
+        Err(e) => {
+            print_error!("Unable to locate the tokengen executable, error is {:#?}.", e);
+            exit(1);
+        }
+    };
+    let mut args = vec![exe, String::from("agent"), String::from("run"), String::from("--interval"), interval.to_string()];
+    if !profiles.is_empty() {
+        args.push(String::from("--profiles"));
+        args.push(profiles.join(","));
+    }
+    args
+}
+
+#[cfg(target_os = "macos")]
+fn plist_path() -> std::path::PathBuf {
+    let mut path = dirs::home_dir().unwrap();
+    path.push("Library");
+    path.push("LaunchAgents");
+    path.push(format!("{}.plist", AGENT_LABEL));
+    path
+}
+
+#[cfg(target_os = "macos")]
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(target_os = "macos")]
+pub fn install(profiles: &[&str], interval: u64) {
+    let mut log_filename = tokengen::profile::cache_root().unwrap();
+    log_filename.push("agent.log");
+
+    let args: Vec<String> = agent_args(profiles, interval).iter()
+        .map(|a| format!("        <string>{}</string>", xml_escape(a)))
+        .collect();
+    let plist = format!(r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>Label</key>
+    <string>{}</string>
+    <key>ProgramArguments</key>
+    <array>
+{}
+    </array>
+    <key>StartInterval</key>
+    <integer>{}</integer>
+    <key>RunAtLoad</key>
+    <true/>
+    <key>StandardErrorPath</key>
+    <string>{}</string>
+</dict>
+</plist>
+"#, AGENT_LABEL, args.join("\n"), interval * 60, xml_escape(&log_filename.to_string_lossy()));
+
+    let path = plist_path();
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).unwrap_or_default();
+    }
+    // Reload if it's already installed
+    Command::new("launchctl").arg("unload").arg(&path).output().ok();
+    match std::fs::write(&path, plist) {
+        Ok(_) => (),
+        Err(e) => {
+            print_error!("Unable to write launch agent at '{}', error is {:#?}.", path.to_string_lossy(), e);
+            exit(1);
+        }
+    }
+    match Command::new("launchctl").arg("load").arg("-w").arg(&path).status() {
+        Ok(s) if s.success() => eprintln!("Launch agent installed at '{}'.", path.to_string_lossy()),
+        _ => {
+            print_error!("Unable to load launch agent '{}'.", path.to_string_lossy());
+            exit(1);
+        }
+    }
+}
+
+#[cfg(target_os = "macos")]
+pub fn uninstall() {
+    let path = plist_path();
+    if !path.exists() {
+        eprintln!("Launch agent is not installed.");
+        return;
+    }
+    Command::new("launchctl").arg("unload").arg("-w").arg(&path).output().ok();
+    match std::fs::remove_file(&path) {
+        Ok(_) => eprintln!("Launch agent removed."),
+        Err(e) => {
+            print_error!("Unable to remove launch agent at '{}', error is {:#?}.", path.to_string_lossy(), e);
+            exit(1);
+        }
+    }
+}
+
+// Quote an argument of the task command line the way `CommandLineToArgvW` parses it back
+#[cfg(target_os = "windows")]
+fn quote_arg(arg: &str) -> String {
+    if !arg.is_empty() && !arg.contains(&[' ', '\t', '"'][..]) {
+        return arg.to_owned();
+    }
+    let mut quoted = String::from("\"");
+    let mut backslashes = 0;
+    for c in arg.chars() {
+        if c == '\\' {
+            backslashes += 1;
+            continue;
+        }
+        // Backslashes only escape when followed by a quote
+        let n = if c == '"' { backslashes * 2 + 1 } else { backslashes };
+        quoted.extend(std::iter::repeat('\\').take(n));
+        quoted.push(c);
+        backslashes = 0;
+    }
+    quoted.extend(std::iter::repeat('\\').take(backslashes * 2));
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(target_os = "windows")]
+pub fn install(profiles: &[&str], interval: u64) {
+    let command_line: Vec<String> = agent_args(profiles, interval).iter()
+        .map(|a| quote_arg(a))
+        .collect();
+    let status = Command::new("schtasks")
+        .args(&["/Create", "/F", "/SC", "MINUTE", "/MO"])
+        .arg(interval.to_string())
+        .args(&["/TN", TASK_NAME])
+        .arg("/TR")
+        .arg(command_line.join(" "))
+        .status();
+    match status {
+        Ok(s) if s.success() => eprintln!("Scheduled task '{}' installed.", TASK_NAME),
+        _ => {
+            print_error!("Unable to create scheduled task '{}'.", TASK_NAME);
+            exit(1);
+        }
+    }
+}
+
+#[cfg(target_os = "windows")]
+pub fn uninstall() {
+    let status = Command::new("schtasks")
+        .args(&["/Delete", "/F", "/TN", TASK_NAME])
+        .status();
+    match status {
+        Ok(s) if s.success() => eprintln!("Scheduled task '{}' removed.", TASK_NAME),
+        _ => {
+            print_error!("Unable to remove scheduled task '{}'.", TASK_NAME);
+            exit(1);
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "macos", target_os = "windows")))]
+pub fn install(profiles: &[&str], interval: u64) {
+    print_error!("Background agent is not supported on this platform, you can add this line to your crontab instead:");
+    let command_line: Vec<String> = agent_args(profiles, interval).iter()
+        .map(|a| tokengen::shell::quote("sh", a))
+        .collect();
+    eprintln!("*/{} * * * * {}", interval, command_line.join(" "));
+    exit(1);
+}
+
+#[cfg(not(any(target_os = "macos", target_os = "windows")))]
+pub fn uninstall() {
+    print_error!("Background agent is not supported on this platform.");
+    exit(1);
+}
+
+// What to do after tokens are renewed, so dependent processes like sidecars or dev servers reload them
+#[derive(Default)]
+pub struct RefreshHook<'a> {
+    // Run with the shell for each renewed profile, with `TOKENGEN_PROFILE` and `TOKENGEN_EXPIRES_ON` set
+    pub command: &'a str,
+    // Rewritten with the names of the renewed profiles, for file watchers
+    pub file: &'a str,
+}
+
+fn run_hook_command(command: &str, profile: &str, expires_on: i64) {
+    #[cfg(target_os = "windows")]
+    let mut cmd = Command::new("cmd");
+    #[cfg(target_os = "windows")]
+    cmd.arg("/C");
+    #[cfg(not(target_os = "windows"))]
+    let mut cmd = Command::new("sh");
+    #[cfg(not(target_os = "windows"))]
+    cmd.arg("-c");
+    let status = cmd.arg(command)
+        .env("TOKENGEN_PROFILE", profile)
+        .env("TOKENGEN_EXPIRES_ON", expires_on.to_string())
+        .status();
+    match status {
+        Ok(s) if s.success() => (),
+        Ok(s) => print_warning!("Refresh hook of profile '{}' failed, {}.", profile, s),
+        Err(e) => print_warning!("Unable to run refresh hook '{}', error is {:#?}.", command, e),
+    }
+}
+
+pub fn run(profiles: &[Profile], interval: u64, hook: &RefreshHook) {
+    // Renew everything that would expire before the next run
+    let margin = (interval * 60 + 60) as i64;
+    let mut renewed = vec![];
+    for p in profiles {
+        // Unattended runs have no terminal to ask for the passphrase on
+        let secret = match p {
+            Profile::App(a) => a.secret.as_str(),
+            Profile::User(u) => u.secret.as_str(),
+            Profile::Plugin(_) | Profile::Custom(_) => "",
+        };
+        if crypt::is_encrypted(secret) && !crypt::can_decrypt() {
+            print_warning!("Profile '{}' has an encrypted secret and there is no terminal to ask for the passphrase, set 'TOKENGEN_PASSPHRASE' to renew it, skipped.", p.get_name());
+            continue;
+        }
+        match p.renew_token(margin) {
+            Renewal::Fresh => (),
+            Renewal::Renewed(t) => {
+                if !hook.command.is_empty() {
+                    run_hook_command(hook.command, p.get_name(), t.get_expires_on());
+                }
+                renewed.push(p.get_name());
+            }
+            Renewal::NeedsSignIn => print_warning!("Profile '{}' needs an interactive sign-in, skipped.", p.get_name()),
+            Renewal::Failed(e) => print_warning!("Failed to renew token of profile '{}', {}", p.get_name(), e),
+        }
+    }
+    if !hook.file.is_empty() && !renewed.is_empty() {
+        if let Err(e) = std::fs::write(hook.file, format!("{}\n", renewed.join("\n"))) {
+            print_warning!("Unable to write refresh hook file '{}', error is {:#?}.", hook.file, e);
+        }
+    }
+}
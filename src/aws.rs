@@ -0,0 +1,94 @@
+use std::collections::HashMap;
+use std::env;
+use std::process::exit;
+
+use chrono::{SecondsFormat, TimeZone, Utc};
+use serde_json::{json, Value};
+
+use tokengen::profile::http_client;
+use tokengen::redact::register_secret;
+
+// The STS endpoint, `AWS_ENDPOINT_URL_STS` as the AWS SDKs, or the regional one if the region is set
+fn sts_endpoint() -> String {
+    if let Ok(url) = env::var("AWS_ENDPOINT_URL_STS") {
+        return url;
+    }
+    match env::var("AWS_REGION").or_else(|_| env::var("AWS_DEFAULT_REGION")) {
+        Ok(r) if r.starts_with("cn-") => format!("https://sts.{}.amazonaws.com.cn", r),
+        Ok(r) if !r.is_empty() => format!("https://sts.{}.amazonaws.com", r),
+        _ => String::from("https://sts.amazonaws.com"),
+    }
+}
+
+// `tokengen-<profile>`, the session name shows up in CloudTrail, only `[\w+=,.@-]` up to 64 characters are allowed
+fn session_name(profile_name: &str) -> String {
+    let name: String = profile_name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || "_+=,.@-".contains(c) { c } else { '-' })
+        .collect();
+    let name = if name.is_empty() { String::from("tokengen") } else { format!("tokengen-{}", name) };
+    name.chars().take(64).collect()
+}
+
+// The message of an STS error response, i.e. `{"Error":{"Code":"InvalidIdentityToken","Message":"..."}}`
+fn describe_error(body: &str) -> String {
+    serde_json::from_str::<Value>(body).ok()
+        .and_then(|v| {
+            let e = v.get("Error").or_else(|| v["ErrorResponse"].get("Error"))?.clone();
+            Some(format!("{} {}", e["Code"].as_str().unwrap_or_default(), e["Message"].as_str().unwrap_or_default()))
+        })
+        .unwrap_or_else(|| body.trim().to_owned())
+}
+
+// Exchange the token for temporary AWS credentials with `AssumeRoleWithWebIdentity`,
+// returns the JSON of `credential_process`, https://docs.aws.amazon.com/cli/latest/userguide/cli-configure-sourcing-external.html
+pub fn assume_role(role_arn: &str, profile_name: &str, token: &str) -> String {
+    let session_name = session_name(profile_name);
+    let mut form: HashMap<&str, &str> = HashMap::new();
+    form.insert("Action", "AssumeRoleWithWebIdentity");
+    form.insert("Version", "2011-06-15");
+    form.insert("RoleArn", role_arn);
+    form.insert("RoleSessionName", &session_name);
+    form.insert("WebIdentityToken", token);
+    // STS answers in XML unless JSON is asked for
+    let headers = [(String::from("Accept"), String::from("application/json"))];
+    let endpoint = sts_endpoint();
+    let resp = match http_client().post_form(&endpoint, &form, &headers) {
+        Ok(r) => r,
+        Err(e) => {
+            print_error!("Unable to reach AWS STS at '{}', error is {}.", endpoint, e);
+            exit(1);
+        }
+    };
+    if !resp.is_success() {
+        print_error!("AWS STS rejected the token, status {}, error is {}", resp.status, describe_error(&resp.body));
+        exit(2);
+    }
+    let body: Value = resp.json().unwrap_or_default();
+    let credentials = &body["AssumeRoleWithWebIdentityResponse"]["AssumeRoleWithWebIdentityResult"]["Credentials"];
+    let (access_key_id, secret_access_key, session_token) = match (
+        credentials["AccessKeyId"].as_str(),
+        credentials["SecretAccessKey"].as_str(),
+        credentials["SessionToken"].as_str(),
+    ) {
+        (Some(a), Some(s), Some(t)) => (a, s, t),
+        _ => {
+            print_error!("AWS STS didn't return the credentials, the response is {}", resp.body);
+            exit(2);
+        }
+    };
+    register_secret(secret_access_key);
+    register_secret(session_token);
+    // Seconds since the epoch in JSON responses
+    let expiration = credentials["Expiration"].as_f64()
+        .and_then(|e| Utc.timestamp_opt(e as i64, 0).single())
+        .map(|e| e.to_rfc3339_opts(SecondsFormat::Secs, true))
+        .or_else(|| credentials["Expiration"].as_str().map(|e| e.to_owned()))
+        .unwrap_or_default();
+    json!({
+        "Version": 1,
+        "AccessKeyId": access_key_id,
+        "SecretAccessKey": secret_access_key,
+        "SessionToken": session_token,
+        "Expiration": expiration,
+    }).to_string()
+}
@@ -0,0 +1,40 @@
+use std::env;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use atty::Stream;
+
+static COLOR: AtomicBool = AtomicBool::new(false);
+
+// Mode can be `always`, `never` or `auto`, which only colors the output when both stdout and stderr are terminals
+pub fn set_color(mode: &str) {
+    let enabled = match mode {
+        "always" => true,
+        "never" => false,
+        _ => atty::is(Stream::Stdout) && atty::is(Stream::Stderr) && env::var_os("NO_COLOR").is_none(),
+    };
+    COLOR.store(enabled, Ordering::Relaxed);
+}
+
+fn paint(text: &str, code: &str) -> String {
+    if COLOR.load(Ordering::Relaxed) {
+        format!("\x1b[{}m{}\x1b[0m", code, text)
+    } else {
+        text.to_owned()
+    }
+}
+
+pub fn red(text: &str) -> String {
+    paint(text, "1;31")
+}
+
+pub fn yellow(text: &str) -> String {
+    paint(text, "1;33")
+}
+
+pub fn green(text: &str) -> String {
+    paint(text, "32")
+}
+
+pub fn bold(text: &str) -> String {
+    paint(text, "1")
+}
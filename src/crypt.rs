@@ -0,0 +1,117 @@
+use std::env;
+use std::process::exit;
+use std::sync::Mutex;
+
+use argon2::Argon2;
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::aead::rand_core::RngCore;
+use tokengen::redact::register_secret;
+
+// Encrypted values in the configuration file are "enc:" followed by the base64 encoded nonce and ciphertext,
+// the key is derived from the passphrase with Argon2id and the salt saved in the file
+const PREFIX: &str = "enc:";
+const NONCE_SIZE: usize = 12;
+
+pub fn is_encrypted(value: &str) -> bool {
+    value.starts_with(PREFIX)
+}
+
+pub fn new_salt() -> String {
+    let mut salt = [0u8; 16];
+    OsRng.fill_bytes(&mut salt);
+    base64::encode(salt)
+}
+
+pub fn derive_key(passphrase: &str, salt: &str) -> Key {
+    let salt = base64::decode(salt).unwrap_or_else(|_| {
+        print_error!("Invalid 'EncryptionSalt' in the configuration file.");
+        exit(1);
+    });
+    let mut key = Key::default();
+    if let Err(e) = Argon2::default().hash_password_into(passphrase.as_bytes(), &salt, &mut key) {
+        print_error!("Unable to derive the key from the passphrase, error is {}.", e);
+        exit(1);
+    }
+    key
+}
+
+pub fn encrypt(key: &Key, value: &str) -> String {
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    // Never fails for in-memory buffers
+    let mut data = ChaCha20Poly1305::new(key).encrypt(&nonce, value.as_bytes()).unwrap();
+    data.splice(0..0, nonce.iter().cloned());
+    format!("{}{}", PREFIX, base64::encode(data))
+}
+
+// Returns None if the passphrase is wrong or the value is corrupted
+pub fn decrypt(key: &Key, value: &str) -> Option<String> {
+    let data = base64::decode(value.strip_prefix(PREFIX)?).ok()?;
+    if data.len() < NONCE_SIZE {
+        return None;
+    }
+    let (nonce, ciphertext) = data.split_at(NONCE_SIZE);
+    let plaintext = ChaCha20Poly1305::new(key).decrypt(Nonce::from_slice(nonce), ciphertext).ok()?;
+    String::from_utf8(plaintext).ok()
+}
+
+// Read the passphrase from `TOKENGEN_PASSPHRASE`, or prompt for it on the terminal
+pub fn read_passphrase(confirm: bool) -> String {
+    if let Ok(passphrase) = env::var("TOKENGEN_PASSPHRASE") {
+        return passphrase;
+    }
+    let read = |prompt: &str| rpassword::read_password_from_tty(Some(prompt)).unwrap_or_else(|e| {
+        print_error!("Unable to read the passphrase, error is {:#?}.", e);
+        exit(1);
+    });
+    let passphrase = read("Config passphrase: ");
+    if passphrase.is_empty() {
+        print_error!("The passphrase is empty.");
+        exit(1);
+    }
+    if confirm && read("Confirm passphrase: ") != passphrase {
+        print_error!("The passphrases don't match.");
+        exit(1);
+    }
+    passphrase
+}
+
+// The salt of the configuration file, the key is derived when the first secret is decrypted
+static SALT: Mutex<String> = Mutex::new(String::new());
+static KEY: Mutex<Option<Key>> = Mutex::new(None);
+
+pub fn set_salt(salt: &str) {
+    *SALT.lock().unwrap() = salt.to_owned();
+}
+
+// Whether the secrets can be decrypted without blocking, the passphrase is known or can be asked on the terminal
+pub fn can_decrypt() -> bool {
+    KEY.lock().unwrap().is_some() || env::var_os("TOKENGEN_PASSPHRASE").is_some() || atty::is(atty::Stream::Stdin)
+}
+
+// Decrypt the secret of the configuration file if it's encrypted, the passphrase is only asked for once
+pub fn decrypt_secret(value: &str) -> String {
+    if !is_encrypted(value) {
+        return value.to_owned();
+    }
+    let mut key = KEY.lock().unwrap();
+    let key = key.get_or_insert_with(|| {
+        let salt = SALT.lock().unwrap().clone();
+        if salt.is_empty() {
+            print_error!("Missing 'EncryptionSalt' in the configuration file.");
+            exit(1);
+        }
+        derive_key(&read_passphrase(false), &salt)
+    });
+    match decrypt(key, value) {
+        Some(v) => {
+            // Keep it out of error messages
+            register_secret(&v);
+            v
+        }
+        None => {
+            print_error!("Unable to decrypt the configuration file, the passphrase may be wrong.");
+            exit(1);
+        }
+    }
+}
@@ -0,0 +1,217 @@
+use std::fs::{read, read_dir, read_to_string};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::time::Instant;
+
+use chrono::{DateTime, Utc};
+use dirs::config_dir;
+use serde_json::Value;
+
+use tokengen::color::{green, red, yellow};
+use tokengen::permissions::shared_mode;
+use tokengen::redact::{redact_text, register_secret};
+use tokengen::profile::{cache_root, decode_cbor, DEFAULT_AUTHORITY, describe_proxy, http_client, Profile, ProxySettings, set_proxy_settings};
+
+use crate::{Configuration, crypt, strip_comments};
+
+// AAD rejects assertions and tokens issued more than 5 minutes off
+const MAX_CLOCK_SKEW: i64 = 300;
+
+#[derive(Default)]
+struct Report {
+    failures: usize,
+    warnings: usize,
+}
+
+impl Report {
+    fn pass(&mut self, check: &str, detail: &str) {
+        println!("[{}] {}: {}", green("PASS"), check, redact_text(detail));
+    }
+
+    fn warn(&mut self, check: &str, detail: &str) {
+        self.warnings += 1;
+        println!("[{}] {}: {}", yellow("WARN"), check, redact_text(detail));
+    }
+
+    fn fail(&mut self, check: &str, detail: &str) {
+        self.failures += 1;
+        println!("[{}] {}: {}", red("FAIL"), check, redact_text(detail));
+    }
+
+    fn check_private(&mut self, check: &str, path: &Path) {
+        if let Some(mode) = shared_mode(path) {
+            self.warn(check, &format!("'{}' is accessible by other users (mode {:o}), run `chmod 600 '{}'` to fix it.", path.to_string_lossy(), mode, path.to_string_lossy()));
+        }
+    }
+}
+
+// The configuration is parsed without decrypting the secrets, so no passphrase is asked
+fn check_config(report: &mut Report) -> Option<Configuration> {
+    let mut path = config_dir()?;
+    path.push("tokengen");
+    path.push("config.json");
+    let text = match read_to_string(&path) {
+        Ok(t) => t,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            report.warn("Configuration", &format!("'{}' doesn't exist, run 'tokengen init' to create one.", path.to_string_lossy()));
+            return None;
+        }
+        Err(e) => {
+            report.fail("Configuration", &format!("Unable to read '{}', error is {}.", path.to_string_lossy(), e));
+            return None;
+        }
+    };
+    match serde_json::from_str::<Configuration>(&strip_comments(&text)) {
+        Ok(cfg) => {
+            report.pass("Configuration", &format!("'{}' has {} profile(s), run 'tokengen profile validate' to check them.", path.to_string_lossy(), cfg.profiles.len()));
+            report.check_private("Configuration", &path);
+            Some(cfg)
+        }
+        Err(e) => {
+            report.fail("Configuration", &format!("Unable to parse '{}', error is {}.", path.to_string_lossy(), e));
+            None
+        }
+    }
+}
+
+fn check_cache(report: &mut Report) {
+    let dir = match cache_root() {
+        Some(d) => d,
+        None => {
+            report.fail("Cache", "The cache directory is not available.");
+            return;
+        }
+    };
+    let mut found = 0;
+    let mut paths: Vec<PathBuf> = ["cache.json", "refresh_tokens.json", "discovery.json", "endpoints.json", "missing_endpoints.json"].iter().map(|n| dir.join(n)).collect();
+    // One file per cached token
+    if let Ok(entries) = read_dir(dir.join("cache")) {
+        paths.extend(entries.filter_map(|e| e.ok()).map(|e| e.path()));
+    }
+    for path in paths {
+        let data = match read(&path) {
+            Ok(d) => d,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => {
+                report.fail("Cache", &format!("Unable to read '{}', error is {}.", path.to_string_lossy(), e));
+                continue;
+            }
+        };
+        found += 1;
+        // The entries are CBOR with `CacheFormat` set to `cbor`
+        let parsed = if path.extension().is_some_and(|e| e == "cbor") {
+            decode_cbor(&data).map(|_| ())
+        } else {
+            serde_json::from_slice::<Value>(&data).map(|_| ()).map_err(|e| e.to_string())
+        };
+        match parsed {
+            Ok(_) => report.check_private("Cache", &path),
+            Err(e) => report.fail("Cache", &format!("'{}' is corrupted, remove it or run 'tokengen logout --all', error is {}.", path.to_string_lossy(), e)),
+        }
+    }
+    report.pass("Cache", &format!("{} file(s) in '{}'.", found, dir.to_string_lossy()));
+}
+
+// The authority is reachable if its OpenID configuration can be fetched, the `Date` header tells the clock skew
+fn check_authority(report: &mut Report, authority: &str) {
+    let url = format!("{}/common/v2.0/.well-known/openid-configuration", authority.trim_end_matches('/'));
+    match describe_proxy(&url) {
+        Some(p) => report.pass("Proxy", &format!("'{}' is reached through {}.", authority, p)),
+        None => report.pass("Proxy", &format!("'{}' is reached directly.", authority)),
+    }
+
+    let start = Instant::now();
+    let resp = match http_client().get(&url) {
+        Ok(r) => r,
+        Err(e) => {
+            report.fail("Network", &format!("Unable to reach '{}', error is {}.", authority, e));
+            return;
+        }
+    };
+    let elapsed = start.elapsed().as_millis();
+    if resp.is_success() {
+        report.pass("Network", &format!("'{}' responded in {} ms.", authority, elapsed));
+    } else {
+        report.fail("Network", &format!("'{}' responded with status {}.", authority, resp.status));
+    }
+
+    let date = resp.headers.iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case("date"))
+        .and_then(|(_, v)| DateTime::parse_from_rfc2822(v).ok());
+    match date {
+        Some(d) => {
+            let skew = Utc::now().timestamp() - d.timestamp();
+            let detail = format!("The local clock is {} second(s) {} '{}'.", skew.abs(), if skew < 0 { "behind" } else { "ahead of" }, authority);
+            if skew.abs() > MAX_CLOCK_SKEW {
+                report.fail("Clock", &format!("{} Tokens may be rejected, sync the clock.", detail));
+            } else {
+                report.pass("Clock", &detail);
+            }
+        }
+        None => report.warn("Clock", &format!("'{}' didn't return the time, the clock skew is unknown.", authority)),
+    }
+}
+
+#[cfg(feature = "nogui")]
+fn check_gui(report: &mut Report) {
+    report.warn("Browser", "This build has no browser or clipboard support, sign in with the device code on another device.");
+}
+
+#[cfg(not(feature = "nogui"))]
+fn check_gui(report: &mut Report) {
+    use clipboard::{ClipboardContext, ClipboardProvider};
+    use tokengen::profile::is_remote_session;
+
+    if is_remote_session() {
+        report.warn("Browser", "This looks like a remote session, the sign-in URL and the code are printed instead of opening the browser.");
+    } else {
+        report.pass("Browser", "The browser is opened for sign-ins.");
+    }
+    match ClipboardProvider::new().and_then(|mut ctx: ClipboardContext| ctx.get_contents()) {
+        Ok(_) => report.pass("Clipboard", "The clipboard is available."),
+        Err(e) => report.warn("Clipboard", &format!("The clipboard is not available, '--copy' won't work, error is {}.", e)),
+    }
+}
+
+// Print a pass/fail report of the environment for troubleshooting, fails if any check fails
+pub fn run() {
+    let mut report = Report::default();
+    let cfg = check_config(&mut report);
+    check_cache(&mut report);
+
+    let mut authorities = vec![];
+    if let Some(cfg) = cfg.as_ref() {
+        register_secret(&cfg.proxy_password);
+        set_proxy_settings(ProxySettings {
+            url: cfg.proxy.clone(),
+            username: cfg.proxy_username.clone(),
+            // Encrypted passwords are not used, so no passphrase is asked
+            password: if crypt::is_encrypted(&cfg.proxy_password) { String::new() } else { cfg.proxy_password.clone() },
+            no_proxy: cfg.no_proxy.clone(),
+        });
+        authorities.push(cfg.default_authority.clone());
+        // Profiles without an authority use the default one
+        for p in cfg.profiles.iter() {
+            match p {
+                Profile::App(p) if !p.authority.is_empty() => authorities.push(p.authority.clone()),
+                Profile::User(p) if !p.authority.is_empty() => authorities.push(p.authority.clone()),
+                _ => (),
+            }
+        }
+    }
+    if authorities.is_empty() || authorities[0].is_empty() {
+        authorities.push(DEFAULT_AUTHORITY.to_owned());
+    }
+    authorities.retain(|a| !a.is_empty());
+    authorities.sort();
+    authorities.dedup();
+    for a in authorities.iter() {
+        check_authority(&mut report, a);
+    }
+
+    check_gui(&mut report);
+
+    println!();
+    println!("{} failure(s), {} warning(s).", report.failures, report.warnings);
+    exit(if report.failures > 0 { 1 } else { 0 });
+}
@@ -0,0 +1,122 @@
+use std::collections::HashMap;
+use std::env::current_exe;
+use std::process::exit;
+
+use serde_json::{json, Value};
+
+use tokengen::profile::http_client;
+use tokengen::redact::register_secret;
+
+const STS_URL: &str = "https://sts.googleapis.com/v1/token";
+const CLOUD_PLATFORM_SCOPE: &str = "https://www.googleapis.com/auth/cloud-platform";
+const JWT_TOKEN_TYPE: &str = "urn:ietf:params:oauth:token-type:jwt";
+
+fn impersonation_url(service_account: &str) -> String {
+    format!("https://iamcredentials.googleapis.com/v1/projects/-/serviceAccounts/{}:generateAccessToken", service_account)
+}
+
+// The message of an OAuth error of STS, or a Google API error of IAM credentials
+fn describe_error(body: &str) -> String {
+    serde_json::from_str::<Value>(body).ok()
+        .and_then(|v| v["error_description"].as_str().or_else(|| v["error"]["message"].as_str()).map(|s| s.to_owned()))
+        .unwrap_or_else(|| body.trim().to_owned())
+}
+
+// Exchange the token for a GCP access token with workload identity federation, then for a token of the
+// service account if set, returns the access token
+pub fn access_token(audience: &str, service_account: &str, token: &str) -> String {
+    let mut form: HashMap<&str, &str> = HashMap::new();
+    form.insert("grant_type", "urn:ietf:params:oauth:grant-type:token-exchange");
+    form.insert("audience", audience);
+    form.insert("scope", CLOUD_PLATFORM_SCOPE);
+    form.insert("requested_token_type", "urn:ietf:params:oauth:token-type:access_token");
+    form.insert("subject_token_type", JWT_TOKEN_TYPE);
+    form.insert("subject_token", token);
+    let resp = match http_client().post_form(STS_URL, &form, &[]) {
+        Ok(r) => r,
+        Err(e) => {
+            print_error!("Unable to reach Google STS, error is {}.", e);
+            exit(1);
+        }
+    };
+    if !resp.is_success() {
+        print_error!("Google STS rejected the token, status {}, error is {}", resp.status, describe_error(&resp.body));
+        exit(2);
+    }
+    let federated = match resp.json::<Value>().ok().and_then(|v| v["access_token"].as_str().map(|s| s.to_owned())) {
+        Some(t) => t,
+        None => {
+            print_error!("Google STS didn't return a token, the response is {}", resp.body);
+            exit(2);
+        }
+    };
+    register_secret(&federated);
+    if service_account.is_empty() {
+        return federated;
+    }
+
+    let headers = [(String::from("Authorization"), format!("Bearer {}", federated))];
+    let resp = match http_client().post_json(&impersonation_url(service_account), &json!({ "scope": [CLOUD_PLATFORM_SCOPE] }), &headers) {
+        Ok(r) => r,
+        Err(e) => {
+            print_error!("Unable to reach Google IAM credentials, error is {}.", e);
+            exit(1);
+        }
+    };
+    if !resp.is_success() {
+        print_error!("Unable to impersonate '{}', status {}, error is {}", service_account, resp.status, describe_error(&resp.body));
+        exit(2);
+    }
+    match resp.json::<Value>().ok().and_then(|v| v["accessToken"].as_str().map(|s| s.to_owned())) {
+        Some(t) => {
+            register_secret(&t);
+            t
+        }
+        None => {
+            print_error!("Google IAM credentials didn't return a token, the response is {}", resp.body);
+            exit(2);
+        }
+    }
+}
+
+// The response of executable-sourced credentials, https://google.aip.dev/auth/4117
+pub fn executable_response(token: &str, expires_on: i64) -> String {
+    json!({
+        "version": 1,
+        "success": true,
+        "token_type": JWT_TOKEN_TYPE,
+        "id_token": token,
+        "expiration_time": expires_on,
+    }).to_string()
+}
+
+// The Application Default Credentials file running tokengen for the token, the Google client libraries
+// only run it if `GOOGLE_EXTERNAL_ACCOUNT_ALLOW_EXECUTABLES` is `1`
+pub fn external_account(audience: &str, service_account: &str, profile_name: &str, token_type: &str) -> String {
+    // The command is split on spaces and can't be quoted, refuse what would be split into other arguments
+    let exe = current_exe().map(|p| p.to_string_lossy().into_owned()).unwrap_or_else(|_| String::from("tokengen"));
+    if exe.contains(char::is_whitespace) {
+        print_error!("The credentials file can't run tokengen at '{}', install it on a path without spaces.", exe);
+        exit(1);
+    }
+    if profile_name.contains(char::is_whitespace) {
+        print_error!("The credentials file can't use the profile '{}', the name must not have spaces.", profile_name);
+        exit(1);
+    }
+    let mut credentials = json!({
+        "type": "external_account",
+        "audience": audience,
+        "subject_token_type": JWT_TOKEN_TYPE,
+        "token_url": STS_URL,
+        "credential_source": {
+            "executable": {
+                "command": format!("{} -p {} -k {} -f gcp-executable", exe, profile_name, token_type),
+                "timeout_millis": 30000,
+            }
+        },
+    });
+    if !service_account.is_empty() {
+        credentials["service_account_impersonation_url"] = json!(impersonation_url(service_account));
+    }
+    serde_json::to_string_pretty(&credentials).unwrap_or_default()
+}
@@ -0,0 +1,41 @@
+use std::process::exit;
+
+// Create a self-signed client certificate with a non-exportable key generated in the TPM by the
+// Microsoft Platform Crypto Provider, it's used through the certificate store like other certificates
+#[cfg(target_os = "windows")]
+pub fn generate(subject: &str, output: &str, years: u32) {
+    use std::process::Command;
+
+    use tokengen::shell::quote;
+
+    let script = format!(
+        "$c = New-SelfSignedCertificate -Subject {} -CertStoreLocation Cert:\\CurrentUser\\My \
+         -Provider 'Microsoft Platform Crypto Provider' -KeyAlgorithm RSA -KeyLength 2048 \
+         -KeyExportPolicy NonExportable -KeyUsage DigitalSignature -NotAfter (Get-Date).AddYears({}); \
+         Export-Certificate -Cert $c -FilePath {} | Out-Null; $c.Thumbprint",
+        quote("powershell", subject), years, quote("powershell", output));
+    let output_text = Command::new("powershell")
+        .args(&["-NoProfile", "-NonInteractive", "-Command", &script])
+        .output();
+    let thumbprint = match output_text {
+        Ok(o) if o.status.success() => String::from_utf8_lossy(&o.stdout).trim().to_owned(),
+        Ok(o) => {
+            print_error!("Unable to create the certificate, the TPM may not be available, error is {}.", String::from_utf8_lossy(&o.stderr).trim());
+            exit(1);
+        }
+        Err(e) => {
+            print_error!("Unable to run PowerShell, error is {:#?}.", e);
+            exit(1);
+        }
+    };
+    eprintln!("Certificate '{}' has been created in the 'CurrentUser' store, its private key is in the TPM and can't be exported.", subject);
+    eprintln!("Upload '{}' to the app registration, then use the certificate in the profile with:", output);
+    eprintln!("    tokengen config set <Profile>.Certificate {}", thumbprint);
+    println!("{}", thumbprint);
+}
+
+#[cfg(not(target_os = "windows"))]
+pub fn generate(_subject: &str, _output: &str, _years: u32) {
+    print_error!("Generating TPM keys is only supported on Windows, on Linux create the key with tpm2-pkcs11 and use it through 'Pkcs11Module', see README for the steps.");
+    exit(1);
+}
@@ -0,0 +1,92 @@
+use std::env;
+use std::ffi::OsString;
+use std::path::Path;
+use std::process::exit;
+
+use chrono::{SecondsFormat, TimeZone, Utc};
+use clap::ArgMatches;
+use serde_json::{json, Value};
+
+use tokengen::profile::{AADToken, ProfileOverrides, Token, TokenType};
+
+// `kubelogin get-token` compatibility, https://azure.github.io/kubelogin/cli/get-token.html,
+// so the exec plugin of AKS kubeconfigs can run tokengen instead of kubelogin
+
+// kubectl asks for the version it understands in `KUBERNETES_EXEC_INFO`
+const DEFAULT_API_VERSION: &str = "client.authentication.k8s.io/v1beta1";
+
+// Installed or linked as `kubelogin`, the existing kubeconfigs run it with the arguments of kubelogin
+pub fn expand_args(args: Vec<OsString>) -> Vec<OsString> {
+    let is_kubelogin = args.first()
+        .and_then(|a| Path::new(a).file_stem())
+        .map(|s| s.eq_ignore_ascii_case("kubelogin"))
+        .unwrap_or_default();
+    if !is_kubelogin {
+        return args;
+    }
+    let mut expanded = args;
+    expanded.insert(1, OsString::from("kubelogin"));
+    expanded
+}
+
+fn authority(environment: &str) -> &'static str {
+    match environment.to_lowercase().as_str() {
+        "azurechinacloud" => "https://login.chinacloudapi.cn",
+        "azureusgovernmentcloud" | "azureusgovernment" => "https://login.microsoftonline.us",
+        _ => "https://login.microsoftonline.com"
+    }
+}
+
+fn flag_or_env(m: &ArgMatches, name: &str, vars: &[&str]) -> String {
+    m.value_of(name).map(|v| v.to_owned())
+        .or_else(|| vars.iter().find_map(|v| env::var(v).ok().filter(|v| !v.is_empty())))
+        .unwrap_or_default()
+}
+
+// The profile type and the fields of the `get-token` flags, the service principal can also be in the environment
+// variables kubelogin reads, other fields come from the global flags
+pub fn profile_overrides(m: &ArgMatches, global: &ProfileOverrides) -> (&'static str, ProfileOverrides) {
+    let server_id = m.value_of("SERVER_ID").unwrap_or_default();
+    let tenant = flag_or_env(m, "TENANT_ID", &["AZURE_TENANT_ID"]);
+    let authority = authority(m.value_of("ENVIRONMENT").unwrap_or_default()).to_owned();
+    match m.value_of("LOGIN").unwrap_or("devicecode") {
+        // There is no browser sign-in, the device code is used for both
+        "devicecode" | "interactive" => ("User", ProfileOverrides {
+            client_id: m.value_of("KUBE_CLIENT_ID").unwrap_or_default().to_owned(),
+            tenant,
+            authority,
+            scope: format!("{}/.default offline_access openid profile", server_id),
+            ..global.clone()
+        }),
+        "spn" => ("App", ProfileOverrides {
+            client_id: flag_or_env(m, "KUBE_CLIENT_ID", &["AAD_SERVICE_PRINCIPAL_CLIENT_ID", "AZURE_CLIENT_ID"]),
+            secret: flag_or_env(m, "KUBE_SECRET", &["AAD_SERVICE_PRINCIPAL_CLIENT_SECRET", "AZURE_CLIENT_SECRET"]),
+            tenant,
+            authority,
+            resource: server_id.to_owned(),
+            ..global.clone()
+        }),
+        login => {
+            print_error!("Login method '{}' is not supported, use 'devicecode', 'interactive' or 'spn'.", login);
+            exit(1);
+        }
+    }
+}
+
+// The ExecCredential with the access token, in the version kubectl asked for
+pub fn exec_credential(token: &Token) -> Value {
+    let api_version = env::var("KUBERNETES_EXEC_INFO").ok()
+        .and_then(|s| serde_json::from_str::<Value>(&s).ok())
+        .and_then(|v| v["apiVersion"].as_str().map(|s| s.to_owned()))
+        .unwrap_or_else(|| DEFAULT_API_VERSION.to_owned());
+    let expires_on = Utc.timestamp_opt(token.get_expires_on(), 0).single().unwrap_or_else(Utc::now);
+    json!({
+        "kind": "ExecCredential",
+        "apiVersion": api_version,
+        "spec": { "interactive": false },
+        "status": {
+            "expirationTimestamp": expires_on.to_rfc3339_opts(SecondsFormat::Secs, true),
+            "token": token.get_token_string(TokenType::Access),
+        }
+    })
+}
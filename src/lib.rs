@@ -0,0 +1,10 @@
+// The token flows of the `tokengen` command, also usable as a library
+#[macro_use]
+pub mod redact;
+pub mod color;
+pub mod permissions;
+pub mod profile;
+pub mod shell;
+
+#[cfg(not(any(feature = "native-tls", feature = "rustls", feature = "ureq")))]
+compile_error!("One of the features `native-tls`, `rustls` or `ureq` must be enabled.");
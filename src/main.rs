@@ -1,16 +1,38 @@
 #[macro_use]
 extern crate clap;
+#[macro_use]
+extern crate tokengen;
 
-use std::fs::{create_dir_all, File};
+use std::env;
+use std::ffi::OsString;
+use std::fs::read_to_string;
+use std::io::{self, Read, stdin, Write};
 use std::process::exit;
+use std::path::{Path, PathBuf};
 
+use chrono::{Local, TimeZone, Utc};
+use clap::ArgMatches;
 use dirs::config_dir;
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 
-use crate::profile::{Profile, AADToken, TokenType};
 use edit::edit_file;
+use tokengen::profile::{Profile, AADToken, Token, cache_root, decode_claims, ProfileOverrides, TokenType, set_debug_http, set_notify, set_offline, set_proxy_settings, ProxySettings, set_cache_policy, CachePolicy, PopKey, set_expiry_skew, set_min_remaining, min_remaining, set_secret_decryptor};
+use tokengen::color::{bold, green, set_color};
+use tokengen::permissions::{check_permissions, create_private_dir, create_private_file, set_strict_permissions};
+use tokengen::redact::register_secret;
+use tokengen::shell::{quote, split_args};
 
-mod profile;
+mod agent;
+mod aws;
+mod crypt;
+mod doctor;
+mod gcp;
+mod keygen;
+mod kubelogin;
+#[cfg(feature = "tui")]
+mod tui;
+mod vault;
 
 #[derive(Clone, PartialEq, Debug, Default, Serialize, Deserialize)]
 #[serde(rename_all = "PascalCase")]
@@ -27,6 +49,50 @@ struct Configuration {
     default_authority: String,
     #[serde(default)]
     default_scope: String,
+    // Set by `config encrypt`, the salt of the key encrypting the secrets
+    #[serde(default)]
+    encryption_salt: String,
+    // The proxy for all requests, `HTTPS_PROXY` and `HTTP_PROXY` are used if not set
+    #[serde(default)]
+    proxy: String,
+    #[serde(default)]
+    proxy_username: String,
+    // Looked up in the OS keyring if the username is set without a password
+    #[serde(default)]
+    proxy_password: String,
+    // Hosts not using the proxy, `NO_PROXY` is used if not set
+    #[serde(default)]
+    no_proxy: String,
+    // Run by `agent run` for each renewed token, i.e. to reload a sidecar
+    #[serde(default)]
+    on_refresh: String,
+    // Rewritten by `agent run` with the names of the renewed profiles, for file watchers
+    #[serde(default)]
+    on_refresh_file: String,
+    // Max number of cached tokens, 0 for no limit
+    #[serde(default)]
+    cache_max_entries: usize,
+    // Days refresh tokens are kept, 0 for 90 days
+    #[serde(default)]
+    refresh_token_max_age: i64,
+    // Keep the token responses as is in the cache, for `last-response`
+    #[serde(default)]
+    keep_raw_response: bool,
+    // `json` or `cbor`, the format of the cached token files
+    #[serde(default)]
+    cache_format: String,
+    // Seconds permanent failures are cached for, 60 if not set, 0 to not cache them
+    #[serde(default)]
+    failure_cache_ttl: Option<i64>,
+    // Desktop notification when a device code sign-in ends
+    #[serde(default)]
+    notify: bool,
+    // Seconds taken off the lifetime of new tokens, 5 if not set
+    #[serde(default)]
+    expiry_skew: Option<i64>,
+    // Seconds a cached token must have left to be used, 60 if not set
+    #[serde(default)]
+    min_remaining: Option<i64>,
     profiles: Vec<Profile>,
 }
 
@@ -38,129 +104,779 @@ impl Configuration {
     fn load() -> Self {
         let mut config_dir = config_dir().unwrap();
         config_dir.push("tokengen");
-        match create_dir_all(config_dir.as_path()) {
+        match create_private_dir(config_dir.as_path()) {
             Ok(_) => (),
             Err(e) => {
-                eprintln!("WARNING: Unable to create config directory '{}', error is {:#?}.", config_dir.to_string_lossy(), e);
+                print_warning!("Unable to create config directory '{}', error is {:#?}.", config_dir.to_string_lossy(), e);
                 return Self::new();
             }
         }
 
         let mut config_filename = config_dir.clone();
         config_filename.push("config.json");
-        let config_file = match File::open(config_filename.as_path()) {
-            Ok(f) => f,
+        check_permissions(config_filename.as_path());
+        let config_text = match read_to_string(config_filename.as_path()) {
+            Ok(s) => s,
             Err(_) => {
                 return Self::new();
             }
         };
 
-        let mut ret = match serde_json::from_reader(config_file) {
+        let mut ret = match serde_json::from_str(&strip_comments(&config_text)) {
             Ok(v) => {
                 v
             }
             Err(e) => {
-                eprintln!("WARNING: Unable to load configuration file at '{}', error is {:#?}.", config_filename.to_string_lossy(), e);
+                print_warning!("Unable to load configuration file at '{}', error is {:#?}.", config_filename.to_string_lossy(), e);
                 Self::new()
             }
         };
         if ret.default_profile.is_empty() {
             ret.default_profile = String::from("DEFAULT")
         }
-        if ret.default_authority.is_empty() {
-            ret.default_authority = String::from("https://login.microsoftonline.com")
+
+        // Encrypted secrets are decrypted when a request sends them, so the passphrase is only asked for then
+        crypt::set_salt(&ret.encryption_salt);
+        set_secret_decryptor(crypt::decrypt_secret);
+
+        // Keep secrets out of error messages
+        for s in ret.secrets_mut() {
+            register_secret(s);
         }
 
         ret
     }
 
-    fn get_profile(&self,
-                   name: &str,
-                   profile_type: &str,
-                   client_id: &str,
-                   secret: &str,
-                   tenant: &str,
-                   authority: &str,
-                   resource: &str,
-                   scope: &str,
-    ) -> Profile {
+    fn secrets_mut(&mut self) -> Vec<&mut String> {
+        let mut secrets = vec![&mut self.default_secret, &mut self.proxy_password];
+        for p in self.profiles.iter_mut() {
+            match p {
+                Profile::App(p) => secrets.push(&mut p.secret),
+                Profile::User(p) => secrets.push(&mut p.secret),
+                Profile::Plugin(_) | Profile::Custom(_) => (),
+            }
+        }
+        secrets
+    }
+
+    // Encrypt the secrets in the configuration file with a passphrase, other fields are kept as is
+    fn encrypt_file() {
+        let (config_filename, mut config) = Self::read_file();
+
+        let salt = config["EncryptionSalt"].as_str().unwrap_or_default().to_owned();
+        let (salt, key) = if salt.is_empty() {
+            let salt = crypt::new_salt();
+            let key = crypt::derive_key(&crypt::read_passphrase(true), &salt);
+            (salt, key)
+        } else {
+            // Already encrypted, the passphrase must be the same
+            let cfg: Configuration = serde_json::from_value(config.clone()).unwrap_or_default();
+            let key = crypt::derive_key(&crypt::read_passphrase(false), &salt);
+            let mut cfg = Configuration { encryption_salt: salt.clone(), ..cfg };
+            if cfg.secrets_mut().iter().any(|s| crypt::is_encrypted(s) && crypt::decrypt(&key, s).is_none()) {
+                print_error!("The passphrase doesn't match the one encrypting the configuration file.");
+                exit(1);
+            }
+            (salt, key)
+        };
+
+        let mut count = 0;
+        let mut encrypt = |v: &mut Value| {
+            if let Some(s) = v.as_str().filter(|s| !s.is_empty() && !crypt::is_encrypted(s)) {
+                *v = Value::String(crypt::encrypt(&key, s));
+                count += 1;
+            }
+        };
+        for field in ["DefaultSecret", "ProxyPassword"] {
+            if let Some(v) = config.get_mut(field) {
+                encrypt(v);
+            }
+        }
+        if let Some(profiles) = config["Profiles"].as_array_mut() {
+            for p in profiles.iter_mut().filter(|p| p["Type"] == "App" || p["Type"] == "User") {
+                if let Some(v) = p.get_mut("Secret") {
+                    encrypt(v);
+                }
+            }
+        }
+        config["EncryptionSalt"] = Value::String(salt);
+
+        Self::write_file(&config_filename, &config);
+        eprintln!("{} secret(s) have been encrypted.", count);
+    }
+
+    // The configuration file as a JSON value, so fields not known to this version are kept when it's written back
+    fn read_file() -> (PathBuf, Value) {
+        let mut config_filename = config_dir().unwrap();
+        config_filename.push("tokengen");
+        config_filename.push("config.json");
+        match read_to_string(config_filename.as_path()).map(|s| serde_json::from_str(&strip_comments(&s))) {
+            Ok(Ok(v)) => (config_filename, v),
+            Ok(Err(e)) => {
+                print_error!("Unable to load configuration file at '{}', error is {:#?}.", config_filename.to_string_lossy(), e);
+                exit(1);
+            }
+            Err(e) => {
+                print_error!("Unable to read configuration file at '{}', error is {:#?}.", config_filename.to_string_lossy(), e);
+                exit(1);
+            }
+        }
+    }
+
+    fn write_file(config_filename: &Path, config: &Value) {
+        let written = create_private_file(config_filename)
+            .and_then(|f| serde_json::to_writer_pretty(f, config).map_err(|e| e.into()));
+        if let Err(e) = written {
+            print_error!("Unable to save configuration file at '{}', error is {:#?}.", config_filename.to_string_lossy(), e);
+            exit(1);
+        }
+    }
+
+    // Split the key into the profile name and the field name, top-level fields have no profile name
+    fn split_key(key: &str) -> (Option<&str>, &str) {
+        match key.rsplit_once('.') {
+            Some((profile, field)) => (Some(profile), field),
+            None => {
+                if !CONFIG_DEFAULTS.contains(&key) {
+                    print_error!("Unknown configuration field '{}', use one of {} or '<Profile>.<Field>'.", key, CONFIG_DEFAULTS.join(", "));
+                    exit(1);
+                }
+                (None, key)
+            }
+        }
+    }
+
+    // Print a top-level field or a profile field, strings are printed without quotes
+    fn get_field(key: &str) {
+        let (_, config) = Self::read_file();
+        let value = match Self::split_key(key) {
+            (None, field) => config.get(field),
+            (Some(profile), field) => config["Profiles"].as_array()
+                .and_then(|profiles| profiles.iter().find(|p| p["Name"] == profile))
+                .and_then(|p| p.get(field)),
+        };
+        match value {
+            Some(Value::String(s)) => println!("{}", s),
+            Some(v) => println!("{}", v),
+            None => {
+                print_error!("'{}' is not set in the configuration file.", key);
+                exit(1);
+            }
+        }
+    }
+
+    // Set a top-level field or a profile field, the profile is added if it doesn't exist
+    fn set_field(key: &str, value: &str, is_json: bool) {
+        let (config_filename, mut config) = Self::read_file();
+        let value = if is_json {
+            serde_json::from_str(value).unwrap_or_else(|e| {
+                print_error!("Invalid JSON value '{}', error is {}.", value, e);
+                exit(1);
+            })
+        } else {
+            Value::String(value.to_owned())
+        };
+        let (profile, field) = Self::split_key(key);
+        if field == "Secret" || field == "DefaultSecret" {
+            register_secret(value.as_str().unwrap_or_default());
+        }
+        match profile {
+            None => config[field] = value,
+            Some(profile) => {
+                if !config["Profiles"].is_array() {
+                    config["Profiles"] = json!([]);
+                }
+                let profiles = config["Profiles"].as_array_mut().unwrap();
+                let index = match profiles.iter().position(|p| p["Name"] == profile) {
+                    Some(i) => i,
+                    None => {
+                        eprintln!("Profile '{}' has been added.", profile);
+                        profiles.push(json!({"Name": profile}));
+                        profiles.len() - 1
+                    }
+                };
+                profiles[index][field] = value;
+            }
+        }
+
+        Self::write_file(&config_filename, &config);
+        // New profiles are set field by field, so an incomplete configuration is only a warning
+        if let Err(e) = serde_json::from_value::<Configuration>(config.clone()) {
+            print_warning!("The configuration file can't be loaded until it's completed, error is {}.", e);
+        }
+        if ["Secret", "DefaultSecret", "ProxyPassword"].contains(&field) && config.get("EncryptionSalt").is_some() {
+            print_warning!("The secret is saved in plain text, run 'tokengen config encrypt' to encrypt it.");
+        }
+    }
+
+    // A profile only made of the command line arguments and the defaults
+    fn new_profile(&self, profile_type: &str, overrides: &ProfileOverrides) -> Profile {
+        Profile::create(profile_type, overrides).with_defaults(
+            &self.default_client_id,
+            &self.default_secret,
+            &self.default_tenant,
+            &self.default_authority,
+            &self.default_scope,
+        ).with_default_authority()
+    }
+
+    fn get_profile(&self, name: &str, profile_type: &str, overrides: &ProfileOverrides) -> Profile {
         let name = if name.is_empty() { &self.default_profile } else { name };
         let p = self.profiles.iter()
             .find(|&p| p.get_name() == name)
-            .map(|p| p.with_overrides(client_id, secret, tenant, authority, resource, scope))
+            .map(|p| p.with_overrides(overrides))
             .map(|p| p.with_defaults(
                 &self.default_client_id,
                 &self.default_secret,
                 &self.default_tenant,
                 &self.default_authority,
                 &self.default_scope,
-            ));
+            ))
+            .map(|p| p.with_default_authority());
         match p {
-            None => {
-                Profile::create(
-                    profile_type,
-                    client_id,
-                    secret,
-                    tenant,
-                    authority,
-                    resource,
-                    scope,
-                ).with_defaults(
-                    &self.default_client_id,
-                    &self.default_secret,
-                    &self.default_tenant,
-                    &self.default_authority,
-                    &self.default_scope,
-                )
-            }
+            None => self.new_profile(profile_type, overrides),
             Some(p) => p
         }
     }
 
+    // Resolve profiles by name, all configured profiles if no name is given
+    fn get_named_profiles(&self, names: &[&str]) -> Vec<Profile> {
+        let names: Vec<&str> = if names.is_empty() {
+            self.profiles.iter().map(|p| p.get_name()).collect()
+        } else {
+            names.to_vec()
+        };
+        names.into_iter().filter_map(|name| {
+            if self.profiles.iter().any(|p| p.get_name() == name) {
+                Some(self.get_profile(name, "", &ProfileOverrides::default()))
+            } else {
+                print_warning!("Profile '{}' not found in the configuration file.", name);
+                None
+            }
+        }).collect()
+    }
+
     fn open_editor() {
         let mut config_dir = config_dir().unwrap();
         config_dir.push("tokengen");
-        match create_dir_all(config_dir.as_path()) {
+        match create_private_dir(config_dir.as_path()) {
             Ok(_) => (),
             Err(e) => {
-                eprintln!("WARNING: Unable to create config directory '{}', error is {:#?}.", config_dir.to_string_lossy(), e);
+                print_warning!("Unable to create config directory '{}', error is {:#?}.", config_dir.to_string_lossy(), e);
                 exit(1);
             }
         }
         let mut config_filename = config_dir.clone();
         config_filename.push("config.json");
+        if !config_filename.exists() {
+            // Make sure the editor doesn't create it with default permissions
+            if let Err(e) = create_private_file(config_filename.as_path()) {
+                print_warning!("Unable to create config file '{}', error is {:#?}.", config_filename.to_string_lossy(), e);
+            }
+        }
         eprintln!("Opening editor to edit config file at '{}'...", config_filename.to_string_lossy());
         edit_file(config_filename).unwrap_or_default();
     }
 }
 
+// Top-level fields `config get` and `config set` accept
+const CONFIG_DEFAULTS: [&str; 21] = ["DefaultProfile", "DefaultClientId", "DefaultSecret", "DefaultTenant", "DefaultAuthority", "DefaultScope",
+    "Proxy", "ProxyUsername", "ProxyPassword", "NoProxy", "OnRefresh", "OnRefreshFile", "CacheMaxEntries", "RefreshTokenMaxAge", "KeepRawResponse",
+    "Aliases", "CacheFormat", "ExpirySkew", "MinRemaining", "Notify", "FailureCacheTtl"];
+
+// Example configuration written by `init`
+const CONFIG_TEMPLATE: &str = r#"// tokengen configuration, run `tokengen profile validate` after editing it
+{
+    "DefaultProfile": "MyUserProfile",       // Used if no profile is given on the command line
+    "DefaultTenant": "contoso.com",          // Tenant name or id, used if the profile doesn't have one
+    "Profiles": [
+        {
+            "Name": "MyAppProfile",
+            "Type": "App",                   // Client credentials flow
+            "ClientId": "00000000-0000-0000-0000-000000000000",  // Application (client) id of the app registration
+            "Secret": "<client secret>",     // Run `tokengen config encrypt` to protect it with a passphrase
+            "Resource": "https://management.azure.com/"
+        },
+        {
+            "Name": "MyUserProfile",
+            "Type": "User",                  // Device code flow, "Allow public client flows" must be enabled for the app
+            "ClientId": "00000000-0000-0000-0000-000000000000",
+            "Scope": "openid profile offline_access User.Read"  // `offline_access` keeps the sign-in refreshable
+        }
+    ]
+}
+"#;
+
+// Remove `//` comments, so the configuration file can be documented inline like the examples
+fn strip_comments(text: &str) -> String {
+    let mut ret = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    let (mut in_string, mut escaped) = (false, false);
+    while let Some(c) = chars.next() {
+        if in_string {
+            in_string = escaped || c != '"';
+            escaped = !escaped && c == '\\';
+        } else if c == '"' {
+            in_string = true;
+        } else if c == '/' && chars.peek() == Some(&'/') {
+            // Skip to the end of the line, keep the newline so error positions stay the same
+            while chars.peek().map(|&c| c != '\n').unwrap_or_default() {
+                chars.next();
+            }
+            continue;
+        }
+        ret.push(c);
+    }
+    ret
+}
+
+// `tokengen @name ...` runs with the arguments of `name` in `Aliases` of the configuration file, followed by the
+// rest of the command line. An alias is a string split like a shell does, or an array of the arguments
+fn expand_alias(args: Vec<OsString>) -> Vec<OsString> {
+    let name = match args.get(1).and_then(|a| a.to_str()).and_then(|a| a.strip_prefix('@')) {
+        Some(n) => n.to_owned(),
+        None => return args
+    };
+    let (_, config) = Configuration::read_file();
+    let alias: Vec<String> = match &config["Aliases"][&name] {
+        Value::String(s) => split_args(s),
+        Value::Array(a) if a.iter().all(|v| v.is_string()) => a.iter().filter_map(|v| v.as_str()).map(|s| s.to_owned()).collect(),
+        Value::Null => {
+            let names: Vec<String> = config["Aliases"].as_object().map(|m| m.keys().cloned().collect()).unwrap_or_default();
+            print_error!("Unknown alias '{}', defined aliases are {}.", name, if names.is_empty() { String::from("none") } else { names.join(", ") });
+            exit(1);
+        }
+        _ => {
+            print_error!("Alias '{}' must be a string or an array of strings.", name);
+            exit(1);
+        }
+    };
+    let mut expanded = vec![args[0].clone()];
+    expanded.extend(alias.into_iter().map(OsString::from));
+    expanded.extend(args.into_iter().skip(2));
+    expanded
+}
+
+// Write the example configuration, existing files are only replaced with `force`
+fn init_config(to_stdout: bool, force: bool) {
+    if to_stdout {
+        print!("{}", CONFIG_TEMPLATE);
+        return;
+    }
+    let mut config_filename = config_dir().unwrap();
+    config_filename.push("tokengen");
+    if let Err(e) = create_private_dir(config_filename.as_path()) {
+        print_error!("Unable to create config directory '{}', error is {:#?}.", config_filename.to_string_lossy(), e);
+        exit(1);
+    }
+    config_filename.push("config.json");
+    if config_filename.exists() && !force {
+        print_error!("Config file '{}' already exists, use '--force' to replace it.", config_filename.to_string_lossy());
+        exit(1);
+    }
+    let written = create_private_file(config_filename.as_path())
+        .and_then(|mut f| f.write_all(CONFIG_TEMPLATE.as_bytes()));
+    if let Err(e) = written {
+        print_error!("Unable to write config file '{}', error is {:#?}.", config_filename.to_string_lossy(), e);
+        exit(1);
+    }
+    eprintln!("Example config file has been written to '{}', edit it with 'tokengen -e'.", config_filename.to_string_lossy());
+}
+
+// `MY_API_` for profile `my-api`, the variable names are prefixed with it for multiple profiles
+fn env_prefix(profile_name: &str) -> String {
+    profile_name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+        .chain(if profile_name.is_empty() { None } else { Some('_') })
+        .collect()
+}
+
+// `AAD_ACCESS_TOKEN=...` lines for dotenv loaders
+fn dotenv_entries(profile_name: &str, token: &Token) -> String {
+    let prefix = env_prefix(profile_name);
+    let mut ret = String::new();
+    for (name, value) in [
+        ("ACCESS_TOKEN", token.get_token_string(TokenType::Access)),
+        ("ID_TOKEN", token.get_token_string(TokenType::Id)),
+        ("TOKEN_EXPIRES_ON", token.get_expires_on().to_string()),
+    ] {
+        if !value.is_empty() {
+            ret.push_str(&format!("AAD_{}{}={}\n", prefix, name, value));
+        }
+    }
+    ret
+}
+
+// The shell of `--format shell` if `--shell` is not given, PowerShell doesn't set `SHELL`
+fn detect_shell() -> &'static str {
+    match env::var("SHELL") {
+        Ok(s) if s.ends_with("fish") => "fish",
+        Ok(s) if !s.is_empty() => "bash",
+        _ if cfg!(target_os = "windows") || env::var_os("PSModulePath").is_some() => "powershell",
+        _ => "bash"
+    }
+}
+
+// A `Connect-AzAccount` command signing the Az PowerShell module in with the access token,
+// Az.Accounts 5 and later take the token as a SecureString
+fn az_powershell(token: &Token) -> String {
+    let access_token = token.get_token_string(TokenType::Access);
+    let claims = decode_claims(&access_token).unwrap_or_default();
+    // The username of `User` tokens, the app id of `App` tokens
+    let claim = |names: &[&str]| names.iter().find_map(|n| claims.get(n)?.as_str()).unwrap_or_default().to_owned();
+    let account_id = claim(&["upn", "unique_name", "preferred_username", "appid", "azp", "oid"]);
+    let tenant_id = claim(&["tid"]);
+    let mut ret = format!("Connect-AzAccount -AccessToken (ConvertTo-SecureString {} -AsPlainText -Force)", quote("powershell", &access_token));
+    if !account_id.is_empty() {
+        ret.push_str(&format!(" -AccountId {}", quote("powershell", &account_id)));
+    }
+    if !tenant_id.is_empty() {
+        ret.push_str(&format!(" -Tenant {}", quote("powershell", &tenant_id)));
+    }
+    ret
+}
+
+// Statements setting the token variables in the shell, i.e. `export AAD_ACCESS_TOKEN='...'`, for `eval`
+fn shell_exports(shell: &str, token: &Token) -> String {
+    let access_token = token.get_token_string(TokenType::Access);
+    let id_token = token.get_token_string(TokenType::Id);
+    let tenant_id = [&access_token, &id_token].iter()
+        .find_map(|t| decode_claims(t)?.get("tid")?.as_str().map(|s| s.to_owned()))
+        .unwrap_or_default();
+    let mut ret = String::new();
+    for (name, value) in [
+        ("ACCESS_TOKEN", access_token),
+        ("ID_TOKEN", id_token),
+        ("TOKEN_EXPIRES_ON", token.get_expires_on().to_string()),
+        ("TENANT_ID", tenant_id),
+    ] {
+        if value.is_empty() {
+            continue;
+        }
+        // Single quoted, so nothing in the value is expanded
+        let line = match shell {
+            "fish" => format!("set -gx AAD_{} {}", name, quote(shell, &value)),
+            "powershell" => format!("$env:AAD_{} = {}", name, quote(shell, &value)),
+            _ => format!("export AAD_{}={}", name, quote(shell, &value)),
+        };
+        ret.push_str(&line);
+        ret.push('\n');
+    }
+    ret
+}
+
+// Write the tokens of the profiles to a dotenv file, only readable by the current user as it has the tokens
+fn write_envfile(output: &str, profiles: &[Profile], tokens: &[Token], prefixed: bool) {
+    let mut text = format!("# Written by tokengen at {}\n", Local::now().format("%Y-%m-%d %H:%M:%S"));
+    for (p, t) in profiles.iter().zip(tokens.iter()) {
+        text.push_str(&dotenv_entries(if prefixed { p.get_name() } else { "" }, t));
+    }
+    let written = create_private_file(Path::new(output))
+        .and_then(|mut f| f.write_all(text.as_bytes()));
+    if let Err(e) = written {
+        print_error!("Unable to write '{}', error is {:#?}.", output, e);
+        exit(1);
+    }
+    let expires_on = tokens.iter().map(|t| t.get_expires_on()).min().unwrap_or_default();
+    eprintln!("Tokens have been written to '{}', they expire at {}.", output, bold(&Local.timestamp(expires_on, 0).format("%Y-%m-%d %H:%M:%S").to_string()));
+}
+
+// An `.envrc` snippet exporting the tokens, the tokens are fetched by tokengen when direnv loads it,
+// and direnv reloads it when the cache changes, i.e. after a sign-in or a refresh by the agent
+fn direnv_snippet(profiles: &[Profile], prefixed: bool) -> String {
+    let mut ret = String::from("# Written by `tokengen direnv`, fresh AAD tokens for the project\n");
+    if let Some(mut path) = cache_root() {
+        path.push("cache");
+        ret.push_str(&format!("watch_dir {}\n", quote("sh", &path.to_string_lossy())));
+    }
+    for p in profiles.iter() {
+        let prefix = env_prefix(if prefixed { p.get_name() } else { "" });
+        let command = format!("tokengen -p {} -f raw", quote("sh", p.get_name()));
+        ret.push_str(&format!("export AAD_{}ACCESS_TOKEN=\"$({} -k a)\"\n", prefix, command));
+        // Only `User` profiles have id tokens
+        if let Profile::User(_) = p {
+            ret.push_str(&format!("export AAD_{}ID_TOKEN=\"$({} -k i)\"\n", prefix, command));
+        }
+    }
+    ret
+}
+
+#[cfg(feature = "tui")]
+fn run_tui(profiles: Vec<Profile>) {
+    if profiles.is_empty() {
+        print_error!("No profile found, the profiles must be in the configuration file.");
+        exit(1);
+    }
+    if let Err(e) = tui::run(profiles) {
+        print_error!("Unable to run the dashboard, error is {:#?}.", e);
+        exit(1);
+    }
+}
+
+#[cfg(not(feature = "tui"))]
+fn run_tui(_profiles: Vec<Profile>) {
+    print_error!("The dashboard is not supported by this build, build it with the 'tui' feature.");
+    exit(1);
+}
+
+fn get_profile_list<'a>(matches: &'a ArgMatches) -> Vec<&'a str> {
+    matches.value_of("PROFILES")
+        .map(|s| s.split(',').map(str::trim).filter(|s| !s.is_empty()).collect())
+        .unwrap_or_default()
+}
+
+#[cfg(feature = "nogui")]
+fn copy_to_clipboard(_text: &str) {
+    print_error!("Clipboard is not supported by this build.");
+    exit(1);
+}
+
+#[cfg(not(feature = "nogui"))]
+fn copy_to_clipboard(text: &str) {
+    use clipboard::{ClipboardContext, ClipboardProvider};
+
+    let copied = ClipboardProvider::new().and_then(|mut ctx: ClipboardContext| {
+        ctx.set_contents(text.to_owned())
+    });
+    if let Err(e) = copied {
+        print_error!("Unable to copy the token to the clipboard, error is {:#?}.", e);
+        exit(1);
+    }
+}
+
+// Print the claims of the token to stderr, timestamps are shown in local time
+fn print_claims(token: &str) {
+    // Keep it apart from the token if both are on the terminal
+    eprintln!();
+    let claims = match decode_claims(token) {
+        Some(Value::Object(claims)) => claims,
+        _ => {
+            print_warning!("The token is not a JWT, there are no claims to show.");
+            return;
+        }
+    };
+    let width = claims.keys().map(|k| k.len()).max().unwrap_or_default();
+    for (name, value) in claims.iter() {
+        let text = match (name.as_str(), value) {
+            ("iat" | "nbf" | "exp" | "auth_time", Value::Number(n)) => {
+                let t = Local.timestamp(n.as_i64().unwrap_or_default(), 0);
+                format!("{} ({})", n, t.format("%Y-%m-%d %H:%M:%S"))
+            }
+            (_, Value::String(s)) => s.to_owned(),
+            _ => value.to_string()
+        };
+        eprintln!("{}  {}", bold(&format!("{:width$}", name, width = width)), text);
+    }
+}
+
+// Parse an optional number of seconds, 0 if it's not given
+fn get_seconds(matches: &ArgMatches, name: &str) -> u64 {
+    match matches.value_of(name).map(|s| (s, s.parse())) {
+        None => 0,
+        Some((_, Ok(v))) if v > 0 => v,
+        Some((s, _)) => {
+            print_error!("Invalid number of seconds {}.\n", s);
+            exit(1);
+        }
+    }
+}
+
 fn main() {
     let mut app = clap_app!(tokengen =>
         (version: "0.1")
         (author: "Chen Xu <windoze@0d0a.com>")
         (about: "Generate AzureAD token.")
-        (@arg PROFILE: -p --profile +takes_value "Profile Name")
-        (@arg TYPE: -y --type +takes_value "Profile type, can be 'App' or 'User'.")
-        (@arg CLIENT_ID: -c --client_id +takes_value "[All] AAD Client Id")
-        (@arg SECRET: -s --secret +takes_value "[App] Client Secret")
-        (@arg TENANT: -t --tenant +takes_value "[All] AAD Tenant")
-        (@arg AUTHORITY: -a --authority +takes_value "[All] Authority")
-        (@arg RESOURCE: -r --resource +takes_value "[App] Resource")
-        (@arg SCOPE: -o --scope +takes_value "[User] Scope")
+        (@arg PROFILE: -p --profile +takes_value +global "Profile Name")
+        (@arg TYPE: -y --type +takes_value +global "Profile type, can be 'App' or 'User'.")
+        (@arg CLIENT_ID: -c --client_id +takes_value +global "[All] AAD Client Id")
+        (@arg SECRET: -s --secret +takes_value +global "[All] Client Secret, optional for 'User' profiles.")
+        (@arg TENANT: -t --tenant +takes_value +global "[All] AAD Tenant")
+        (@arg AUTHORITY: -a --authority +takes_value +global "[All] Authority")
+        (@arg RESOURCE: -r --resource +takes_value +global "[App] Resource")
+        (@arg TOKEN_ENDPOINT_AUTH_METHOD: --("token-endpoint-auth-method") +takes_value +global possible_value[client_secret_post client_secret_basic] "[App] How the client secret is sent, default value is 'client_secret_post'.")
+        (@arg FEDERATED_CREDENTIAL: --("federated-credential") +takes_value +global "[App] Use the OIDC token of the CI job instead of a secret, 'github' for GitHub Actions, or 'azure-pipelines'.")
+        (@arg SCOPE: -o --scope +takes_value +global "[User] Scope")
+        (@arg AZURE_REGION: --("azure-region") +takes_value +global "[App] Azure region, i.e. 'westus2', or 'auto' to detect it, uses the regional token endpoint.")
+        (@arg LOGIN_HINT: --("login-hint") +takes_value +global "[User] Username of the account to sign in.")
+        (@arg DOMAIN_HINT: --("domain-hint") +takes_value +global "[User] Federated domain of the account, skips the home realm discovery.")
+        (@arg ACCOUNT: --account +takes_value +global "[User] Username of the account to use, each account has its own cached token.")
+        (@arg AUTH_TIMEOUT: --("auth-timeout") +takes_value +global "[User] Max seconds to wait for the device code sign-in.")
+        (@arg POLL_INTERVAL: --("poll-interval") +takes_value +global "[User] Seconds between polls during the device code sign-in.")
+        (@arg SHARED_CACHE: --("shared-cache") +global "[User] Share the sign-in with Azure PowerShell, Visual Studio and other Microsoft tools.")
+        (@arg IWA: --iwa +global "[User] Sign in with the Windows account through AD FS, falls back to the device code, Windows only.")
+        (@arg NOTIFY: --notify +global "[User] Show a desktop notification when the device code sign-in ends.")
+        (@arg VS_CODE: --vscode +global "[User] Use the sign-in of the Azure Account extension of VS Code, falls back to the device code.")
+        (@arg BROWSER: --browser +takes_value +global "[User] Browser to sign in with, can be 'default', 'firefox', 'chrome', 'edge', a command line, or 'none' to only print the URL.")
+        (@arg PROMPT: --prompt +takes_value +global possible_value[select_account consent login] "[User] Force account selection, consent, or login.")
+        (@arg CLIENT_CAPABILITIES: --("client-capabilities") +takes_value +global "[All] Comma separated client capabilities, i.e. 'cp1' to get Continuous Access Evaluation tokens.")
+        (@arg RESOLVE: --resolve +takes_value +global "[All] Comma separated 'host=address' pairs, send the requests to the hosts to the addresses instead, i.e. 'login.microsoftonline.com=http://127.0.0.1:8080'.")
+        (@arg AWS_ROLE_ARN: --("aws-role-arn") +takes_value +global "[All] AWS role to assume with the token for '--format aws-credential-process'.")
+        (@arg GCP_AUDIENCE: --("gcp-audience") +takes_value +global "[All] GCP workload identity pool provider exchanging the token for the 'gcp-*' formats.")
+        (@arg GCP_SERVICE_ACCOUNT: --("gcp-service-account") +takes_value +global "[All] GCP service account to impersonate for the 'gcp-*' formats.")
         (@arg TOKEN_TYPE: -k --token_type +takes_value "Token Type, can be 'a', 'i', 'ai', or 'ia', default value is 'ia'.")
-        (@arg FORMAT: -f --format +takes_value "Format, can be 'header' or 'raw', default value is 'header'.")
+        (@arg FORMAT: -f --format +takes_value "Format, can be 'header', 'raw', 'json', 'dotenv', 'shell', 'aws-credential-process', 'gcp-access-token', 'gcp-external-account', 'gcp-executable' or 'az-powershell', default value is 'header'.")
+        (@arg SHELL: --shell +takes_value possible_value[bash zsh fish powershell] "Shell of the 'shell' format, detected from the environment by default.")
+        (@arg DECODE: --decode "Also print the claims of the token to stderr.")
+        (@arg COPY: --copy "Copy the token to the clipboard instead of printing it.")
+        (@arg NEWLINE: --newline "Print a newline after the token.")
+        (@arg NO_NEWLINE: --("no-newline") conflicts_with[NEWLINE] "Don't print a newline after the token, this is the default.")
         (@arg EDIT: -e --edit "Open config file in the default editor.")
+        (@arg CLAIMS: --claims +takes_value "Claims challenge JSON returned by the resource, i.e. for Continuous Access Evaluation.")
+        (@arg ADDITIONAL_SCOPE: --("additional-scope") +takes_value conflicts_with[OFFLINE] "[User] Scopes to add to the scope of the profile, the cached refresh token is tried before signing in again to consent.")
+        (@arg POP: --pop "[User] Get a Proof-of-Possession token, prints a signed HTTP request carrying it instead of the token.")
+        (@arg POP_METHOD: --("pop-method") +takes_value requires[POP] "[User] HTTP method the signed HTTP request is bound to, i.e. 'GET'.")
+        (@arg POP_URL: --("pop-url") +takes_value requires[POP] "[User] URL the signed HTTP request is bound to.")
+        (@arg OFFLINE: --offline +global "Only use the cached token, never access the network.")
+        (@arg MIN_REMAINING: --("min-remaining") +takes_value "Seconds the token must stay valid for, the cached token is refreshed if it expires sooner, default value is 60.")
+        (@arg ASSERTION: --assertion +takes_value conflicts_with[OFFLINE] "[App] File containing the user assertion, '-' for stdin, gets a token on behalf of the user.")
+        (@arg ASSERTION_TYPE: --("assertion-type") +takes_value requires[ASSERTION] possible_value[jwt saml1 saml2] "[App] Assertion type, default value is 'jwt'.")
+        (@arg STRICT_AUDIENCE: --("strict-audience") "Fail if the audience of the token doesn't match the requested resource or scope.")
+        (@arg STRICT_PERMISSIONS: --("strict-permissions") +global "Refuse to use config or cache files accessible by other users.")
+        (@arg COLOR: --color +takes_value +global possible_value[auto always never] "Color the messages, default value is 'auto'.")
+        (@arg DEBUG_HTTP: --("debug-http") +global "Trace HTTP requests and responses to stderr, secrets are redacted.")
+        (@subcommand agent =>
+            (about: "Manage the background agent keeping cached tokens refreshed.")
+            (@subcommand install =>
+                (about: "Install the agent as a launchd agent (macOS) or a Scheduled Task (Windows).")
+                (@arg PROFILES: --profiles +takes_value "Comma separated profile names, default to all profiles.")
+                (@arg INTERVAL: --interval +takes_value "Refresh interval in minutes, default value is 30."))
+            (@subcommand uninstall =>
+                (about: "Uninstall the agent."))
+            (@subcommand run =>
+                (about: "Refresh the cached tokens once, this is what the agent runs.")
+                (@arg PROFILES: --profiles +takes_value "Comma separated profile names, default to all profiles.")
+                (@arg INTERVAL: --interval +takes_value "Refresh interval in minutes, default value is 30."))
+        )
+        (@subcommand warm =>
+            (about: "Make sure the profiles have valid cached tokens, sign in if needed.")
+            (@arg PROFILES: --profiles +takes_value "Comma separated profile names, default to all profiles.")
+            (@arg PARALLEL: --parallel +takes_value "Max number of concurrent requests, default value is 4."))
+        (@subcommand envfile =>
+            (about: "Write the tokens to a dotenv file, i.e. 'AAD_ACCESS_TOKEN=...', for local dev stacks using dotenv loaders.")
+            (@arg PROFILES: --profiles +takes_value "Comma separated profile names, the variable names are prefixed with the profile names, default to the selected profile.")
+            (@arg OUTPUT: --output +takes_value "The file to write, '.env.tokens' by default."))
+        (@subcommand direnv =>
+            (about: "Print an '.envrc' snippet exporting fresh tokens of the profiles, i.e. 'tokengen direnv >> .envrc'.")
+            (@arg PROFILES: --profiles +takes_value "Comma separated profile names, the variable names are prefixed with the profile names, default to the selected profile."))
+        (@subcommand vault_login =>
+            (name: "vault-login")
+            (about: "Exchange the token for a Vault token with the Vault JWT auth method, and print the Vault token.")
+            (@arg ADDRESS: --address +takes_value "Vault address, default to 'VAULT_ADDR'.")
+            (@arg NAMESPACE: --namespace +takes_value "Vault Enterprise namespace, default to 'VAULT_NAMESPACE'.")
+            (@arg MOUNT: --mount +takes_value "Path of the JWT auth method, 'jwt' by default.")
+            (@arg ROLE: --role +takes_value "Vault role, default to the default role of the auth method."))
+        (@subcommand tui =>
+            (about: "Show the profiles, the expiration and the claims of their cached tokens in a dashboard.")
+            (@arg PROFILES: --profiles +takes_value "Comma separated profile names, default to all profiles."))
+        (@subcommand logout =>
+            (about: "Remove the cached tokens of the profile.")
+            (@arg ALL: --all "Remove all cached tokens.")
+            (@arg SIGNOUT: --signout conflicts_with[ALL] "Also sign out the account in the browser, 'User' profiles only.")
+            (@arg REVOKE: --revoke conflicts_with[ALL] "Also revoke the refresh token on the server, 'User' profiles only.")
+            (@arg REVOKE_SESSIONS: --("revoke-sessions") requires[REVOKE] "Revoke all sign-in sessions of the user through Microsoft Graph if there is no revocation endpoint, i.e. with AAD, it signs out all devices and apps."))
+        (@subcommand cache =>
+            (about: "Manage the token cache.")
+            (@subcommand export =>
+                (about: "Print the cached tokens without the refresh tokens, in the cache format, 'CacheFormat' in the configuration file.")
+                (@arg JSON: --json "Print JSON even if the cache format is binary, for inspection.")))
+        (@subcommand profile =>
+            (about: "Manage profiles.")
+            (@subcommand validate =>
+                (about: "Check the profiles for missing or suspicious values.")
+                (@arg NAME: "Profile name, default to all profiles.")
+                (@arg LIVE: --live "Also try the profiles against AAD, 'User' profiles only request a device code.")))
+        (@subcommand doctor =>
+            (about: "Check the configuration, the cache, the network and the clock, and print a report for troubleshooting."))
+        (@subcommand init =>
+            (about: "Write an example configuration file with one 'App' and one 'User' profile.")
+            (@arg STDOUT: --stdout "Print it to stdout instead.")
+            (@arg FORCE: --force conflicts_with[STDOUT] "Replace the existing configuration file."))
+        (@subcommand keygen =>
+            (about: "Create a client certificate with a non-exportable key in the TPM, Windows only.")
+            (@arg SUBJECT: --subject +takes_value "Subject of the certificate, 'CN=tokengen' by default.")
+            (@arg OUTPUT: --output +takes_value "File to save the public certificate to, 'tokengen.cer' by default.")
+            (@arg YEARS: --years +takes_value "Years the certificate is valid for, 1 by default."))
+        (@subcommand config =>
+            (about: "Manage the configuration file.")
+            (@subcommand encrypt =>
+                (about: "Encrypt the secrets in the configuration file with a passphrase."))
+            (@subcommand get =>
+                (about: "Print a field of the configuration file, i.e. 'DefaultTenant' or 'SomeProfile.ClientId'.")
+                (@arg KEY: +required "Top-level field name, or '<Profile>.<Field>'."))
+            (@subcommand set =>
+                (about: "Set a field of the configuration file, the profile is added if it doesn't exist.")
+                (@arg JSON: --json "Parse the value as JSON instead of a string.")
+                (@arg KEY: +required "Top-level field name, or '<Profile>.<Field>'.")
+                (@arg VALUE: +required "The new value.")))
+        (@subcommand refresh =>
+            (about: "Refresh the cached token now even if it's still valid, and print the new token."))
+        (@subcommand ttl =>
+            (about: "Print the remaining seconds of the cached token, fails if there is no valid one."))
+        (@subcommand auth =>
+            (about: "Sign in with the device code step by step, for wrapper scripts and UIs, 'User' profiles only.")
+            (@subcommand start =>
+                (about: "Request the device code and print the user code and the handle of the sign-in as JSON, without waiting."))
+            (@subcommand poll =>
+                (about: "Check the sign-in once, print the token if the user has signed in, exits with code 6 if not yet.")
+                (@arg HANDLE: +required "The handle printed by 'auth start'.")))
+        (@subcommand import_token =>
+            (name: "import-token")
+            (about: "Cache a token response JSON acquired on another machine for the selected profile, so it can be used offline.")
+            (@arg FILE: "File containing the token response, stdin if missing or '-'."))
+        (@subcommand kubelogin =>
+            (about: "Get AKS tokens like kubelogin, also implied if the binary is named 'kubelogin', to replace it in kubeconfigs.")
+            (@subcommand get_token =>
+                (name: "get-token")
+                (about: "Print the token as a Kubernetes ExecCredential, takes the flags of 'kubelogin get-token'.")
+                (@arg SERVER_ID: --("server-id") +takes_value +required "Application id of the AKS AAD server.")
+                (@arg KUBE_CLIENT_ID: --("client-id") +takes_value "Application id of the client, or of the service principal with 'spn'.")
+                (@arg TENANT_ID: --("tenant-id") +takes_value "AAD tenant id.")
+                (@arg LOGIN: -l --login +takes_value possible_value[devicecode interactive spn] "Login method, default value is 'devicecode', 'interactive' also signs in with the device code.")
+                (@arg KUBE_SECRET: --("client-secret") +takes_value "Client secret of the service principal with 'spn'.")
+                (@arg ENVIRONMENT: --environment +takes_value "Azure cloud, i.e. 'AzureChinaCloud', default value is 'AzurePublicCloud'.")
+                (@arg TOKEN_CACHE_DIR: --("token-cache-dir") +takes_value "Ignored, tokens are kept in the cache of tokengen.")))
+        (@subcommand last_response =>
+            (name: "last-response")
+            (about: "Print the raw JSON of the last token response of the profile, needs 'KeepRawResponse' in the configuration file."))
     );
-    let matches = app.clone().get_matches();
+    let matches = app.clone().get_matches_from(expand_alias(kubelogin::expand_args(env::args_os().collect())));
 
     let profile = matches.value_of("PROFILE").unwrap_or_default();
     let profile_type = matches.value_of("TYPE").unwrap_or_default();
-    let client_id = matches.value_of("CLIENT_ID").unwrap_or_default();
-    let secret = matches.value_of("SECRET").unwrap_or_default();
-    let tenant = matches.value_of("TENANT").unwrap_or_default();
-    let authority = matches.value_of("AUTHORITY").unwrap_or_default();
-    let resource = matches.value_of("RESOURCE").unwrap_or_default();
-    let scope = matches.value_of("SCOPE").unwrap_or_default();
+    let overrides = ProfileOverrides {
+        client_id: matches.value_of("CLIENT_ID").unwrap_or_default().to_owned(),
+        secret: matches.value_of("SECRET").unwrap_or_default().to_owned(),
+        tenant: matches.value_of("TENANT").unwrap_or_default().to_owned(),
+        authority: matches.value_of("AUTHORITY").unwrap_or_default().to_owned(),
+        resource: matches.value_of("RESOURCE").unwrap_or_default().to_owned(),
+        scope: matches.value_of("SCOPE").unwrap_or_default().to_owned(),
+        login_hint: matches.value_of("LOGIN_HINT").unwrap_or_default().to_owned(),
+        domain_hint: matches.value_of("DOMAIN_HINT").unwrap_or_default().to_owned(),
+        prompt: matches.value_of("PROMPT").unwrap_or_default().to_owned(),
+        azure_region: matches.value_of("AZURE_REGION").unwrap_or_default().to_owned(),
+        account: matches.value_of("ACCOUNT").unwrap_or_default().to_owned(),
+        client_capabilities: matches.value_of("CLIENT_CAPABILITIES").unwrap_or_default()
+            .split(',')
+            .map(|s| s.trim().to_owned())
+            .filter(|s| !s.is_empty())
+            .collect(),
+        token_endpoint_auth_method: matches.value_of("TOKEN_ENDPOINT_AUTH_METHOD").unwrap_or_default().to_owned(),
+        federated_credential: matches.value_of("FEDERATED_CREDENTIAL").unwrap_or_default().to_owned(),
+        auth_timeout: get_seconds(&matches, "AUTH_TIMEOUT"),
+        poll_interval: get_seconds(&matches, "POLL_INTERVAL"),
+        shared_cache: matches.is_present("SHARED_CACHE"),
+        integrated_windows_auth: matches.is_present("IWA"),
+        vs_code: matches.is_present("VS_CODE"),
+        browser: matches.value_of("BROWSER").unwrap_or_default().to_owned(),
+        resolve: matches.value_of("RESOLVE").unwrap_or_default()
+            .split(',')
+            .filter_map(|s| s.split_once('='))
+            .map(|(k, v)| (k.trim().to_owned(), v.trim().to_owned()))
+            .collect(),
+        aws_role_arn: matches.value_of("AWS_ROLE_ARN").unwrap_or_default().to_owned(),
+        gcp_audience: matches.value_of("GCP_AUDIENCE").unwrap_or_default().to_owned(),
+        gcp_service_account: matches.value_of("GCP_SERVICE_ACCOUNT").unwrap_or_default().to_owned(),
+    };
     let format = matches.value_of("FORMAT").unwrap_or("header");
     let token_type_str = matches.value_of("TOKEN_TYPE").unwrap_or("ia");
     let token_type = match token_type_str {
@@ -169,37 +885,593 @@ fn main() {
         "ai" => TokenType::AccessOrId,
         "ia" => TokenType::IdOrAccess,
         _ => {
-            eprintln!("ERROR: Invalid token type {}.\n", token_type_str);
+            print_error!("Invalid token type {}.\n", token_type_str);
             exit(1);
         }
     };
 
+    set_color(matches.value_of("COLOR").unwrap_or("auto"));
+    set_debug_http(matches.is_present("DEBUG_HTTP"));
+    // Only the mode of the files is checked, on Windows they're private through the ACL of the directories instead
+    if cfg!(windows) && matches.is_present("STRICT_PERMISSIONS") {
+        print_error!("'--strict-permissions' is not supported on Windows.");
+        exit(1);
+    }
+    set_strict_permissions(matches.is_present("STRICT_PERMISSIONS"));
+    register_secret(&overrides.secret);
+
+    // They always go to the network, the others only use the cached tokens offline, and any request they'd still
+    // send fails instead
+    if matches.is_present("OFFLINE") {
+        let online = match matches.subcommand() {
+            (name @ ("refresh" | "vault-login" | "auth"), _) => Some(name),
+            ("logout", Some(m)) if m.is_present("REVOKE") || m.is_present("SIGNOUT") => Some("logout --revoke/--signout"),
+            ("profile", Some(m)) if m.subcommand_matches("validate").is_some_and(|m| m.is_present("LIVE")) => Some("profile validate --live"),
+            _ if format.starts_with("aws") || format == "gcp-access-token" => Some(format),
+            _ => None
+        };
+        if let Some(name) = online {
+            print_error!("'{}' needs the network, it can't be used with '--offline'.", name);
+            exit(1);
+        }
+    }
+    set_offline(matches.is_present("OFFLINE"));
+
     if matches.is_present("EDIT") {
         Configuration::open_editor();
         exit(0);
     }
 
+    if let ("doctor", Some(_)) = matches.subcommand() {
+        doctor::run();
+    }
+
+    if let ("init", Some(m)) = matches.subcommand() {
+        init_config(m.is_present("STDOUT"), m.is_present("FORCE"));
+        exit(0);
+    }
+
+    if let ("keygen", Some(m)) = matches.subcommand() {
+        let years_str = m.value_of("YEARS").unwrap_or("1");
+        let years: u32 = match years_str.parse() {
+            Ok(v) if v > 0 => v,
+            _ => {
+                print_error!("Invalid years {}.", years_str);
+                exit(1);
+            }
+        };
+        keygen::generate(m.value_of("SUBJECT").unwrap_or("CN=tokengen"), m.value_of("OUTPUT").unwrap_or("tokengen.cer"), years);
+        exit(0);
+    }
+
+    if let ("config", Some(m)) = matches.subcommand() {
+        match m.subcommand_name() {
+            Some("encrypt") => Configuration::encrypt_file(),
+            Some("get") => {
+                let m = m.subcommand_matches("get").unwrap();
+                Configuration::get_field(m.value_of("KEY").unwrap());
+            }
+            Some("set") => {
+                let m = m.subcommand_matches("set").unwrap();
+                Configuration::set_field(m.value_of("KEY").unwrap(), m.value_of("VALUE").unwrap(), m.is_present("JSON"));
+            }
+            _ => {
+                eprintln!("{}", m.usage());
+                exit(1);
+            }
+        }
+        exit(0);
+    }
+
     let cfg = Configuration::load();
-    let profile = cfg.get_profile(
-        profile,
-        profile_type,
-        client_id,
-        secret,
-        tenant,
-        authority,
-        resource,
-        scope,
-    );
-    if !profile.is_valid() {
-        eprintln!("ERROR: Missing command line arguments.\n");
-        app.print_help().unwrap();
-        println!();
-        exit(1)
-    }
-    let token = profile.get_token();
-    if format.starts_with("h") {
-        print!("Authorization: Bearer {}", token.get_token_string(token_type));
-    } else if format.starts_with("r") {
-        print!("{}", token.get_token_string(token_type));
+    set_proxy_settings(ProxySettings {
+        url: cfg.proxy.clone(),
+        username: cfg.proxy_username.clone(),
+        password: cfg.proxy_password.clone(),
+        no_proxy: cfg.no_proxy.clone(),
+    });
+    set_cache_policy(CachePolicy {
+        max_entries: cfg.cache_max_entries,
+        refresh_token_max_age: cfg.refresh_token_max_age * 24 * 3600,
+        keep_raw_response: cfg.keep_raw_response,
+        binary: cfg.cache_format.eq_ignore_ascii_case("cbor"),
+        failure_ttl: cfg.failure_cache_ttl.unwrap_or(60).max(0),
+    });
+    if let Some(skew) = cfg.expiry_skew {
+        set_expiry_skew(skew);
+    }
+    let min_seconds = match matches.value_of("MIN_REMAINING").map(|v| v.parse::<i64>()) {
+        Some(Ok(v)) if v >= 0 => Some(v),
+        Some(_) => {
+            print_error!("'--min-remaining' must be a number of seconds.");
+            exit(1);
+        }
+        None => cfg.min_remaining
+    };
+    if let Some(v) = min_seconds {
+        set_min_remaining(v);
+    }
+    set_notify(cfg.notify || matches.is_present("NOTIFY"));
+    if !matches!(cfg.cache_format.to_lowercase().as_str(), "" | "json" | "cbor") {
+        print_warning!("Unknown cache format '{}', can be 'json' or 'cbor', 'json' is used.", cfg.cache_format);
+    }
+
+    // The profile selected by the command line arguments, only resolved when needed
+    let mut get_selected_profile = || {
+        let profile = cfg.get_profile(profile, profile_type, &overrides);
+        if !profile.is_valid() {
+            print_error!("Missing command line arguments.\n");
+            app.print_help().unwrap();
+            println!();
+            exit(1)
+        }
+        profile
+    };
+
+    if let ("agent", Some(m)) = matches.subcommand() {
+        let (sub, m) = match m.subcommand() {
+            (sub, Some(m)) => (sub, m),
+            _ => {
+                eprintln!("{}", m.usage());
+                exit(1);
+            }
+        };
+        let profiles = get_profile_list(m);
+        let interval_str = m.value_of("INTERVAL").unwrap_or("30");
+        let interval: u64 = match interval_str.parse() {
+            Ok(v) if v > 0 => v,
+            _ => {
+                print_error!("Invalid interval {}.\n", interval_str);
+                exit(1);
+            }
+        };
+        match sub {
+            "install" => agent::install(&profiles, interval),
+            "uninstall" => agent::uninstall(),
+            _ if crypt::is_encrypted(&cfg.proxy_password) && !crypt::can_decrypt() => {
+                print_error!("'ProxyPassword' is encrypted and there is no terminal to ask for the passphrase, set 'TOKENGEN_PASSPHRASE'.");
+                exit(1);
+            }
+            _ => agent::run(&cfg.get_named_profiles(&profiles), interval, &agent::RefreshHook {
+                command: &cfg.on_refresh,
+                file: &cfg.on_refresh_file,
+            }),
+        }
+        exit(0);
+    }
+
+    if let ("warm", Some(m)) = matches.subcommand() {
+        let parallel_str = m.value_of("PARALLEL").unwrap_or("4");
+        let parallel: usize = match parallel_str.parse() {
+            Ok(v) if v > 0 => v,
+            _ => {
+                print_error!("Invalid parallelism {}.\n", parallel_str);
+                exit(1);
+            }
+        };
+        let profiles = cfg.get_named_profiles(&get_profile_list(m));
+        for (p, token) in profiles.iter().zip(Profile::get_tokens(&profiles, parallel)) {
+            let expires_on = Local.timestamp(token.get_expires_on(), 0);
+            eprintln!("Profile '{}' is {}, token expires at {}.", p.get_name(), green("ready"), bold(&expires_on.format("%Y-%m-%d %H:%M:%S").to_string()));
+        }
+        exit(0);
+    }
+
+    if let ("envfile", Some(m)) = matches.subcommand() {
+        let output = m.value_of("OUTPUT").unwrap_or(".env.tokens");
+        if m.is_present("PROFILES") {
+            let profiles = cfg.get_named_profiles(&get_profile_list(m));
+            let tokens = Profile::get_tokens(&profiles, 4);
+            write_envfile(output, &profiles, &tokens, true);
+        } else {
+            let profile = get_selected_profile();
+            let token = profile.get_token();
+            write_envfile(output, &[profile], &[token], false);
+        }
+        exit(0);
+    }
+
+    if let ("tui", Some(m)) = matches.subcommand() {
+        run_tui(cfg.get_named_profiles(&get_profile_list(m)));
+        exit(0);
+    }
+
+    if let ("direnv", Some(m)) = matches.subcommand() {
+        // The snippet runs tokengen with the profile names, so only configured profiles can be used
+        let (names, prefixed) = if m.is_present("PROFILES") {
+            (get_profile_list(m), true)
+        } else if profile.is_empty() {
+            (vec![cfg.default_profile.as_str()], false)
+        } else {
+            (vec![profile], false)
+        };
+        let profiles = cfg.get_named_profiles(&names);
+        if profiles.is_empty() {
+            print_error!("No profile found, the profiles must be in the configuration file.");
+            exit(1);
+        }
+        print!("{}", direnv_snippet(&profiles, prefixed));
+        exit(0);
+    }
+
+    if let ("logout", Some(m)) = matches.subcommand() {
+        if m.is_present("ALL") {
+            Profile::clear_cache();
+            eprintln!("All cached tokens have been removed.");
+        } else {
+            let profile = get_selected_profile();
+            // The refresh token is removed locally even if the server refuses to revoke it
+            if m.is_present("REVOKE") {
+                match profile.revoke_cached_token(m.is_present("REVOKE_SESSIONS")) {
+                    Ok(_) => eprintln!("Refresh token has been revoked."),
+                    Err(e) => print_warning!("Unable to revoke the refresh token: {}.", e)
+                }
+            }
+            if profile.remove_cached_token() {
+                eprintln!("Cached token has been removed.");
+            } else {
+                eprintln!("No cached token found.");
+            }
+            if m.is_present("SIGNOUT") {
+                profile.sign_out();
+            }
+        }
+        exit(0);
+    }
+
+    if let ("cache", Some(m)) = matches.subcommand() {
+        let m = match m.subcommand() {
+            ("export", Some(m)) => m,
+            _ => {
+                eprintln!("{}", m.usage());
+                exit(1);
+            }
+        };
+        let binary = cfg.cache_format.eq_ignore_ascii_case("cbor") && !m.is_present("JSON");
+        if binary && atty::is(atty::Stream::Stdout) {
+            print_error!("The cache format is binary, redirect the output to a file, or use '--json'.");
+            exit(1);
+        }
+        let mut data = Profile::export_cache(binary);
+        if !binary {
+            data.push(b'\n');
+        }
+        if let Err(e) = io::stdout().write_all(&data) {
+            print_error!("Unable to write the cache, error is {:#?}.", e);
+            exit(1);
+        }
+        exit(0);
+    }
+
+    if let ("profile", Some(m)) = matches.subcommand() {
+        let m = match m.subcommand() {
+            ("validate", Some(m)) => m,
+            _ => {
+                eprintln!("{}", m.usage());
+                exit(1);
+            }
+        };
+        let names: Vec<&str> = m.value_of("NAME").into_iter().collect();
+        let profiles = cfg.get_named_profiles(&names);
+        let mut failed = profiles.is_empty();
+        for p in profiles.iter() {
+            let (errors, warnings) = p.validate();
+            for e in errors.iter() {
+                print_error!("Profile '{}': {}", p.get_name(), e);
+            }
+            for w in warnings.iter() {
+                print_warning!("Profile '{}': {}", p.get_name(), w);
+            }
+            if !errors.is_empty() {
+                failed = true;
+                continue;
+            }
+            if m.is_present("LIVE") {
+                if let Err(e) = p.test_live() {
+                    print_error!("Profile '{}': {}", p.get_name(), e);
+                    failed = true;
+                    continue;
+                }
+            }
+            eprintln!("Profile '{}' is {}.", p.get_name(), green("OK"));
+        }
+        exit(if failed { 1 } else { 0 });
+    }
+
+    if let ("auth", Some(m)) = matches.subcommand() {
+        match m.subcommand() {
+            ("start", Some(_)) => {
+                match get_selected_profile().start_sign_in() {
+                    Ok(v) => println!("{}", v),
+                    Err(e) => {
+                        print_error!("{}", e);
+                        exit(2);
+                    }
+                }
+                exit(0);
+            }
+            // Prints the token below
+            ("poll", Some(_)) => (),
+            _ => {
+                eprintln!("{}", m.usage());
+                exit(1);
+            }
+        }
+    }
+
+    if let ("import-token", Some(m)) = matches.subcommand() {
+        let path = m.value_of("FILE").unwrap_or("-");
+        let response = if path == "-" {
+            let mut s = String::new();
+            stdin().read_to_string(&mut s).map(|_| s)
+        } else {
+            read_to_string(path)
+        };
+        let response = response.unwrap_or_else(|e| {
+            print_error!("Unable to read the token response from '{}', error is {:#?}.", path, e);
+            exit(1);
+        });
+        let profile = get_selected_profile();
+        match profile.import_token(&response) {
+            Ok(t) => eprintln!("Token has been imported, it expires at {}.", Local.timestamp(t.get_expires_on(), 0)),
+            Err(e) => {
+                print_error!("{}", e);
+                exit(1);
+            }
+        }
+        exit(0);
+    }
+
+    if let ("kubelogin", Some(m)) = matches.subcommand() {
+        let m = match m.subcommand_matches("get-token") {
+            Some(m) => m,
+            None => {
+                eprintln!("{}", m.usage());
+                exit(1);
+            }
+        };
+        let (profile_type, overrides) = kubelogin::profile_overrides(m, &overrides);
+        register_secret(&overrides.secret);
+        let profile = cfg.new_profile(profile_type, &overrides);
+        if !profile.is_valid() {
+            print_error!("The client id and the tenant id are missing, set '--client-id' and '--tenant-id'.");
+            exit(1);
+        }
+        println!("{}", kubelogin::exec_credential(&profile.get_token()));
+        exit(0);
+    }
+
+    if let ("ttl", Some(_)) = matches.subcommand() {
+        match get_selected_profile().get_cached_token() {
+            Some(t) => {
+                println!("{}", t.get_expires_on() - Utc::now().timestamp());
+                exit(0);
+            }
+            None => exit(4)
+        }
+    }
+
+    if let ("last-response", Some(_)) = matches.subcommand() {
+        match get_selected_profile().get_last_response() {
+            Some(r) => {
+                println!("{}", serde_json::to_string_pretty(&r).unwrap_or_default());
+                exit(0);
+            }
+            None => {
+                print_error!("No token response is kept for the profile, set 'KeepRawResponse' in the configuration file and get a new token.");
+                exit(4);
+            }
+        }
+    }
+
+    let vault_address = env::var("VAULT_ADDR").unwrap_or_default();
+    let vault_namespace = env::var("VAULT_NAMESPACE").unwrap_or_default();
+    let vault = matches.subcommand_matches("vault-login").map(|m| vault::VaultLogin {
+        address: m.value_of("ADDRESS").unwrap_or(&vault_address),
+        namespace: m.value_of("NAMESPACE").unwrap_or(&vault_namespace),
+        mount: m.value_of("MOUNT").unwrap_or("jwt"),
+        role: m.value_of("ROLE").unwrap_or_default(),
+    });
+    if let Some(v) = vault.as_ref() {
+        // Checked before signing in
+        if v.address.is_empty() {
+            print_error!("The Vault address is missing, set '--address' or 'VAULT_ADDR'.");
+            exit(1);
+        }
+    }
+
+    let mut profile = get_selected_profile();
+    let aws = format.starts_with("aws");
+    if aws && profile.get_aws_role_arn().is_empty() {
+        print_error!("The AWS role is missing, set 'AwsRoleArn' of the profile or '--aws-role-arn'.");
+        exit(1);
+    }
+    let (gcp_audience, gcp_service_account) = profile.get_gcp_federation();
+    // `gcp-executable` only prints the token for the credentials file, which has the audience
+    if (format == "gcp-access-token" || format == "gcp-external-account") && gcp_audience.is_empty() {
+        print_error!("The GCP workload identity pool provider is missing, set 'GcpAudience' of the profile or '--gcp-audience'.");
+        exit(1);
+    }
+    if format == "gcp-external-account" {
+        // The credentials file runs tokengen to get the token when it's used
+        if profile.get_name().is_empty() {
+            print_error!("The credentials file can only use profiles in the configuration file.");
+            exit(1);
+        }
+        println!("{}", gcp::external_account(gcp_audience, gcp_service_account, profile.get_name(), token_type_str));
+        exit(0);
+    }
+    if let Some(claims) = matches.value_of("CLAIMS") {
+        if !serde_json::from_str::<serde_json::Value>(claims).map(|v| v.is_object()).unwrap_or_default() {
+            print_error!("Invalid claims {}, it must be a JSON object.\n", claims);
+            exit(1);
+        }
+        profile = profile.with_claims(claims);
+    }
+    if let Some(scope) = matches.value_of("ADDITIONAL_SCOPE") {
+        if !matches!(profile, Profile::User(_)) {
+            print_error!("Additional scopes are only supported by 'User' profiles.");
+            exit(1);
+        }
+        profile = profile.with_additional_scope(scope);
+    }
+    let pop = matches.is_present("POP");
+    if pop {
+        if !matches!(profile, Profile::User(_)) {
+            print_error!("Proof-of-Possession tokens are only supported by 'User' profiles.");
+            exit(1);
+        }
+        if vault.is_some() || !(format.starts_with("h") || format.starts_with("r") || format.starts_with("j")) {
+            print_error!("Proof-of-Possession tokens can only be printed in the 'header', 'raw' or 'json' format.");
+            exit(1);
+        }
+        profile = profile.with_pop();
+    }
+    if let Some(path) = matches.value_of("ASSERTION") {
+        let assertion = if path == "-" {
+            let mut s = String::new();
+            stdin().read_to_string(&mut s).map(|_| s)
+        } else {
+            read_to_string(path)
+        };
+        match assertion {
+            Ok(a) if !a.trim().is_empty() => {
+                register_secret(a.trim());
+                profile = profile.with_assertion(a.trim(), matches.value_of("ASSERTION_TYPE").unwrap_or("jwt"));
+            }
+            Ok(_) => {
+                print_error!("The assertion is empty.");
+                exit(1);
+            }
+            Err(e) => {
+                print_error!("Unable to read assertion from '{}', error is {:#?}.", path, e);
+                exit(1);
+            }
+        }
+    }
+    if matches.is_present("PROMPT") && !matches.is_present("OFFLINE") {
+        // Prompting only makes sense with a new sign-in
+        profile.remove_cached_token();
+    }
+    let token = if let ("refresh", Some(_)) = matches.subcommand() {
+        match profile.force_refresh() {
+            Some(t) => t,
+            None => {
+                print_error!("No cached token can be refreshed, sign in first.");
+                exit(4);
+            }
+        }
+    } else if let Some(m) = matches.subcommand_matches("auth").and_then(|m| m.subcommand_matches("poll")) {
+        match profile.poll_sign_in(m.value_of("HANDLE").unwrap_or_default()) {
+            Ok(Some(t)) => t,
+            Ok(None) => {
+                eprintln!("The user hasn't signed in yet.");
+                exit(6);
+            }
+            Err(e) => {
+                print_error!("{}", e);
+                exit(2);
+            }
+        }
+    } else if matches.is_present("OFFLINE") {
+        match profile.get_cached_token() {
+            Some(t) => t,
+            None => {
+                print_error!("No valid cached token found in offline mode.");
+                exit(4);
+            }
+        }
+    } else {
+        profile.get_token()
+    };
+    // AAD decides the lifetime, a new token may still expire sooner than asked for
+    let remaining = token.get_expires_on() - Utc::now().timestamp();
+    if remaining < min_remaining() {
+        print_warning!("The token expires in {} second(s), less than the {} second(s) asked for.", remaining, min_remaining());
+    }
+    // Catch mistakes like using a Graph token to call ARM, only access tokens are for the resource
+    if token.get_token_string(token_type) == token.get_token_string(TokenType::Access) {
+        if let Some(e) = profile.check_audience(&token) {
+            if matches.is_present("STRICT_AUDIENCE") {
+                print_error!("{}", e);
+                exit(2);
+            }
+            print_warning!("{}", e);
+        }
+    }
+    if let Some(v) = vault.as_ref() {
+        let (vault_token, lease_duration) = vault::login(v, &token.get_token_string(token_type));
+        eprintln!("Vault token expires in {} second(s).", lease_duration);
+        if matches.is_present("COPY") {
+            copy_to_clipboard(&vault_token);
+            eprintln!("Token has been copied to the clipboard.");
+        } else {
+            print!("{}{}", vault_token, if matches.is_present("NEWLINE") { "\n" } else { "" });
+        }
+        exit(0);
+    }
+    if aws {
+        // The JSON is read by the AWS CLI and SDKs, newlines and the clipboard don't apply
+        let credentials = aws::assume_role(profile.get_aws_role_arn(), profile.get_name(), &token.get_token_string(token_type));
+        print!("{}", credentials);
+        exit(0);
+    }
+    let newline = if matches.is_present("NEWLINE") { "\n" } else { "" };
+    if format == "gcp-executable" {
+        print!("{}", gcp::executable_response(&token.get_token_string(token_type), token.get_expires_on()));
+        exit(0);
+    }
+    if format == "gcp-access-token" {
+        let (gcp_audience, gcp_service_account) = profile.get_gcp_federation();
+        print!("{}{}", gcp::access_token(gcp_audience, gcp_service_account, &token.get_token_string(token_type)), newline);
+        exit(0);
+    }
+    if format.starts_with("az") {
+        print!("{}{}", az_powershell(&token), newline);
+        exit(0);
+    }
+    // The resource takes the signed HTTP request carrying the access token instead of the token itself
+    let (scheme, output) = if pop {
+        let shr = match PopKey::load() {
+            Ok(key) => key.signed_http_request(
+                &token.get_token_string(TokenType::Access),
+                matches.value_of("POP_METHOD").unwrap_or_default(),
+                matches.value_of("POP_URL").unwrap_or_default(),
+            ),
+            Err(e) => {
+                print_error!("{}.", e);
+                exit(1);
+            }
+        };
+        ("PoP", shr)
+    } else {
+        ("Bearer", token.get_token_string(token_type))
+    };
+    if matches.is_present("COPY") {
+        copy_to_clipboard(&output);
+        eprintln!("Token has been copied to the clipboard.");
+    } else {
+        // No trailing newline by default, so the output can be embedded with `$(tokengen ...)` as is
+        if format.starts_with("h") {
+            print!("Authorization: {} {}{}", scheme, output, newline);
+        } else if format.starts_with("r") {
+            print!("{}{}", output, newline);
+        } else if format.starts_with("j") {
+            let mut doc = token.to_json();
+            if pop {
+                doc["token_type"] = json!("pop");
+                doc["signed_http_request"] = json!(output);
+            }
+            print!("{}{}", doc, newline);
+        } else if format.starts_with("d") {
+            print!("{}", dotenv_entries("", &token));
+        } else if format.starts_with("s") {
+            print!("{}", shell_exports(matches.value_of("SHELL").unwrap_or_else(|| detect_shell()), &token));
+        }
+    }
+    if matches.is_present("DECODE") {
+        // The token is printed first
+        io::stdout().flush().unwrap_or_default();
+        print_claims(&token.get_token_string(token_type));
     }
 }
@@ -0,0 +1,148 @@
+use std::fs::{create_dir_all, remove_file, rename, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use fs2::FileExt;
+
+static STRICT: AtomicBool = AtomicBool::new(false);
+
+// Refuse to use config and cache files readable by other users
+pub fn set_strict_permissions(enabled: bool) {
+    STRICT.store(enabled, Ordering::Relaxed);
+}
+
+#[cfg(unix)]
+fn restrict(path: &Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+}
+
+#[cfg(unix)]
+fn restrict_dir(path: &Path) -> io::Result<()> {
+    restrict(path, 0o700)
+}
+
+#[cfg(windows)]
+fn restrict_dir(path: &Path) -> io::Result<()> {
+    // Remove inherited ACEs and grant access to the current user only, files and directories created in it inherit
+    // the ACL, so it's only done once when the directory is created
+    let user = std::env::var("USERNAME").unwrap_or_default();
+    let status = std::process::Command::new("icacls")
+        .arg(path)
+        .args(&["/inheritance:r", "/grant:r"])
+        .arg(format!("{}:(OI)(CI)F", user))
+        .output()?
+        .status;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::new(io::ErrorKind::Other, "icacls failed"))
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+fn restrict_dir(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+// Create a directory and its missing parents only accessible by the current user, existing ones are left untouched
+pub fn create_private_dir(path: &Path) -> io::Result<()> {
+    let missing: Vec<&Path> = path.ancestors().take_while(|p| !p.as_os_str().is_empty() && !p.exists()).collect();
+    create_dir_all(path)?;
+    missing.into_iter().rev().try_for_each(restrict_dir)
+}
+
+// Create or truncate a file only accessible by the current user
+pub fn create_private_file(path: &Path) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    open_private(path, &mut options)
+}
+
+// Write a file only accessible by the current user through a temporary file renamed over it,
+// so other processes never read it partially written
+pub fn write_private_file(path: &Path, data: &[u8]) -> io::Result<()> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let temp = path.with_extension(format!("{}-{}.tmp", std::process::id(), COUNTER.fetch_add(1, Ordering::Relaxed)));
+    let written = create_private_file(&temp)
+        .and_then(|mut f| f.write_all(data))
+        .and_then(|_| rename(&temp, path));
+    if written.is_err() {
+        remove_file(&temp).ok();
+    }
+    written
+}
+
+// Lock `<path>.lock`, held while the file is read, modified and written back, so concurrent processes don't drop
+// each other's changes, readers don't need it as the file is replaced at once
+pub fn lock_file(path: &Path) -> Option<File> {
+    let filename = path.with_extension("lock");
+    let file = match OpenOptions::new().write(true).create(true).truncate(false).open(&filename) {
+        Ok(f) => f,
+        Err(e) => {
+            print_warning!("Unable to create lock file '{}', error is {:#?}.", filename.to_string_lossy(), e);
+            return None;
+        }
+    };
+    match file.lock_exclusive() {
+        Ok(_) => Some(file),
+        Err(e) => {
+            print_warning!("Unable to lock file '{}', error is {:#?}.", filename.to_string_lossy(), e);
+            None
+        }
+    }
+}
+
+// Create a file only accessible by the current user, fails with `AlreadyExists` if another process has created it
+pub fn create_new_private_file(path: &Path) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    open_private(path, &mut options)
+}
+
+fn open_private(path: &Path, options: &mut OpenOptions) -> io::Result<File> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let file = options.open(path)?;
+    // The mode only applies to new files, on Windows the ACL is inherited from the private directory
+    #[cfg(unix)]
+    restrict(path, 0o600)?;
+    Ok(file)
+}
+
+// The mode of the file if it's accessible by other users
+#[cfg(unix)]
+pub fn shared_mode(path: &Path) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = std::fs::metadata(path).ok()?.permissions().mode();
+    if mode & 0o077 == 0 { None } else { Some(mode & 0o777) }
+}
+
+#[cfg(not(unix))]
+pub fn shared_mode(_path: &Path) -> Option<u32> {
+    None
+}
+
+// Warn, or exit in strict mode, if the file is accessible by other users
+#[cfg(unix)]
+pub fn check_permissions(path: &Path) {
+    use std::process::exit;
+
+    let mode = match shared_mode(path) {
+        Some(m) => m,
+        None => return,
+    };
+    if STRICT.load(Ordering::Relaxed) {
+        print_error!("'{}' is accessible by other users (mode {:o}), run `chmod 600 '{}'` to fix it.", path.to_string_lossy(), mode, path.to_string_lossy());
+        exit(5);
+    }
+    print_warning!("'{}' is accessible by other users (mode {:o}), run `chmod 600 '{}'` to fix it.", path.to_string_lossy(), mode, path.to_string_lossy());
+}
+
+#[cfg(not(unix))]
+pub fn check_permissions(_path: &Path) {}
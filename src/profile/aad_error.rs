@@ -0,0 +1,70 @@
+use serde_json::Value;
+
+// What to do about the common AAD errors, https://docs.microsoft.com/en-us/azure/active-directory/develop/reference-aadsts-error-codes
+const HINTS: &[(u64, &str)] = &[
+    (7000215, "The client secret is wrong, check 'Secret' of the profile or 'DefaultSecret'."),
+    (7000222, "The client secret has expired, create a new one in the app registration."),
+    (7000218, "The app is not a public client, set 'Secret' or enable 'Allow public client flows' in the app registration."),
+    (700016, "The app is not found in the tenant, check 'ClientId' and 'Tenant'."),
+    (90002, "The tenant is not found, check 'Tenant'."),
+    (500011, "The resource is not found in the tenant, check 'Resource' or 'Scope'."),
+    (65001, "The app hasn't been consented, sign in with '--prompt consent', or ask an administrator to grant the consent for the tenant."),
+    (50076, "Multi-factor authentication is required, sign in again with '--prompt login'."),
+    (50079, "The account has to register for multi-factor authentication, sign in again with '--prompt login'."),
+    (53003, "The sign-in is blocked by Conditional Access, the device or the location may not be allowed."),
+    (700082, "The refresh token has expired after being unused for too long, sign in again."),
+    (50173, "The refresh token has been revoked, i.e. after a password change, sign in again."),
+    (70011, "The scope is invalid, 'App' profiles can only use '<resource>/.default'."),
+    (50011, "The redirect URI is not registered in the app registration."),
+];
+
+// The AADSTS code, from `error_codes` or the "AADSTSxxx:" prefix of the description
+fn error_code(body: &Value) -> Option<u64> {
+    body["error_codes"].get(0).and_then(|c| c.as_u64()).or_else(|| {
+        let description = body["error_description"].as_str()?;
+        let digits: String = description.strip_prefix("AADSTS")?.chars().take_while(|c| c.is_ascii_digit()).collect();
+        digits.parse().ok()
+    })
+}
+
+// Errors retrying can't fix until the app registration or the profile changes, i.e. a wrong or expired client secret
+const PERMANENT_ERRORS: &[&str] = &["invalid_client", "unauthorized_client", "consent_required"];
+
+pub fn is_permanent_error(body: &str) -> bool {
+    let body: Value = match serde_json::from_str(body) {
+        Ok(v) => v,
+        Err(_) => return false
+    };
+    // `consent_required` is usually the suberror of `invalid_grant`
+    [&body["error"], &body["suberror"]].iter().any(|e| PERMANENT_ERRORS.contains(&e.as_str().unwrap_or_default()))
+        || error_code(&body) == Some(65001)
+}
+
+// The refresh token can't be used anymore, i.e. it has expired or been revoked, or the account has to sign in again
+// for MFA or consent, an interactive sign-in fixes it
+pub fn is_rejected_grant(body: &str) -> bool {
+    let body: Value = match serde_json::from_str(body) {
+        Ok(v) => v,
+        Err(_) => return false
+    };
+    matches!(body["error"].as_str(), Some("invalid_grant") | Some("interaction_required"))
+}
+
+// A concise explanation of the error response, the first line of the description and the hint of the code,
+// None if the body is not an OAuth error
+pub fn explain_error(body: &str) -> Option<String> {
+    let body: Value = serde_json::from_str(body).ok()?;
+    let description = body["error_description"].as_str()
+        .or_else(|| body["error"].as_str())?
+        .lines().next().unwrap_or_default().trim();
+    let hint = error_code(&body).and_then(|code| HINTS.iter().find(|(c, _)| *c == code)).map(|(_, h)| *h);
+    let mut ret = description.to_owned();
+    if let Some(h) = hint {
+        ret = format!("{}\n{}", ret, h);
+    }
+    // To look the request up in the AAD sign-in logs
+    if let (Some(trace_id), Some(correlation_id)) = (body["trace_id"].as_str(), body["correlation_id"].as_str()) {
+        ret = format!("{}\nTrace ID: {}, Correlation ID: {}, Timestamp: {}", ret, trace_id, correlation_id, body["timestamp"].as_str().unwrap_or_default());
+    }
+    Some(ret)
+}
@@ -1,9 +1,15 @@
 use std::collections::HashMap;
+use std::env;
 use std::process::exit;
 
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
-use crate::profile::{AADToken, is_expired, send_request, TokenType};
+use crate::profile::{AADToken, add_extra_params, basic_auth_header, check_clock_skew, extra_params_key, is_expired, merge_client_capabilities, plain_secret, raw_response, send_request_with_headers, TokenType};
+use crate::profile::{cert_store, federated, pkcs11};
+use crate::profile::client_assertion::CLIENT_ASSERTION_TYPE;
+use crate::profile::discovery::{discover_endpoints, is_tenant_keyword};
+use crate::profile::http::HttpResponse;
 
 #[derive(Clone, PartialEq, Debug, Default, Serialize, Deserialize)]
 #[serde(default)]
@@ -11,6 +17,27 @@ pub struct AppToken {
     id_token: String,
     access_token: String,
     expires_on: String,
+    // Only used to check the clock skew and the extended lifetime when the token is issued
+    #[serde(skip_serializing)]
+    expires_in: String,
+    #[serde(skip_serializing)]
+    ext_expires_in: String,
+    // The extended lifetime, the token is still accepted by the resources during AAD outages
+    pub(crate) ext_expires_on: i64,
+    // The token response as is, only kept with `KeepRawResponse`
+    #[serde(skip_serializing_if = "Value::is_null")]
+    pub(crate) raw_response: Value,
+}
+
+impl AppToken {
+    // A token from other sources, i.e. a custom `TokenProvider`
+    pub fn new(access_token: &str, expires_on: i64) -> AppToken {
+        AppToken {
+            access_token: access_token.to_owned(),
+            expires_on: expires_on.to_string(),
+            ..Default::default()
+        }
+    }
 }
 
 impl AADToken for AppToken {
@@ -18,12 +45,16 @@ impl AADToken for AppToken {
         match self.expires_on.parse() {
             Ok(v) => is_expired(v),
             Err(_) => {
-                eprintln!("WARNING: Invalid token expiration value.");
+                print_warning!("Invalid token expiration value.");
                 return true;
             }
         }
     }
 
+    fn get_expires_on(&self) -> i64 {
+        self.expires_on.parse().unwrap_or_default()
+    }
+
     fn get_token_string(&self, token_type: TokenType) -> String {
         match token_type {
             TokenType::Access => &self.access_token,
@@ -34,7 +65,7 @@ impl AADToken for AppToken {
     }
 }
 
-#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[derive(Clone, PartialEq, Debug, Default, Serialize, Deserialize)]
 #[serde(rename_all = "PascalCase")]
 pub struct AppProfile {
     pub name: String,
@@ -47,37 +78,208 @@ pub struct AppProfile {
     #[serde(default)]
     pub authority: String,
     pub resource: String,
+    #[serde(default)]
+    pub azure_region: String,
+    #[serde(default)]
+    pub extra_params: HashMap<String, String>,
+    #[serde(default)]
+    pub client_capabilities: Vec<String>,
+    // `client_secret_post` (default) or `client_secret_basic`
+    #[serde(default)]
+    pub token_endpoint_auth_method: String,
+    // Thumbprint or subject of the client certificate in the Windows certificate store, used instead of the secret
+    #[serde(default)]
+    pub certificate: String,
+    // `CurrentUser` (default) or `LocalMachine`
+    #[serde(default)]
+    pub certificate_store: String,
+    // The PKCS#11 module of the smart card or HSM holding the key, used instead of the secret
+    #[serde(default)]
+    pub pkcs11_module: String,
+    // The first slot with a token if it's not set
+    #[serde(default)]
+    pub pkcs11_slot: Option<u64>,
+    // `CKA_ID` of the private key and the certificate, in hex
+    #[serde(default)]
+    pub pkcs11_key_id: String,
+    // The OIDC token of the CI job is the client assertion instead of the secret, `github` for GitHub Actions,
+    // or `azure-pipelines` for Azure Pipelines
+    #[serde(default)]
+    pub federated_credential: String,
+    // The audience of the federated token, `api://AzureADTokenExchange` by default
+    #[serde(default)]
+    pub federated_audience: String,
+    // The id of the Azure Pipelines service connection, `AZURESUBSCRIPTION_SERVICE_CONNECTION_ID` by default
+    #[serde(default)]
+    pub federated_service_connection: String,
+    // Alternate addresses of the hosts, i.e. to send the requests to a test server or a private cloud
+    #[serde(default)]
+    pub resolve: HashMap<String, String>,
+    // The AWS role assumed with the token by `--format aws-credential-process`
+    #[serde(default)]
+    pub aws_role_arn: String,
+    // The workload identity pool provider exchanging the token by the `gcp-*` formats,
+    // `//iam.googleapis.com/projects/<number>/locations/global/workloadIdentityPools/<pool>/providers/<provider>`
+    #[serde(default)]
+    pub gcp_audience: String,
+    // The GCP service account to impersonate with the federated token, optional
+    #[serde(default)]
+    pub gcp_service_account: String,
+    // Used as is instead of the one under the authority, i.e. for STS deployments and emulators with other paths
+    #[serde(default)]
+    pub token_endpoint: String,
+    // Keep the tokens apart from other profiles with the same parameters, the profile name is in the cache key
+    #[serde(default)]
+    pub isolated_cache: bool,
+    // Fields not known to `App` profiles, reported by `profile validate`
+    #[serde(flatten)]
+    pub unknown_fields: HashMap<String, Value>,
+    #[serde(skip)]
+    pub claims: String,
+    #[serde(skip)]
+    pub assertion: String,
+    #[serde(skip)]
+    pub assertion_type: String,
 }
 
 impl AppProfile {
     pub fn get_token(&self) -> AppToken {
+        if is_tenant_keyword(&self.tenant) {
+            print_warning!("Tenant '{}' is not a real tenant, the client credentials flow needs the tenant id or the domain name.", self.tenant);
+        }
+        let resp = self.send_token_request(false);
+
+        let mut token: AppToken = resp.json().map_err(|e| {
+            print_error!("Failed to decode response, error is {:#?}.", e);
+            exit(2);
+        }).unwrap();
+        let expires_in: i64 = token.expires_in.parse().unwrap_or_default();
+        check_clock_skew(&token.access_token, expires_in);
+        if let Ok(ext_expires_in) = token.ext_expires_in.parse::<i64>() {
+            token.ext_expires_on = token.get_expires_on() + ext_expires_in - expires_in;
+        }
+        token.raw_response = raw_response(&resp.body);
+        token
+    }
+
+    pub fn send_token_request(&self, ignore_error: bool) -> HttpResponse {
         // Refer to:
         // https://docs.microsoft.com/en-us/azure/active-directory/azuread-dev/v1-oauth2-client-creds-grant-flow
-        let url = format!("{}/{}/oauth2/token", self.authority, self.tenant);
+        let url = self.get_token_endpoint();
+        let claims = merge_client_capabilities(&self.claims, &self.client_capabilities);
+
+        // SAML assertions are sent base64url encoded, https://tools.ietf.org/html/rfc7522#section-2.1
+        let saml_assertion = if self.assertion.starts_with('<') {
+            base64::encode_config(&self.assertion, base64::URL_SAFE_NO_PAD)
+        } else {
+            self.assertion.clone()
+        };
 
         let mut form = HashMap::new();
-        form.insert("grant_type", "client_credentials");
-        form.insert("client_id", &self.client_id);
-        form.insert("client_secret", &self.secret);
+        if self.assertion.is_empty() {
+            form.insert("grant_type", "client_credentials");
+        } else if self.assertion_type.starts_with("saml") {
+            form.insert("grant_type", if self.assertion_type == "saml1" {
+                "urn:ietf:params:oauth:grant-type:saml1_1-bearer"
+            } else {
+                "urn:ietf:params:oauth:grant-type:saml2-bearer"
+            });
+            form.insert("assertion", &saml_assertion);
+            form.insert("scope", "openid");
+        } else {
+            // https://docs.microsoft.com/en-us/azure/active-directory/azuread-dev/v1-oauth2-on-behalf-of-flow
+            form.insert("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer");
+            form.insert("assertion", &self.assertion);
+            form.insert("requested_token_use", "on_behalf_of");
+        }
+        let client_assertion = self.get_client_assertion(&url);
+        let secret = if client_assertion.is_empty() { plain_secret(&self.secret) } else { String::new() };
+        let mut headers = vec![];
+        if !client_assertion.is_empty() {
+            form.insert("client_id", &self.client_id);
+            form.insert("client_assertion_type", CLIENT_ASSERTION_TYPE);
+            form.insert("client_assertion", &client_assertion);
+        } else if self.token_endpoint_auth_method == "client_secret_basic" {
+            headers.push(basic_auth_header(&self.client_id, &secret));
+        } else {
+            form.insert("client_id", &self.client_id);
+            form.insert("client_secret", &secret);
+        }
         form.insert("resource", &self.resource);
+        if !claims.is_empty() {
+            form.insert("claims", &claims);
+        }
+        add_extra_params(&mut form, &self.extra_params);
 
-        let resp = send_request(&url, &form, false);
+        send_request_with_headers(&url, &form, &headers, ignore_error)
+    }
 
-        resp.json().map_err(|e| {
-            eprintln!("ERROR: Failed to decode response, error is {:#?}.", e);
-            exit(2);
-        }).unwrap()
+    // Sign the client assertion with the certificate if there is one, empty if the secret is used
+    fn get_client_assertion(&self, audience: &str) -> String {
+        let assertion = if !self.pkcs11_module.is_empty() {
+            pkcs11::client_assertion(&self.pkcs11_module, self.pkcs11_slot, &self.pkcs11_key_id, &self.client_id, audience)
+        } else if !self.certificate.is_empty() {
+            cert_store::client_assertion(&self.certificate_store, &self.certificate, &self.client_id, audience)
+        } else if !self.federated_credential.is_empty() {
+            federated::client_assertion(&self.federated_credential, &self.federated_audience, &self.federated_service_connection)
+        } else {
+            return String::new();
+        };
+        assertion.unwrap_or_else(|e| {
+            print_error!("{}.", e);
+            exit(1);
+        })
+    }
+
+    // Route to the regional token endpoint (ESTS-R), i.e. https://westus2.login.microsoft.com
+    fn get_regional_authority(&self) -> String {
+        let region = if self.azure_region == "auto" {
+            // Set by Azure Functions and App Service
+            env::var("REGION_NAME").unwrap_or_default()
+        } else {
+            self.azure_region.clone()
+        };
+        let region = region.to_lowercase().replace(' ', "");
+        if region.is_empty() {
+            return self.authority.clone();
+        }
+        let host = self.authority.trim_start_matches("https://").trim_end_matches('/');
+        match host {
+            "login.microsoftonline.com" | "login.microsoft.com" | "login.windows.net" | "sts.windows.net" => {
+                format!("https://{}.login.microsoft.com", region)
+            }
+            // External ID tenants have no regional endpoints
+            h if h.ends_with(".ciamlogin.com") => self.authority.clone(),
+            _ => format!("https://{}.{}", region, host)
+        }
+    }
+
+    pub fn get_token_endpoint(&self) -> String {
+        if !self.token_endpoint.is_empty() {
+            return self.token_endpoint.clone();
+        }
+        // The regional endpoints are not in the OpenID configuration
+        let authority = self.get_regional_authority();
+        if authority == self.authority {
+            if let Some(e) = discover_endpoints(&self.authority, &self.tenant, false) {
+                return e.token_endpoint;
+            }
+        }
+        format!("{}/{}/oauth2/token", authority, self.tenant)
     }
 
     pub fn is_valid(&self) -> bool {
         !(self.client_id.is_empty()
-            || self.secret.is_empty()
-            || self.tenant.is_empty()
-            || self.authority.is_empty())
+            || (self.secret.is_empty() && self.certificate.is_empty() && self.pkcs11_module.is_empty() && self.federated_credential.is_empty())
+            || (self.token_endpoint.is_empty() && (self.tenant.is_empty() || self.authority.is_empty())))
     }
 
     pub fn get_key(&self) -> String {
-        format!("App:{}\t{}\t{}\t{}", self.client_id, self.tenant, self.authority, self.resource)
+        // The token endpoint replaces the authority
+        let authority = if self.token_endpoint.is_empty() { &self.authority } else { &self.token_endpoint };
+        let key = format!("App:{}\t{}\t{}\t{}", self.client_id, self.tenant, authority, self.resource);
+        let key = if self.extra_params.is_empty() { key } else { format!("{}\t{}", key, extra_params_key(&self.extra_params)) };
+        if self.isolated_cache { format!("{}:{}", self.name, key) } else { key }
     }
 }
 
@@ -0,0 +1,142 @@
+use std::convert::TryFrom;
+
+use serde_json::{Map, Number, Value};
+
+// A minimal CBOR (https://www.rfc-editor.org/rfc/rfc8949) codec of JSON values for the binary cache format,
+// only definite lengths, text strings and 64-bit floats are written, byte strings and tags are not supported
+
+fn write_head(out: &mut Vec<u8>, major: u8, n: u64) {
+    let major = major << 5;
+    if n < 24 {
+        out.push(major | n as u8);
+    } else if n <= u8::MAX as u64 {
+        out.push(major | 24);
+        out.push(n as u8);
+    } else if n <= u16::MAX as u64 {
+        out.push(major | 25);
+        out.extend_from_slice(&(n as u16).to_be_bytes());
+    } else if n <= u32::MAX as u64 {
+        out.push(major | 26);
+        out.extend_from_slice(&(n as u32).to_be_bytes());
+    } else {
+        out.push(major | 27);
+        out.extend_from_slice(&n.to_be_bytes());
+    }
+}
+
+fn write_value(out: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Null => out.push(0xf6),
+        Value::Bool(b) => out.push(if *b { 0xf5 } else { 0xf4 }),
+        Value::Number(n) => match (n.as_u64(), n.as_i64()) {
+            (Some(u), _) => write_head(out, 0, u),
+            (None, Some(i)) => write_head(out, 1, (-1 - i) as u64),
+            _ => {
+                out.push(0xfb);
+                out.extend_from_slice(&n.as_f64().unwrap_or_default().to_be_bytes());
+            }
+        },
+        Value::String(s) => {
+            write_head(out, 3, s.len() as u64);
+            out.extend_from_slice(s.as_bytes());
+        }
+        Value::Array(a) => {
+            write_head(out, 4, a.len() as u64);
+            for v in a {
+                write_value(out, v);
+            }
+        }
+        Value::Object(o) => {
+            write_head(out, 5, o.len() as u64);
+            for (k, v) in o {
+                write_head(out, 3, k.len() as u64);
+                out.extend_from_slice(k.as_bytes());
+                write_value(out, v);
+            }
+        }
+    }
+}
+
+pub fn encode(value: &Value) -> Vec<u8> {
+    let mut out = vec![];
+    write_value(&mut out, value);
+    out
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let end = self.pos.checked_add(len).filter(|e| *e <= self.data.len()).ok_or("Unexpected end of data")?;
+        let ret = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(ret)
+    }
+
+    fn read_uint(&mut self, info: u8) -> Result<u64, String> {
+        let mut be = |len| self.take(len).map(|b| b.iter().fold(0u64, |n, b| n << 8 | *b as u64));
+        match info {
+            0..=23 => Ok(info as u64),
+            24 => be(1),
+            25 => be(2),
+            26 => be(4),
+            27 => be(8),
+            _ => Err(format!("Unsupported length {}", info))
+        }
+    }
+
+    fn read_text(&mut self, len: u64) -> Result<String, String> {
+        let bytes = self.take(len as usize)?;
+        String::from_utf8(bytes.to_vec()).map_err(|e| e.to_string())
+    }
+
+    fn read_value(&mut self) -> Result<Value, String> {
+        let head = self.take(1)?[0];
+        let (major, info) = (head >> 5, head & 0x1f);
+        match major {
+            0 => Ok(Value::from(self.read_uint(info)?)),
+            1 => i64::try_from(self.read_uint(info)?).map(|n| Value::from(-1 - n)).map_err(|e| e.to_string()),
+            3 => {
+                let len = self.read_uint(info)?;
+                self.read_text(len).map(Value::String)
+            }
+            4 => {
+                let len = self.read_uint(info)?;
+                (0..len).map(|_| self.read_value()).collect::<Result<Vec<_>, _>>().map(Value::Array)
+            }
+            5 => {
+                let len = self.read_uint(info)?;
+                let mut map = Map::new();
+                for _ in 0..len {
+                    let key = match self.read_value()? {
+                        Value::String(s) => s,
+                        _ => return Err(String::from("Map keys must be strings"))
+                    };
+                    map.insert(key, self.read_value()?);
+                }
+                Ok(Value::Object(map))
+            }
+            7 => match info {
+                20 => Ok(Value::Bool(false)),
+                21 => Ok(Value::Bool(true)),
+                22 | 23 => Ok(Value::Null),
+                26 => Ok(Number::from_f64(f32::from_bits(self.read_uint(info)? as u32) as f64).map(Value::Number).unwrap_or_default()),
+                27 => Ok(Number::from_f64(f64::from_bits(self.read_uint(info)?)).map(Value::Number).unwrap_or_default()),
+                _ => Err(format!("Unsupported simple value {}", info))
+            },
+            _ => Err(format!("Unsupported major type {}", major))
+        }
+    }
+}
+
+pub fn decode(data: &[u8]) -> Result<Value, String> {
+    let mut reader = Reader { data, pos: 0 };
+    let value = reader.read_value()?;
+    if reader.pos != data.len() {
+        return Err(String::from("Trailing data"));
+    }
+    Ok(value)
+}
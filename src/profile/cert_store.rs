@@ -0,0 +1,121 @@
+// Client certificates in the Windows certificate store, the private key is used through CNG so it never leaves the store
+#[cfg(target_os = "windows")]
+use crate::profile::client_assertion::{finish, signing_input};
+
+// Thumbprints are 40 hex digits, Windows shows them with spaces and the MMC snap-in may add an invisible mark at the start
+#[cfg(target_os = "windows")]
+fn parse_thumbprint(certificate: &str) -> Option<Vec<u8>> {
+    let hex: String = certificate.chars().filter(|c| c.is_ascii_alphanumeric()).collect();
+    if hex.len() != 40 {
+        return None;
+    }
+    (0..40).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok()).collect()
+}
+
+#[cfg(target_os = "windows")]
+mod ffi {
+    use winapi::shared::minwindef::{DWORD, PBYTE};
+    use winapi::shared::ntdef::{SECURITY_STATUS, VOID};
+    use winapi::um::ncrypt::NCRYPT_KEY_HANDLE;
+
+    // Not declared by `winapi`
+    #[link(name = "ncrypt")]
+    extern "system" {
+        pub fn NCryptSignHash(
+            hKey: NCRYPT_KEY_HANDLE,
+            pPaddingInfo: *mut VOID,
+            pbHashValue: PBYTE,
+            cbHashValue: DWORD,
+            pbSignature: PBYTE,
+            cbSignature: DWORD,
+            pcbResult: *mut DWORD,
+            dwFlags: DWORD,
+        ) -> SECURITY_STATUS;
+    }
+}
+
+// Build a client assertion signed by the certificate found by thumbprint or subject in the `CurrentUser` or `LocalMachine` store
+#[cfg(target_os = "windows")]
+pub fn client_assertion(store: &str, certificate: &str, client_id: &str, audience: &str) -> Result<String, String> {
+    use std::ptr::{null, null_mut};
+    use sha2::{Digest, Sha256};
+    use winapi::shared::bcrypt::{BCRYPT_PKCS1_PADDING_INFO, BCRYPT_SHA256_ALGORITHM};
+    use winapi::shared::minwindef::{DWORD, FALSE};
+    use winapi::um::ncrypt::{NCRYPT_PAD_PKCS1_FLAG, NCryptFreeObject};
+    use winapi::um::wincrypt::{
+        CERT_FIND_HASH, CERT_FIND_SUBJECT_STR, CERT_SHA1_HASH_PROP_ID, CERT_STORE_OPEN_EXISTING_FLAG,
+        CERT_STORE_PROV_SYSTEM_W, CERT_STORE_READONLY_FLAG, CERT_SYSTEM_STORE_CURRENT_USER,
+        CERT_SYSTEM_STORE_LOCAL_MACHINE, CertCloseStore, CertFindCertificateInStore, CertFreeCertificateContext,
+        CertGetCertificateContextProperty, CertOpenStore, CRYPT_ACQUIRE_ONLY_NCRYPT_KEY_FLAG, CRYPT_HASH_BLOB,
+        CryptAcquireCertificatePrivateKey, PKCS_7_ASN_ENCODING, X509_ASN_ENCODING,
+    };
+
+    fn wide(s: &str) -> Vec<u16> {
+        s.encode_utf16().chain(Some(0)).collect()
+    }
+
+    let location = match store {
+        "" | "CurrentUser" => CERT_SYSTEM_STORE_CURRENT_USER,
+        "LocalMachine" => CERT_SYSTEM_STORE_LOCAL_MACHINE,
+        _ => return Err(format!("Unknown certificate store '{}', use 'CurrentUser' or 'LocalMachine'", store))
+    };
+    let store_name = wide("My");
+    let encoding = X509_ASN_ENCODING | PKCS_7_ASN_ENCODING;
+    unsafe {
+        let store = CertOpenStore(CERT_STORE_PROV_SYSTEM_W, 0, 0,
+                                  location | CERT_STORE_OPEN_EXISTING_FLAG | CERT_STORE_READONLY_FLAG,
+                                  store_name.as_ptr() as _);
+        if store.is_null() {
+            return Err(format!("Unable to open the '{}' certificate store", if location == CERT_SYSTEM_STORE_LOCAL_MACHINE { "LocalMachine" } else { "CurrentUser" }));
+        }
+        let cert = match parse_thumbprint(certificate) {
+            Some(mut hash) => {
+                let blob = CRYPT_HASH_BLOB { cbData: hash.len() as DWORD, pbData: hash.as_mut_ptr() };
+                CertFindCertificateInStore(store, encoding, 0, CERT_FIND_HASH, &blob as *const _ as _, null())
+            }
+            None => {
+                let subject = wide(certificate);
+                CertFindCertificateInStore(store, encoding, 0, CERT_FIND_SUBJECT_STR, subject.as_ptr() as _, null())
+            }
+        };
+        CertCloseStore(store, 0);
+        if cert.is_null() {
+            return Err(format!("Certificate '{}' is not found in the certificate store", certificate));
+        }
+
+        let mut thumbprint = vec![0u8; 20];
+        let mut size = thumbprint.len() as DWORD;
+        let mut key = 0;
+        let mut key_spec = 0;
+        let mut must_free = FALSE;
+        let ok = CertGetCertificateContextProperty(cert, CERT_SHA1_HASH_PROP_ID, thumbprint.as_mut_ptr() as _, &mut size) != 0
+            && CryptAcquireCertificatePrivateKey(cert, CRYPT_ACQUIRE_ONLY_NCRYPT_KEY_FLAG, null_mut(),
+                                                 &mut key, &mut key_spec, &mut must_free) != 0;
+        CertFreeCertificateContext(cert);
+        if !ok {
+            return Err(format!("Unable to use the private key of certificate '{}', it may not have one or it's not accessible", certificate));
+        }
+
+        let input = signing_input(client_id, audience, &thumbprint);
+        let mut hash = Sha256::digest(input.as_bytes()).to_vec();
+        let algorithm = wide(BCRYPT_SHA256_ALGORITHM);
+        let mut padding = BCRYPT_PKCS1_PADDING_INFO { pszAlgId: algorithm.as_ptr() };
+        let mut signature = vec![0u8; 512];
+        let mut size = 0;
+        let status = ffi::NCryptSignHash(key, &mut padding as *mut _ as _, hash.as_mut_ptr(), hash.len() as DWORD,
+                                         signature.as_mut_ptr(), signature.len() as DWORD, &mut size, NCRYPT_PAD_PKCS1_FLAG);
+        if must_free != FALSE {
+            NCryptFreeObject(key);
+        }
+        if status != 0 {
+            return Err(format!("Unable to sign the client assertion with certificate '{}', error is {:#x}", certificate, status));
+        }
+        signature.truncate(size as usize);
+        Ok(finish(&input, &signature))
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+pub fn client_assertion(_store: &str, _certificate: &str, _client_id: &str, _audience: &str) -> Result<String, String> {
+    Err(String::from("The certificate store is only supported on Windows"))
+}
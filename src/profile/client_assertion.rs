@@ -0,0 +1,42 @@
+use chacha20poly1305::aead::OsRng;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chrono::Utc;
+use serde_json::json;
+
+// Client assertions are JWTs signed with the private key of the app certificate, used instead of the client secret,
+// https://docs.microsoft.com/en-us/azure/active-directory/develop/active-directory-certificate-credentials
+pub const CLIENT_ASSERTION_TYPE: &str = "urn:ietf:params:oauth:client-assertion-type:jwt-bearer";
+
+// Valid for 10 minutes, it's only sent once
+const LIFETIME: i64 = 600;
+
+fn encode(data: &[u8]) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+// The encoded header and claims to be signed with RS256, `thumbprint` is the SHA-1 hash of the certificate
+#[cfg_attr(not(any(target_os = "windows", feature = "pkcs11")), allow(dead_code))]
+pub fn signing_input(client_id: &str, audience: &str, thumbprint: &[u8]) -> String {
+    let mut jti = [0u8; 16];
+    OsRng.fill_bytes(&mut jti);
+    let now = Utc::now().timestamp();
+    let header = json!({
+        "alg": "RS256",
+        "typ": "JWT",
+        "x5t": encode(thumbprint),
+    });
+    let claims = json!({
+        "aud": audience,
+        "iss": client_id,
+        "sub": client_id,
+        "jti": jti.iter().map(|b| format!("{:02x}", b)).collect::<String>(),
+        "nbf": now,
+        "exp": now + LIFETIME,
+    });
+    format!("{}.{}", encode(header.to_string().as_bytes()), encode(claims.to_string().as_bytes()))
+}
+
+#[cfg_attr(not(any(target_os = "windows", feature = "pkcs11")), allow(dead_code))]
+pub fn finish(signing_input: &str, signature: &[u8]) -> String {
+    format!("{}.{}", signing_input, encode(signature))
+}
@@ -0,0 +1,68 @@
+use std::fs::{read, read_dir, remove_file};
+use std::path::{Path, PathBuf};
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+use crate::permissions::{check_permissions, create_private_dir, create_private_file};
+use crate::profile::cache_root;
+
+// The device code sign-ins started by `auth start`, each in `pending/<handle>.json` until `auth poll` completes it,
+// the device code is as good as the token once the user has signed in, so the files are private
+const DIRNAME: &str = "pending";
+
+#[derive(Serialize, Deserialize)]
+pub struct PendingSignIn {
+    // The cache key of the profile, so the handle can't be completed with another profile
+    pub key: String,
+    pub device_code: String,
+    pub expires_on: i64,
+}
+
+fn filename(handle: &str) -> Result<PathBuf, String> {
+    // The handle is a UUID, anything else could escape the directory
+    if handle.is_empty() || !handle.chars().all(|c| c.is_ascii_hexdigit() || c == '-') {
+        return Err(format!("Invalid handle '{}'.", handle));
+    }
+    let mut dir = cache_root().ok_or("The cache directory is not available.")?;
+    dir.push(DIRNAME);
+    create_private_dir(&dir).map_err(|e| format!("Unable to create directory '{}', error is {}.", dir.to_string_lossy(), e))?;
+    Ok(dir.join(format!("{}.json", handle)))
+}
+
+// Remove the sign-ins never completed once their codes have expired
+fn prune(dir: &Path) {
+    let now = Utc::now().timestamp();
+    for path in read_dir(dir).into_iter().flatten().filter_map(|f| f.ok()).map(|f| f.path()) {
+        let expired = read(&path).ok()
+            .and_then(|data| serde_json::from_slice::<PendingSignIn>(&data).ok())
+            .map(|p| p.expires_on <= now)
+            .unwrap_or(true);
+        if expired {
+            remove_file(path).ok();
+        }
+    }
+}
+
+pub fn save(handle: &str, pending: &PendingSignIn) -> Result<(), String> {
+    let filename = filename(handle)?;
+    if let Some(dir) = filename.parent() {
+        prune(dir);
+    }
+    create_private_file(&filename)
+        .and_then(|f| serde_json::to_writer(f, pending).map_err(|e| e.into()))
+        .map_err(|e| format!("Unable to save the sign-in to '{}', error is {}.", filename.to_string_lossy(), e))
+}
+
+pub fn load(handle: &str) -> Result<PendingSignIn, String> {
+    let filename = filename(handle)?;
+    check_permissions(&filename);
+    let data = read(&filename).map_err(|_| format!("No sign-in is pending with handle '{}'.", handle))?;
+    serde_json::from_slice(&data).map_err(|e| format!("Unable to load the sign-in from '{}', error is {}.", filename.to_string_lossy(), e))
+}
+
+pub fn remove(handle: &str) {
+    if let Ok(filename) = filename(handle) {
+        remove_file(filename).ok();
+    }
+}
@@ -0,0 +1,206 @@
+use std::collections::HashMap;
+use std::fs::{remove_file, File};
+use std::path::PathBuf;
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+
+use crate::permissions::{create_private_dir, lock_file, write_private_file};
+use crate::profile::http::resolve_url;
+use crate::profile::{cache_root, send_get_request};
+
+// The global Azure cloud, tenants in other clouds can also be discovered from here
+pub const DEFAULT_AUTHORITY: &str = "https://login.microsoftonline.com";
+
+// Seconds the OpenID configuration of an authority is used before it's fetched again, in case the endpoints have moved
+const ENDPOINTS_TTL: i64 = 24 * 3600;
+
+// Seconds an authority without an OpenID configuration, i.e. ADFS or an emulator, isn't asked again
+const MISSING_ENDPOINTS_TTL: i64 = 3600;
+
+// Multi-tenant endpoints, the tenant is picked by the signed-in account
+const TENANT_KEYWORDS: &[&str] = &["common", "organizations", "consumers"];
+
+pub fn is_tenant_keyword(tenant: &str) -> bool {
+    TENANT_KEYWORDS.iter().any(|k| k.eq_ignore_ascii_case(tenant))
+}
+
+pub fn is_guid(s: &str) -> bool {
+    s.len() == 36 && s.chars().enumerate().all(|(i, c)| match i {
+        8 | 13 | 18 | 23 => c == '-',
+        _ => c.is_ascii_hexdigit()
+    })
+}
+
+// A tenant id, a domain name, or a tenant keyword
+pub fn is_valid_tenant(tenant: &str) -> bool {
+    is_guid(tenant) || is_tenant_keyword(tenant) || (tenant.contains('.') && !tenant.contains('/'))
+}
+
+#[derive(Clone, PartialEq, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TenantInfo {
+    pub authority: String,
+    pub tenant_id: String,
+}
+
+// Entra External ID (CIAM) tenants sign in at "https://<subdomain>.ciamlogin.com/<tenant>", the tenant is the path
+// of the authority, or "<subdomain>.onmicrosoft.com" if neither the path nor the tenant is given
+pub fn split_ciam_authority(authority: &str, tenant: &str) -> Option<TenantInfo> {
+    let rest = authority.strip_prefix("https://")?.trim_end_matches('/');
+    let (host, path) = rest.split_once('/').unwrap_or((rest, ""));
+    let subdomain = host.to_lowercase().strip_suffix(".ciamlogin.com")?.to_owned();
+    // There are no multi-tenant endpoints
+    let tenant_id = if !path.is_empty() {
+        path.to_owned()
+    } else if !tenant.is_empty() && !is_tenant_keyword(tenant) {
+        tenant.to_owned()
+    } else {
+        format!("{}.onmicrosoft.com", subdomain)
+    };
+    Some(TenantInfo { authority: format!("https://{}", host.to_lowercase()), tenant_id })
+}
+
+// The endpoints in the OpenID configuration of the authority, https://openid.net/specs/openid-connect-discovery-1_0.html
+#[derive(Clone, PartialEq, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OpenIdConfiguration {
+    pub token_endpoint: String,
+    pub device_authorization_endpoint: String,
+    pub end_session_endpoint: String,
+    // The signing keys of the tokens
+    pub jwks_uri: String,
+    // RFC 7009 token revocation, AAD doesn't have it
+    pub revocation_endpoint: String,
+}
+
+impl OpenIdConfiguration {
+    fn has_endpoint(&self, url: &str) -> bool {
+        [&self.token_endpoint, &self.device_authorization_endpoint, &self.revocation_endpoint].iter().any(|e| *e == url)
+    }
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct CachedEndpoints {
+    #[serde(flatten)]
+    endpoints: OpenIdConfiguration,
+    expires_on: i64,
+}
+
+const CACHE_FILES: &[&str] = &["discovery.json", "endpoints.json", "missing_endpoints.json"];
+
+// The tenants are cached in `discovery.json`, the OpenID configurations in `endpoints.json`, and until when the
+// authorities without one are not asked again in `missing_endpoints.json`
+fn discovery_cache_filename(name: &str) -> Option<PathBuf> {
+    let mut cache_dir = cache_root()?;
+    create_private_dir(cache_dir.as_path()).ok()?;
+    cache_dir.push(name);
+    Some(cache_dir)
+}
+
+fn load_discovery_cache<T: DeserializeOwned>(name: &str) -> HashMap<String, T> {
+    discovery_cache_filename(name)
+        .and_then(|f| File::open(f).ok())
+        .and_then(|f| serde_json::from_reader(f).ok())
+        .unwrap_or_default()
+}
+
+// Change the cache under the lock, it's read again so the entries added by other processes are kept
+fn update_discovery_cache<T: Serialize + DeserializeOwned>(name: &str, update: impl FnOnce(&mut HashMap<String, T>)) {
+    let filename = match discovery_cache_filename(name) {
+        Some(f) => f,
+        None => return
+    };
+    let _lock = lock_file(&filename);
+    let mut cache = load_discovery_cache(name);
+    update(&mut cache);
+    if let Ok(data) = serde_json::to_vec(&cache) {
+        write_private_file(&filename, &data).ok();
+    }
+}
+
+// The endpoints of the tenant from the OpenID configuration of the authority, `v2` for the v2.0 endpoints, results are
+// cached. None if the authority doesn't have the document, the endpoints follow the AAD convention then
+pub fn discover_endpoints(authority: &str, tenant: &str, v2: bool) -> Option<OpenIdConfiguration> {
+    let url = format!("{}/{}{}/.well-known/openid-configuration", authority.trim_end_matches('/'), tenant, if v2 { "/v2.0" } else { "" });
+    // Keyed by the address actually used, so the endpoints of test servers are not used without `Resolve`
+    let key = resolve_url(&url);
+    let now = Utc::now().timestamp();
+    let cache: HashMap<String, CachedEndpoints> = load_discovery_cache("endpoints.json");
+    if let Some(cached) = cache.get(&key).filter(|c| c.expires_on > now) {
+        return Some(cached.endpoints.clone());
+    }
+    let missing: HashMap<String, i64> = load_discovery_cache("missing_endpoints.json");
+    if missing.get(&key).is_some_and(|until| *until > now) {
+        return None;
+    }
+
+    // Unreachable servers and server errors are not cached, they may be temporary
+    let resp = send_get_request(&url).filter(|r| r.status < 500)?;
+    let cfg: Option<OpenIdConfiguration> = if resp.is_success() { resp.json().ok() } else { None };
+    let cfg = match cfg {
+        // Multi-tenant documents may have placeholders, i.e. "{tenantid}"
+        Some(cfg) if !cfg.token_endpoint.is_empty() && !cfg.token_endpoint.contains('{') => cfg,
+        _ => {
+            update_discovery_cache("missing_endpoints.json", |missing: &mut HashMap<String, i64>| {
+                missing.retain(|_, until| *until > now);
+                missing.insert(key, now + MISSING_ENDPOINTS_TTL);
+            });
+            return None;
+        }
+    };
+    update_discovery_cache("endpoints.json", |cache: &mut HashMap<String, CachedEndpoints>| {
+        cache.retain(|_, c| c.expires_on > now);
+        cache.insert(key, CachedEndpoints { endpoints: cfg.clone(), expires_on: now + ENDPOINTS_TTL });
+    });
+    Some(cfg)
+}
+
+// Drop the cached OpenID configurations having the endpoint after a request to it has failed, they're fetched again
+// next time in case the endpoints have moved
+pub fn forget_endpoint(url: &str) {
+    let cache: HashMap<String, CachedEndpoints> = load_discovery_cache("endpoints.json");
+    if cache.values().any(|c| c.endpoints.has_endpoint(url)) {
+        update_discovery_cache("endpoints.json", |cache: &mut HashMap<String, CachedEndpoints>| {
+            cache.retain(|_, c| !c.endpoints.has_endpoint(url));
+        });
+    }
+}
+
+// Remove all discovery results
+pub fn clear() {
+    for name in CACHE_FILES {
+        if let Some(filename) = discovery_cache_filename(name) {
+            remove_file(filename).ok();
+        }
+    }
+}
+
+// Resolve the cloud authority and the tenant id from the tenant name, results are cached
+pub fn discover_tenant(tenant: &str) -> Option<TenantInfo> {
+    let cache: HashMap<String, TenantInfo> = load_discovery_cache("discovery.json");
+    if let Some(info) = cache.get(tenant) {
+        return Some(info.clone());
+    }
+
+    // https://docs.microsoft.com/en-us/azure/active-directory/develop/v2-protocols-oidc#fetch-the-openid-connect-metadata-document
+    let url = format!("{}/{}/v2.0/.well-known/openid-configuration", DEFAULT_AUTHORITY, tenant);
+    let cfg: OpenIdConfiguration = send_get_request(&url).filter(|r| r.is_success())?.json().ok()?;
+
+    // The token endpoint looks like "https://login.microsoftonline.com/{tenant_id}/oauth2/v2.0/token"
+    let mut parts = cfg.token_endpoint.strip_prefix("https://")?.split('/');
+    let host = parts.next()?;
+    let tenant_id = parts.next()?;
+    let info = TenantInfo {
+        authority: format!("https://{}", host),
+        // Multi-tenant endpoints like "common" don't have a tenant id
+        tenant_id: if tenant_id.starts_with('{') { tenant.to_owned() } else { tenant_id.to_owned() },
+    };
+
+    update_discovery_cache("discovery.json", |cache| {
+        cache.insert(tenant.to_owned(), info.clone());
+    });
+    Some(info)
+}
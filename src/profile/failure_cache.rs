@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+use std::fs::{read, remove_file, File};
+use std::path::PathBuf;
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+use crate::permissions::{check_permissions, create_private_dir, lock_file, write_private_file};
+use crate::profile::cache_root;
+
+// Permanent failures of the token requests, i.e. an expired client secret, kept for a short while by the cache key,
+// so retry loops fail fast instead of sending the same bad credentials to AAD again and tripping the smart lockout
+const FILENAME: &str = "failures.json";
+
+#[derive(Serialize, Deserialize)]
+struct Failure {
+    error: String,
+    expires_on: i64,
+}
+
+fn filename() -> Option<PathBuf> {
+    let dir = cache_root()?;
+    create_private_dir(&dir).ok()?;
+    Some(dir.join(FILENAME))
+}
+
+// Held while the failures are read, modified and written back, so concurrent processes keep each other's failures
+fn lock() -> Option<File> {
+    lock_file(&filename()?)
+}
+
+// Expired failures are dropped
+fn load() -> HashMap<String, Failure> {
+    let now = Utc::now().timestamp();
+    let mut failures: HashMap<String, Failure> = filename()
+        .and_then(|f| {
+            check_permissions(&f);
+            read(f).ok()
+        })
+        .and_then(|data| serde_json::from_slice(&data).ok())
+        .unwrap_or_default();
+    failures.retain(|_, f| f.expires_on > now);
+    failures
+}
+
+fn save(failures: &HashMap<String, Failure>) {
+    let filename = match filename() {
+        Some(f) => f,
+        None => return
+    };
+    if failures.is_empty() {
+        remove_file(filename).ok();
+        return;
+    }
+    let written = serde_json::to_vec(failures).map_err(|e| e.into())
+        .and_then(|data| write_private_file(&filename, &data));
+    if let Err(e) = written {
+        print_warning!("Unable to save the failure to '{}', error is {:#?}.", filename.to_string_lossy(), e);
+    }
+}
+
+// The error of the failure and the seconds it's still kept for
+pub fn find(key: &str) -> Option<(String, i64)> {
+    load().remove(key).map(|f| (f.error, f.expires_on - Utc::now().timestamp()))
+}
+
+pub fn insert(key: &str, error: &str, ttl: i64) {
+    let _lock = lock();
+    let mut failures = load();
+    failures.insert(key.to_owned(), Failure { error: error.to_owned(), expires_on: Utc::now().timestamp() + ttl });
+    save(&failures);
+}
+
+pub fn remove(key: &str) {
+    let _lock = lock();
+    let mut failures = load();
+    if failures.remove(key).is_some() {
+        save(&failures);
+    }
+}
+
+pub fn clear() {
+    let _lock = lock();
+    if let Some(f) = filename() {
+        remove_file(f).ok();
+    }
+}
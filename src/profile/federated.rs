@@ -0,0 +1,71 @@
+use std::env;
+
+use serde_json::{json, Value};
+
+use crate::profile::form_urlencode;
+use crate::profile::http::{http_client, resolve_url};
+
+// Workload identity federation, the OIDC token of the CI job is the client assertion, so no secret is stored,
+// the app registration needs a federated credential trusting the issuer and the subject of the job
+// https://learn.microsoft.com/en-us/entra/workload-id/workload-identity-federation
+
+// The audience AAD expects in the federated tokens
+pub const DEFAULT_AUDIENCE: &str = "api://AzureADTokenExchange";
+
+pub const SOURCES: &[&str] = &["github", "azure-pipelines"];
+
+fn env_var(name: &str, hint: &str) -> Result<String, String> {
+    env::var(name).ok().filter(|v| !v.is_empty()).ok_or_else(|| format!("'{}' is not set, {}", name, hint))
+}
+
+// https://docs.github.com/en/actions/deployment/security-hardening-your-deployments/about-security-hardening-with-openid-connect
+fn github_actions_token(audience: &str) -> Result<String, String> {
+    let hint = "the workflow needs 'permissions: id-token: write'";
+    let url = env_var("ACTIONS_ID_TOKEN_REQUEST_URL", hint)?;
+    let request_token = env_var("ACTIONS_ID_TOKEN_REQUEST_TOKEN", hint)?;
+    let url = format!("{}{}audience={}", url, if url.contains('?') { '&' } else { '?' }, form_urlencode(audience));
+    let headers = [(String::from("Authorization"), format!("bearer {}", request_token))];
+    let resp = http_client().get_with_headers(&resolve_url(&url), &headers)
+        .map_err(|e| format!("Unable to reach the GitHub OIDC provider, error is {}", e))?;
+    if !resp.is_success() {
+        return Err(format!("The GitHub OIDC provider returned status {}, {}", resp.status, resp.body.trim()));
+    }
+    resp.json::<Value>().ok()
+        .and_then(|v| v["value"].as_str().map(|s| s.to_owned()))
+        .ok_or_else(|| String::from("The GitHub OIDC provider didn't return a token"))
+}
+
+// The audience is always `api://AzureADTokenExchange`, the token is issued for the service connection,
+// `SYSTEM_ACCESSTOKEN` must be mapped into the environment of the step
+// https://learn.microsoft.com/en-us/rest/api/azure/devops/distributedtask/oidctoken/create
+fn azure_pipelines_token(service_connection: &str) -> Result<String, String> {
+    let hint = "the step needs 'env: SYSTEM_ACCESSTOKEN: $(System.AccessToken)'";
+    let url = env_var("SYSTEM_OIDCREQUESTURI", "it's only set in Azure Pipelines jobs")?;
+    let access_token = env_var("SYSTEM_ACCESSTOKEN", hint)?;
+    let service_connection = match service_connection {
+        // Set by the `AzureCLI` and `AzurePowerShell` tasks
+        "" => env_var("AZURESUBSCRIPTION_SERVICE_CONNECTION_ID", "set 'FederatedServiceConnection' to the id of the service connection")?,
+        s => s.to_owned()
+    };
+    let url = format!("{}?api-version=7.1&serviceConnectionId={}", url, form_urlencode(&service_connection));
+    let headers = [(String::from("Authorization"), format!("Bearer {}", access_token))];
+    let resp = http_client().post_json(&resolve_url(&url), &json!({}), &headers)
+        .map_err(|e| format!("Unable to reach the Azure DevOps OIDC endpoint, error is {}", e))?;
+    if !resp.is_success() {
+        return Err(format!("The Azure DevOps OIDC endpoint returned status {}, {}", resp.status, resp.body.trim()));
+    }
+    resp.json::<Value>().ok()
+        .and_then(|v| v["oidcToken"].as_str().map(|s| s.to_owned()))
+        .ok_or_else(|| String::from("The Azure DevOps OIDC endpoint didn't return a token"))
+}
+
+// The federated token of the source, `github` for GitHub Actions, or `azure-pipelines` for the service connection of
+// Azure Pipelines
+pub fn client_assertion(source: &str, audience: &str, service_connection: &str) -> Result<String, String> {
+    let audience = if audience.is_empty() { DEFAULT_AUDIENCE } else { audience };
+    match source.to_lowercase().as_str() {
+        "github" => github_actions_token(audience),
+        "azure-pipelines" => azure_pipelines_token(service_connection),
+        _ => Err(format!("Unknown 'FederatedCredential' '{}', use one of {}", source, SOURCES.join(", ")))
+    }
+}
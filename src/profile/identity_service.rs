@@ -0,0 +1,295 @@
+use std::fs::{create_dir_all, File, OpenOptions};
+use std::path::PathBuf;
+
+use chrono::Utc;
+use fs2::FileExt;
+use serde_json::{json, Map, Value};
+
+use crate::profile::jwt::decode_claims;
+use crate::profile::user_profile::{UserProfile, UserToken};
+
+// The token cache shared by Microsoft developer tools like Azure PowerShell and Visual Studio, in the MSAL cache format
+// and protected the same way as MSAL extensions, https://github.com/AzureAD/microsoft-authentication-extensions-for-dotnet
+#[cfg(not(target_os = "windows"))]
+const SERVICE: &str = "Microsoft.Developer.IdentityService";
+#[cfg(not(target_os = "windows"))]
+const ACCOUNT: &str = "MSALCache";
+
+fn cache_dir() -> Option<PathBuf> {
+    #[cfg(target_os = "windows")]
+    let mut dir = dirs::data_local_dir()?;
+    #[cfg(not(target_os = "windows"))]
+    let mut dir = dirs::home_dir()?;
+    dir.push(".IdentityService");
+    create_dir_all(&dir).ok()?;
+    Some(dir)
+}
+
+fn cache_filename() -> Option<PathBuf> {
+    let mut path = cache_dir()?;
+    path.push("msal.cache");
+    Some(path)
+}
+
+// Other tools lock the same file while updating the cache
+fn lock_cache() -> Option<File> {
+    let mut path = cache_dir()?;
+    path.push("msal.cache.lockfile");
+    let file = OpenOptions::new().write(true).create(true).truncate(false).open(path).ok()?;
+    file.lock_exclusive().ok()?;
+    Some(file)
+}
+
+// Other tools check the modification time of the file to reload the cache
+#[cfg(not(target_os = "windows"))]
+fn touch_cache_file() {
+    if let Some(path) = cache_filename() {
+        OpenOptions::new().write(true).create(true).truncate(true).open(path).ok();
+    }
+}
+
+#[cfg(target_os = "windows")]
+fn dpapi(data: &[u8], protect: bool) -> Option<Vec<u8>> {
+    use std::ptr::{null, null_mut};
+    use winapi::um::dpapi::{CryptProtectData, CryptUnprotectData};
+    use winapi::um::winbase::LocalFree;
+    use winapi::um::wincrypt::DATA_BLOB;
+
+    let mut input = DATA_BLOB { cbData: data.len() as u32, pbData: data.as_ptr() as *mut u8 };
+    let mut output = DATA_BLOB { cbData: 0, pbData: null_mut() };
+    let ok = unsafe {
+        if protect {
+            CryptProtectData(&mut input, null(), null_mut(), null_mut(), null_mut(), 0, &mut output)
+        } else {
+            CryptUnprotectData(&mut input, null_mut(), null_mut(), null_mut(), null_mut(), 0, &mut output)
+        }
+    };
+    if ok == 0 {
+        return None;
+    }
+    let ret = unsafe { std::slice::from_raw_parts(output.pbData, output.cbData as usize).to_vec() };
+    unsafe { LocalFree(output.pbData as _) };
+    Some(ret)
+}
+
+// Encrypted with DPAPI for the current user, None if there is no cache yet
+#[cfg(target_os = "windows")]
+fn read_data() -> Result<Option<Vec<u8>>, String> {
+    let path = cache_filename().ok_or("the cache directory is not available")?;
+    let data = match std::fs::read(path) {
+        Ok(d) => d,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("error is {}", e))
+    };
+    if data.is_empty() {
+        return Ok(None);
+    }
+    dpapi(&data, false).map(Some).ok_or_else(|| String::from("it can't be decrypted"))
+}
+
+#[cfg(target_os = "windows")]
+fn write_data(data: &[u8]) -> bool {
+    match (dpapi(data, true), cache_filename()) {
+        (Some(data), Some(path)) => std::fs::write(path, data).is_ok(),
+        _ => false
+    }
+}
+
+// Stored in the login keychain
+#[cfg(target_os = "macos")]
+fn read_data() -> Result<Option<Vec<u8>>, String> {
+    use security_framework::os::macos::keychain::SecKeychain;
+
+    // errSecItemNotFound
+    const ITEM_NOT_FOUND: i32 = -25300;
+    let keychain = SecKeychain::default().map_err(|e| format!("error is {}", e))?;
+    match keychain.find_generic_password(SERVICE, ACCOUNT) {
+        Ok((password, _)) => Ok(Some(password.to_vec())),
+        Err(e) if e.code() == ITEM_NOT_FOUND => Ok(None),
+        Err(e) => Err(format!("error is {}", e))
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn write_data(data: &[u8]) -> bool {
+    use security_framework::os::macos::keychain::SecKeychain;
+
+    let ok = SecKeychain::default().and_then(|k| k.set_generic_password(SERVICE, ACCOUNT, data)).is_ok();
+    touch_cache_file();
+    ok
+}
+
+// Stored in the Secret Service keyring via libsecret's `secret-tool`
+#[cfg(not(any(target_os = "macos", target_os = "windows")))]
+fn secret_tool_attributes() -> Vec<&'static str> {
+    vec!["xdg:schema", "msal.cache", "MsalClientID", SERVICE, SERVICE, "1.0.0.0"]
+}
+
+// `secret-tool` fails without a message if there is no such item
+#[cfg(not(any(target_os = "macos", target_os = "windows")))]
+fn read_data() -> Result<Option<Vec<u8>>, String> {
+    use std::process::Command;
+
+    let output = Command::new("secret-tool").arg("lookup").args(secret_tool_attributes()).output()
+        .map_err(|e| format!("unable to run 'secret-tool', error is {}", e))?;
+    let error = String::from_utf8_lossy(&output.stderr).trim().to_owned();
+    match (output.status.success(), error.is_empty()) {
+        (true, _) => Ok(Some(output.stdout)),
+        (false, true) => Ok(None),
+        (false, false) => Err(format!("error is {}", error))
+    }
+}
+
+#[cfg(not(any(target_os = "macos", target_os = "windows")))]
+fn write_data(data: &[u8]) -> bool {
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+
+    let child = Command::new("secret-tool")
+        .arg("store")
+        .arg(format!("--label={}", ACCOUNT))
+        .args(secret_tool_attributes())
+        .stdin(Stdio::piped())
+        .spawn();
+    let ok = match child {
+        Ok(mut c) => {
+            // Close stdin before waiting, `secret-tool` reads it to the end
+            let written = c.stdin.take().map(|mut s| s.write_all(data).is_ok()).unwrap_or_default();
+            c.wait().map(|s| s.success()).unwrap_or_default() && written
+        }
+        Err(_) => false
+    };
+    touch_cache_file();
+    ok
+}
+
+// The shared cache, empty if there is none yet, fails if it can't be read or isn't in the MSAL format,
+// so it's never written back without the entries of the other tools
+fn load_cache() -> Result<Value, String> {
+    let data = match read_data()? {
+        Some(d) if !d.is_empty() => d,
+        _ => return Ok(json!({}))
+    };
+    match serde_json::from_slice(&data) {
+        Ok(v @ Value::Object(_)) => Ok(v),
+        Ok(_) => Err(String::from("it's not a JSON object")),
+        Err(e) => Err(format!("it's not valid JSON, error is {}", e))
+    }
+}
+
+fn environment(profile: &UserProfile) -> String {
+    profile.authority.trim_start_matches("https://").trim_end_matches('/').to_lowercase()
+}
+
+// Find a refresh token of the client, of the account if it's given
+pub fn find_refresh_token(profile: &UserProfile, account: &str) -> Option<String> {
+    let _lock = lock_cache();
+    let cache = load_cache().ok()?;
+    let env = environment(profile);
+
+    // The home account id of the account, the username is looked up in the accounts
+    let home_account_id = if account.is_empty() {
+        None
+    } else {
+        Some(cache["Account"].as_object()
+            .and_then(|accounts| accounts.values().find(|a| {
+                a["username"].as_str().unwrap_or_default().eq_ignore_ascii_case(account)
+            }))
+            .and_then(|a| a["home_account_id"].as_str())
+            .unwrap_or(account))
+    };
+
+    cache["RefreshToken"].as_object()?.values().find(|t| {
+        // Saved in lower case
+        t["client_id"].as_str().unwrap_or_default().eq_ignore_ascii_case(&profile.client_id)
+            && t["environment"].as_str().unwrap_or_default().eq_ignore_ascii_case(&env)
+            && match home_account_id {
+                Some(id) => t["home_account_id"].as_str().unwrap_or_default().eq_ignore_ascii_case(id),
+                None => true
+            }
+    }).and_then(|t| t["secret"].as_str()).map(|s| s.to_owned())
+}
+
+// Save the tokens into the shared cache, so other tools can use the sign-in
+pub fn save_token(profile: &UserProfile, token: &UserToken) {
+    let home_account_id = match token.get_home_account_id() {
+        Some(id) => id.to_lowercase(),
+        // Tokens without an account can not be shared
+        None => return
+    };
+    let claims = decode_claims(&token.id_token).unwrap_or_default();
+    let env = environment(profile);
+    let realm = claims["tid"].as_str().unwrap_or(&profile.tenant).to_lowercase();
+    let client_id = profile.client_id.to_lowercase();
+    let target = token.scope.to_lowercase();
+    let now = Utc::now().timestamp();
+
+    // Other tools may be writing it, or have written what can't be read back, their entries would be lost
+    let _lock = match lock_cache() {
+        Some(l) => l,
+        None => {
+            print_warning!("Unable to lock the shared token cache, the token is not saved to it.");
+            return;
+        }
+    };
+    let mut cache = match load_cache() {
+        Ok(c) => c,
+        Err(e) => {
+            print_warning!("Unable to read the shared token cache, {}, the token is not saved to it.", e);
+            return;
+        }
+    };
+    let mut insert = |section: &str, key: String, value: Value| {
+        if !cache[section].is_object() {
+            cache[section] = Value::Object(Map::new());
+        }
+        cache[section][key] = value;
+    };
+
+    insert("Account", format!("{}-{}-{}", home_account_id, env, realm), json!({
+        "home_account_id": home_account_id,
+        "environment": env,
+        "realm": realm,
+        "local_account_id": claims["oid"].as_str().unwrap_or_default(),
+        "username": token.get_username().unwrap_or_default(),
+        "authority_type": "MSSTS",
+    }));
+    if !token.access_token.is_empty() {
+        insert("AccessToken", format!("{}-{}-accesstoken-{}-{}-{}", home_account_id, env, client_id, realm, target), json!({
+            "home_account_id": home_account_id,
+            "environment": env,
+            "client_id": client_id,
+            "credential_type": "AccessToken",
+            "secret": token.access_token,
+            "realm": realm,
+            "target": target,
+            "cached_at": now.to_string(),
+            "expires_on": token.expires_on.to_string(),
+            // Only set by the responses with `ext_expires_in`
+            "extended_expires_on": if token.ext_expires_on > 0 { token.ext_expires_on } else { token.expires_on }.to_string(),
+        }));
+    }
+    if !token.refresh_token.is_empty() {
+        insert("RefreshToken", format!("{}-{}-refreshtoken-{}--", home_account_id, env, client_id), json!({
+            "home_account_id": home_account_id,
+            "environment": env,
+            "client_id": client_id,
+            "credential_type": "RefreshToken",
+            "secret": token.refresh_token,
+        }));
+    }
+    if !token.id_token.is_empty() {
+        insert("IdToken", format!("{}-{}-idtoken-{}-{}-", home_account_id, env, client_id, realm), json!({
+            "home_account_id": home_account_id,
+            "environment": env,
+            "realm": realm,
+            "client_id": client_id,
+            "credential_type": "IdToken",
+            "secret": token.id_token,
+        }));
+    }
+
+    if !write_data(cache.to_string().as_bytes()) {
+        print_warning!("Unable to save the token to the shared token cache.");
+    }
+}
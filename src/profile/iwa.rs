@@ -0,0 +1,123 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use serde::Deserialize;
+
+use crate::profile::http::{http_client, resolve_url};
+use crate::profile::new_request_id;
+
+// Integrated Windows Authentication, the federated identity provider (AD FS) issues a SAML assertion for the Kerberos
+// ticket of the Windows user, which is exchanged for the tokens, so domain-joined machines get them without prompts,
+// https://learn.microsoft.com/en-us/entra/identity-platform/msal-authentication-flows#integrated-windows-authentication
+
+const SAML1_TOKEN_TYPE: &str = "urn:oasis:names:tc:SAML:1.0:assertion";
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct UserRealm {
+    account_type: String,
+    federation_protocol: String,
+    federation_metadata_url: String,
+}
+
+// The signed in Windows user, `whoami /upn` only works for domain accounts
+pub fn windows_username() -> Option<String> {
+    let output = Command::new("whoami").arg("/upn").output().ok().filter(|o| o.status.success())?;
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_owned()).filter(|u| u.contains('@'))
+}
+
+fn get_text(url: &str) -> Result<String, String> {
+    let resp = http_client().get(&resolve_url(url)).map_err(|e| format!("unable to reach '{}', error is {}", url, e))?;
+    if !resp.is_success() {
+        return Err(format!("'{}' returned status {}", url, resp.status));
+    }
+    Ok(resp.body)
+}
+
+// The WS-Trust endpoint of Windows transport in the metadata exchange document, WS-Trust 1.3 is preferred
+fn windows_transport_endpoint(mex: &str) -> Option<String> {
+    let addresses: Vec<&str> = mex.split('<')
+        .filter_map(|e| e.split_once('>').map(|(_, text)| text.trim()))
+        .filter(|text| text.starts_with("https://") && text.to_lowercase().ends_with("/windowstransport"))
+        .collect();
+    addresses.iter().find(|a| a.contains("/13/")).or_else(|| addresses.first()).map(|a| a.to_string())
+}
+
+fn request_security_token(endpoint: &str) -> String {
+    // WS-Trust 1.3, or WS-Trust 2005 of older AD FS
+    let (version, key_type) = if endpoint.contains("/13/") {
+        ("http://docs.oasis-open.org/ws-sx/ws-trust/200512", "http://docs.oasis-open.org/ws-sx/ws-trust/200512/Bearer")
+    } else {
+        ("http://schemas.xmlsoap.org/ws/2005/02/trust", "http://schemas.xmlsoap.org/ws/2005/05/identity/NoProofKey")
+    };
+    format!(concat!(
+        r#"<s:Envelope xmlns:s="http://www.w3.org/2003/05/soap-envelope" xmlns:wsa="http://www.w3.org/2005/08/addressing">"#,
+        r#"<s:Header><wsa:Action s:mustUnderstand="1">{version}/RST/Issue</wsa:Action>"#,
+        r#"<wsa:MessageID>urn:uuid:{id}</wsa:MessageID>"#,
+        r#"<wsa:ReplyTo><wsa:Address>http://www.w3.org/2005/08/addressing/anonymous</wsa:Address></wsa:ReplyTo>"#,
+        r#"<wsa:To s:mustUnderstand="1">{endpoint}</wsa:To></s:Header>"#,
+        r#"<s:Body><wst:RequestSecurityToken xmlns:wst="{version}">"#,
+        r#"<wsp:AppliesTo xmlns:wsp="http://schemas.xmlsoap.org/ws/2004/09/policy"><wsa:EndpointReference>"#,
+        r#"<wsa:Address>urn:federation:MicrosoftOnline</wsa:Address></wsa:EndpointReference></wsp:AppliesTo>"#,
+        r#"<wst:KeyType>{key_type}</wst:KeyType><wst:RequestType>{version}/Issue</wst:RequestType>"#,
+        r#"</wst:RequestSecurityToken></s:Body></s:Envelope>"#),
+        version = version, id = new_request_id(), endpoint = endpoint, key_type = key_type)
+}
+
+// The HTTP client can't do Negotiate, PowerShell sends the request with the Kerberos ticket of the Windows user
+fn post_with_windows_credentials(url: &str, body: &str) -> Result<String, String> {
+    let script = format!(
+        "$r = Invoke-WebRequest -UseBasicParsing -UseDefaultCredentials -Method Post -Uri '{}' \
+         -ContentType 'application/soap+xml; charset=utf-8' -Body ([Console]::In.ReadToEnd()); $r.Content",
+        resolve_url(url).replace('\'', "''"));
+    let mut child = Command::new("powershell")
+        .args(["-NoProfile", "-NonInteractive", "-Command", &script])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("unable to run PowerShell, error is {}", e))?;
+    child.stdin.take().map(|mut stdin| stdin.write_all(body.as_bytes())).transpose().map_err(|e| e.to_string())?;
+    let output = child.wait_with_output().map_err(|e| e.to_string())?;
+    if !output.status.success() {
+        return Err(format!("AD FS rejected the Windows credentials, error is {}", String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+// The content of the `RequestedSecurityToken` element, whatever the namespace prefix is
+fn requested_security_token(response: &str) -> Option<&str> {
+    let start = response.find("RequestedSecurityToken>")? + "RequestedSecurityToken>".len();
+    let end = response.rfind("RequestedSecurityToken>")?;
+    let end = response[..end].rfind("</")?;
+    Some(response[start..end].trim()).filter(|t| !t.is_empty())
+}
+
+// Get the SAML assertion of the Windows user from the federated identity provider,
+// returns the grant type and the assertion to send to the token endpoint
+pub fn get_assertion(authority: &str, username: &str) -> Result<(&'static str, String), String> {
+    let url = format!("{}/common/userrealm/{}?api-version=1.0", authority.trim_end_matches('/'), username);
+    let realm: UserRealm = serde_json::from_str(&get_text(&url)?).map_err(|e| format!("unable to parse the user realm, error is {}", e))?;
+    if !realm.account_type.eq_ignore_ascii_case("Federated") || realm.federation_metadata_url.is_empty() {
+        return Err(format!("'{}' is not a federated account, only AD FS and other WS-Trust providers are supported", username));
+    }
+    if !realm.federation_protocol.eq_ignore_ascii_case("WSTrust") {
+        return Err(format!("the federation protocol is '{}' instead of WS-Trust", realm.federation_protocol));
+    }
+
+    let mex = get_text(&realm.federation_metadata_url)?;
+    let endpoint = windows_transport_endpoint(&mex)
+        .ok_or("the identity provider doesn't have a Windows transport endpoint")?;
+    let response = post_with_windows_credentials(&endpoint, &request_security_token(&endpoint))?;
+    if response.contains("Fault>") {
+        return Err(format!("AD FS returned a fault, {}", response.trim()));
+    }
+    let assertion = requested_security_token(&response).ok_or("AD FS didn't return an assertion")?;
+    let grant_type = if response.contains(SAML1_TOKEN_TYPE) {
+        "urn:ietf:params:oauth:grant-type:saml1_1-bearer"
+    } else {
+        "urn:ietf:params:oauth:grant-type:saml2-bearer"
+    };
+    // Sent base64url encoded, https://tools.ietf.org/html/rfc7522#section-2.1
+    Ok((grant_type, base64::encode_config(assertion, base64::URL_SAFE_NO_PAD)))
+}
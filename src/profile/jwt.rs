@@ -0,0 +1,8 @@
+use serde_json::Value;
+
+// Decode the claims of a JWT, the signature is not verified
+pub fn decode_claims(token: &str) -> Option<Value> {
+    let payload = token.split('.').nth(1)?;
+    let bytes = base64::decode_config(payload, base64::URL_SAFE_NO_PAD).ok()?;
+    serde_json::from_slice(&bytes).ok()
+}
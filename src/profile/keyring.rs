@@ -0,0 +1,88 @@
+// Passwords saved in the OS keyring, found by the service and the account name:
+// * Windows: a generic credential named "<service>:<account>" in the Credential Manager
+// * macOS: a generic password in the login keychain
+// * Linux: a Secret Service item with the `service` and `username` attributes, looked up with `secret-tool`
+
+#[cfg(target_os = "windows")]
+pub fn find_password(service: &str, account: &str) -> Option<String> {
+    use std::ptr::null_mut;
+    use winapi::um::wincred::{CRED_TYPE_GENERIC, CredFree, CredReadW, PCREDENTIALW};
+
+    let target: Vec<u16> = format!("{}:{}", service, account).encode_utf16().chain(Some(0)).collect();
+    let mut credential: PCREDENTIALW = null_mut();
+    unsafe {
+        if CredReadW(target.as_ptr(), CRED_TYPE_GENERIC, 0, &mut credential) == 0 {
+            return None;
+        }
+        // `cmdkey` saves the password in UTF-16
+        let c = &*credential;
+        let blob = std::slice::from_raw_parts(c.CredentialBlob as *const u16, c.CredentialBlobSize as usize / 2);
+        let password = String::from_utf16_lossy(blob);
+        CredFree(credential as _);
+        Some(password)
+    }
+}
+
+#[cfg(target_os = "macos")]
+pub fn find_password(service: &str, account: &str) -> Option<String> {
+    use security_framework::os::macos::keychain::SecKeychain;
+
+    let (password, _) = SecKeychain::default().ok()?.find_generic_password(service, account).ok()?;
+    String::from_utf8(password.to_vec()).ok()
+}
+
+#[cfg(not(any(target_os = "macos", target_os = "windows")))]
+pub fn find_password(service: &str, account: &str) -> Option<String> {
+    use std::process::Command;
+
+    let output = Command::new("secret-tool")
+        .args(["lookup", "service", service, "username", account])
+        .output().ok()?;
+    if output.status.success() && !output.stdout.is_empty() {
+        String::from_utf8(output.stdout).ok().map(|s| s.trim_end_matches('\n').to_owned())
+    } else {
+        None
+    }
+}
+
+// Passwords saved by `keytar`, the keyring module of Electron apps like VS Code, which stores them differently:
+// * Windows: a generic credential named "<service>/<account>", in UTF-8
+// * Linux: a Secret Service item with the `service` and `account` attributes
+
+#[cfg(target_os = "windows")]
+pub fn find_keytar_password(service: &str, account: &str) -> Option<String> {
+    use std::ptr::null_mut;
+    use winapi::um::wincred::{CRED_TYPE_GENERIC, CredFree, CredReadW, PCREDENTIALW};
+
+    let target: Vec<u16> = format!("{}/{}", service, account).encode_utf16().chain(Some(0)).collect();
+    let mut credential: PCREDENTIALW = null_mut();
+    unsafe {
+        if CredReadW(target.as_ptr(), CRED_TYPE_GENERIC, 0, &mut credential) == 0 {
+            return None;
+        }
+        let c = &*credential;
+        let blob = std::slice::from_raw_parts(c.CredentialBlob as *const u8, c.CredentialBlobSize as usize);
+        let password = String::from_utf8_lossy(blob).into_owned();
+        CredFree(credential as _);
+        Some(password)
+    }
+}
+
+#[cfg(target_os = "macos")]
+pub fn find_keytar_password(service: &str, account: &str) -> Option<String> {
+    find_password(service, account)
+}
+
+#[cfg(not(any(target_os = "macos", target_os = "windows")))]
+pub fn find_keytar_password(service: &str, account: &str) -> Option<String> {
+    use std::process::Command;
+
+    let output = Command::new("secret-tool")
+        .args(["lookup", "service", service, "account", account])
+        .output().ok()?;
+    if output.status.success() && !output.stdout.is_empty() {
+        String::from_utf8(output.stdout).ok().map(|s| s.trim_end_matches('\n').to_owned())
+    } else {
+        None
+    }
+}
@@ -1,48 +1,599 @@
-use std::collections::HashMap;
-use std::fs::{create_dir_all, File};
+use std::collections::{HashMap, VecDeque};
+use std::collections::hash_map::DefaultHasher;
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::hash::{Hash, Hasher};
+use std::path::PathBuf;
 use std::process::exit;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::sync::mpsc::channel;
+use std::thread;
 
-use chrono::{DateTime, NaiveDateTime, Utc};
+use chacha20poly1305::aead::OsRng;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chrono::{Local, TimeZone, Utc};
 use dirs::cache_dir;
-use reqwest::blocking::{Client, Response};
+use fs2::FileExt;
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 
-pub use app_profile::AppProfile;
-pub use user_profile::UserProfile;
+pub use app_profile::{AppProfile, AppToken};
+pub use cbor::{decode as decode_cbor, encode as encode_cbor};
+pub use discovery::DEFAULT_AUTHORITY;
+pub use http::{describe_proxy, http_client, HttpClient, HttpResponse, is_no_proxy, is_offline, ProxySettings, set_http_client, set_offline, set_proxy_settings};
+pub use jwt::decode_claims;
+pub use notify::set_notify;
+pub use plugin_profile::PluginProfile;
+pub use pop::PopKey;
+pub use user_profile::{UserProfile, UserToken};
+use user_profile::DeviceCodePoll;
+#[cfg(not(feature = "nogui"))]
+pub use user_profile::is_remote_session;
 
-use crate::profile::app_profile::AppToken;
-use crate::profile::user_profile::UserToken;
+use crate::profile::aad_error::{explain_error, is_permanent_error};
+use crate::profile::discovery::{discover_tenant, forget_endpoint, is_guid, is_tenant_keyword, split_ciam_authority, TenantInfo};
+use crate::profile::http::{resolve_url, with_resolve};
+use crate::redact::{is_secret, redact_json, redact_text, redact_value};
 
 mod user_profile;
+mod aad_error;
+mod cbor;
 mod app_profile;
+mod cert_store;
+mod client_assertion;
+mod device_flow;
+mod discovery;
+mod failure_cache;
+mod federated;
+mod http;
+mod identity_service;
+mod iwa;
+mod jwt;
+mod keyring;
+mod notify;
+mod pkcs11;
+mod plugin_profile;
+mod pop;
+mod refresh_tokens;
+mod token_cache;
+mod validate;
+mod vscode;
 
-fn send_request(url: &str, form: &HashMap<&str, &str>, ignore_error: bool) -> Response {
-    let resp = match Client::builder().build().unwrap().post(url).form(form).send() {
+static DEBUG_HTTP: AtomicBool = AtomicBool::new(false);
+
+// Trace all HTTP requests and responses to stderr, with secrets redacted
+pub fn set_debug_http(enabled: bool) {
+    DEBUG_HTTP.store(enabled, Ordering::Relaxed);
+}
+
+// Decrypts the secrets encrypted in the configuration file right before they're sent,
+// so commands not sending any never ask for the passphrase
+type SecretDecryptor = fn(&str) -> String;
+
+static SECRET_DECRYPTOR: Mutex<Option<SecretDecryptor>> = Mutex::new(None);
+
+pub fn set_secret_decryptor(decrypt: SecretDecryptor) {
+    *SECRET_DECRYPTOR.lock().unwrap() = Some(decrypt);
+}
+
+// The secret as it's sent
+fn plain_secret(secret: &str) -> String {
+    let decrypt = *SECRET_DECRYPTOR.lock().unwrap();
+    match decrypt {
+        Some(decrypt) if !secret.is_empty() => decrypt(secret),
+        _ => secret.to_owned()
+    }
+}
+
+// Limits of the token cache, so it doesn't grow with tokens of profiles no longer used
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct CachePolicy {
+    // Max number of cached tokens, the least recently refreshed ones are evicted first, 0 for no limit
+    pub max_entries: usize,
+    // Seconds a refresh token is kept after it's issued, 0 for the AAD default of 90 days
+    pub refresh_token_max_age: i64,
+    // Keep the token responses as is, for `last-response`
+    pub keep_raw_response: bool,
+    // Save the cached tokens in CBOR instead of JSON
+    pub binary: bool,
+    // Seconds permanent failures, i.e. an expired client secret, are cached for, 0 to not cache them
+    pub failure_ttl: i64,
+}
+
+static CACHE_POLICY: Mutex<CachePolicy> = Mutex::new(CachePolicy { max_entries: 0, refresh_token_max_age: 0, keep_raw_response: false, binary: false, failure_ttl: 0 });
+
+pub fn set_cache_policy(policy: CachePolicy) {
+    *CACHE_POLICY.lock().unwrap() = policy;
+}
+
+fn cache_policy() -> CachePolicy {
+    *CACHE_POLICY.lock().unwrap()
+}
+
+// The directory of the cached tokens, refresh tokens, discovery results and PoP keys, `TOKENGEN_CACHE_DIR`
+// replaces the platform cache directory, i.e. to keep tests away from the real cache
+pub fn cache_root() -> Option<PathBuf> {
+    match std::env::var_os("TOKENGEN_CACHE_DIR") {
+        Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir)),
+        _ => cache_dir().map(|d| d.join("tokengen"))
+    }
+}
+
+// The token response kept in the cache if `keep_raw_response` is set, the refresh token is redacted as it's saved separately
+pub(crate) fn raw_response(body: &str) -> Value {
+    if !cache_policy().keep_raw_response {
+        return Value::Null;
+    }
+    let mut response: Value = serde_json::from_str(body).unwrap_or(Value::Null);
+    if let Some(Value::String(s)) = response.get_mut("refresh_token") {
+        *s = redact_value(s);
+    }
+    response
+}
+
+fn trace_request(url: &str, form: &HashMap<&str, &str>, headers: &[(String, String)]) {
+    eprintln!("> POST {}", url);
+    eprintln!("> content-type: application/x-www-form-urlencoded");
+    for (k, v) in headers {
+        eprintln!("> {}: {}", k.to_lowercase(), if is_secret(k) { redact_value(v) } else { v.to_owned() });
+    }
+    let mut fields: Vec<_> = form.iter().collect();
+    fields.sort();
+    for (k, v) in fields {
+        eprintln!("> {}={}", k, if is_secret(k) { redact_value(v) } else { v.to_string() });
+    }
+}
+
+fn trace_response(resp: &HttpResponse) {
+    eprintln!("< {}", resp.status);
+    for (k, v) in resp.headers.iter() {
+        eprintln!("< {}: {}", k, if is_secret(k) { redact_value(v) } else { v.to_owned() });
+    }
+    eprintln!("< {}", redact_text(&redact_json(&resp.body)));
+}
+
+fn send_request(url: &str, form: &HashMap<&str, &str>, ignore_error: bool) -> HttpResponse {
+    send_request_with_headers(url, form, &[], ignore_error)
+}
+
+thread_local! {
+    // Whether the token requests can fail softly because a token within its extended lifetime can be served instead,
+    // and whether they have failed
+    static OUTAGE_FALLBACK: Cell<bool> = const { Cell::new(false) };
+    static OUTAGE: Cell<bool> = const { Cell::new(false) };
+    // The cache key of the token being requested, permanent failures are cached for it
+    static FAILURE_KEY: RefCell<String> = const { RefCell::new(String::new()) };
+    // Whether failed requests are returned instead of exiting, i.e. when the agent renews many profiles,
+    // and the error of the last one
+    static SOFT_FAILURE: Cell<bool> = const { Cell::new(false) };
+    static FAILURE: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+// Run `f` with the outage fallback enabled, returns whether AAD was unreachable
+fn with_outage_fallback<T>(enabled: bool, f: impl FnOnce() -> T) -> (T, bool) {
+    OUTAGE_FALLBACK.with(|c| c.set(enabled));
+    OUTAGE.with(|c| c.set(false));
+    let ret = f();
+    OUTAGE_FALLBACK.with(|c| c.set(false));
+    (ret, OUTAGE.with(|c| c.replace(false)))
+}
+
+// Run `f` without exiting on failed requests, returns the error of the last one
+fn with_soft_failures<T>(f: impl FnOnce() -> T) -> (T, Option<String>) {
+    let soft = SOFT_FAILURE.with(|c| c.replace(true));
+    let failure = FAILURE.with(|e| e.borrow_mut().take());
+    let ret = f();
+    SOFT_FAILURE.with(|c| c.set(soft));
+    (ret, FAILURE.with(|e| e.replace(failure)))
+}
+
+// Whether a request has failed while running with soft failures
+fn has_failed() -> bool {
+    FAILURE.with(|e| e.borrow().is_some())
+}
+
+// Record the error of a failed request, the flows treat the returned response as a failure
+fn soft_failure(error: String, resp: Option<HttpResponse>) -> HttpResponse {
+    FAILURE.with(|e| *e.borrow_mut() = Some(error));
+    resp.unwrap_or_else(|| HttpResponse {
+        status: 503,
+        headers: vec![],
+        body: json!({ "error": "temporarily_unavailable" }).to_string(),
+    })
+}
+
+// Run `f` requesting the token of `key`, so its permanent failures are cached
+fn with_failure_key<T>(key: &str, f: impl FnOnce() -> T) -> T {
+    let previous = FAILURE_KEY.with(|k| k.replace(key.to_owned()));
+    let ret = f();
+    FAILURE_KEY.with(|k| *k.borrow_mut() = previous);
+    ret
+}
+
+// Cache the failure of the token being requested if retrying can't fix it
+fn remember_failure(body: &str) {
+    let ttl = cache_policy().failure_ttl;
+    let key = FAILURE_KEY.with(|k| k.borrow().clone());
+    if ttl > 0 && !key.is_empty() && is_permanent_error(body) {
+        failure_cache::insert(&key, &explain_error(body).unwrap_or_default(), ttl);
+    }
+}
+
+// Fail fast with the cached failure of the key instead of sending the same request again,
+// returns false if it has failed with soft failures
+fn check_failure(key: &str) -> bool {
+    if cache_policy().failure_ttl <= 0 {
+        return true;
+    }
+    if let Some((error, ttl)) = failure_cache::find(key) {
+        let error = format!("Failed to get token, the last attempt has failed with:\n{}\nIt's not retried for {} more second(s), run 'logout' to retry now after fixing the profile.", error, ttl);
+        if SOFT_FAILURE.with(|c| c.get()) {
+            FAILURE.with(|e| *e.borrow_mut() = Some(error));
+            return false;
+        }
+        print_error!("{}", error);
+        exit(2);
+    }
+    true
+}
+
+// Only the cached token can be used in offline mode, fails like a failed request if a new one is needed,
+// exits with code 4 without soft failures
+fn check_offline() -> bool {
+    if !is_offline() {
+        return true;
+    }
+    let error = String::from("No valid cached token found in offline mode.");
+    if SOFT_FAILURE.with(|c| c.get()) {
+        FAILURE.with(|e| *e.borrow_mut() = Some(error));
+        return false;
+    }
+    print_error!("{}", error);
+    exit(4);
+}
+
+// The response of a token request to AAD being unreachable, the flows treat it as a failure
+fn outage_response() -> HttpResponse {
+    OUTAGE.with(|c| c.set(true));
+    HttpResponse {
+        status: 503,
+        headers: vec![],
+        body: json!({ "error": "temporarily_unavailable" }).to_string(),
+    }
+}
+
+// A random UUID v4
+fn new_request_id() -> String {
+    let mut b = [0u8; 16];
+    OsRng.fill_bytes(&mut b);
+    b[6] = (b[6] & 0x0f) | 0x40;
+    b[8] = (b[8] & 0x3f) | 0x80;
+    let hex: String = b.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+// The ids to look the request up in the AAD sign-in logs, `client-request-id` is sent with the request and
+// `x-ms-request-id` is returned by AAD, which is also the trace id in the error responses
+fn request_ids(client_request_id: &str, resp: Option<&HttpResponse>) -> String {
+    let request_id = resp.and_then(|r| r.headers.iter().find(|(k, _)| k.eq_ignore_ascii_case("x-ms-request-id")));
+    match request_id {
+        Some((_, id)) => format!("client-request-id is {}, x-ms-request-id is {}", client_request_id, id),
+        None => format!("client-request-id is {}", client_request_id)
+    }
+}
+
+fn send_request_with_headers(url: &str, form: &HashMap<&str, &str>, headers: &[(String, String)], ignore_error: bool) -> HttpResponse {
+    post_form_request(url, form, headers, |_| ignore_error)
+}
+
+// Only the error responses `ignored` accepts are returned, the others are fatal
+fn send_request_ignoring(url: &str, form: &HashMap<&str, &str>, ignored: fn(&str) -> bool) -> HttpResponse {
+    post_form_request(url, form, &[], |r| ignored(&r.body))
+}
+
+fn post_form_request(url: &str, form: &HashMap<&str, &str>, headers: &[(String, String)], ignore_error: impl Fn(&HttpResponse) -> bool) -> HttpResponse {
+    let endpoint = url;
+    let url = &resolve_url(url);
+    let client_request_id = new_request_id();
+    let mut headers = headers.to_vec();
+    headers.push((String::from("client-request-id"), client_request_id.clone()));
+    headers.push((String::from("return-client-request-id"), String::from("true")));
+    let debug = DEBUG_HTTP.load(Ordering::Relaxed);
+    if debug {
+        trace_request(url, form, &headers);
+    }
+    let fallback = OUTAGE_FALLBACK.with(|c| c.get());
+    let result = http_client().post_form(url, form, &headers);
+    // The endpoint may have moved since it was discovered
+    if result.as_ref().map_or(!is_offline(), |r| r.status == 404) {
+        forget_endpoint(endpoint);
+    }
+    let resp = match result {
         Ok(r) => r,
+        Err(e) if fallback => {
+            print_warning!("Request failed, {}, error is {:#?}", request_ids(&client_request_id, None), e);
+            return outage_response();
+        }
+        Err(e) if SOFT_FAILURE.with(|c| c.get()) => {
+            return soft_failure(format!("Request failed, {}, error is {:?}", request_ids(&client_request_id, None), e), None);
+        }
         Err(e) => {
-            eprintln!("ERROR: Request failed, error is {:#?}", e);
+            print_error!("Request failed, {}, error is {:#?}", request_ids(&client_request_id, None), e);
             exit(1);
         }
     };
-    if !ignore_error && !resp.status().is_success() {
-        eprintln!("ERROR: Request failed, status is {}", resp.status());
-        exit(i32::from(resp.status().as_u16()))
+    if debug {
+        trace_response(&resp);
+    }
+    // Server errors and throttling, the request itself is fine
+    if fallback && (resp.status >= 500 || resp.status == 429) {
+        print_warning!("Request failed, status is {}, {}", resp.status, request_ids(&client_request_id, Some(&resp)));
+        return outage_response();
+    }
+    if !resp.is_success() && !ignore_error(&resp) {
+        remember_failure(&resp.body);
+        if SOFT_FAILURE.with(|c| c.get()) {
+            let error = format!("Request failed, status is {}, {}", resp.status, request_ids(&client_request_id, Some(&resp)));
+            let error = match explain_error(&resp.body) {
+                Some(e) => format!("{}\n{}", error, e),
+                None => error
+            };
+            return soft_failure(error, Some(resp));
+        }
+        match explain_error(&resp.body) {
+            Some(e) => print_error!("Request failed, status is {}, {}\n{}", resp.status, request_ids(&client_request_id, Some(&resp)), e),
+            None => print_error!("Request failed, status is {}, {}", resp.status, request_ids(&client_request_id, Some(&resp)))
+        }
+        exit(i32::from(resp.status))
     }
 
     resp
 }
 
+fn form_urlencode(s: &str) -> String {
+    s.bytes().map(|b| match b {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'*' => (b as char).to_string(),
+        b' ' => String::from("+"),
+        _ => format!("%{:02X}", b),
+    }).collect()
+}
+
+// Client credentials in the Authorization header, https://tools.ietf.org/html/rfc6749#section-2.3.1
+fn basic_auth_header(client_id: &str, secret: &str) -> (String, String) {
+    let credentials = format!("{}:{}", form_urlencode(client_id), form_urlencode(secret));
+    (String::from("Authorization"), format!("Basic {}", base64::encode(credentials)))
+}
+
+// Send a GET request for the discovery, failures are not fatal and not reported as the defaults are used instead,
+// None if the server is unreachable
+fn send_get_request(url: &str) -> Option<HttpResponse> {
+    let url = &resolve_url(url);
+    let debug = DEBUG_HTTP.load(Ordering::Relaxed);
+    if debug {
+        eprintln!("> GET {}", url);
+    }
+    let resp = match http_client().get(url) {
+        Ok(r) => r,
+        Err(e) => {
+            if debug {
+                eprintln!("< {:#?}", e);
+            }
+            return None;
+        }
+    };
+    if debug {
+        trace_response(&resp);
+    }
+    Some(resp)
+}
+
+// Lock a file for the cache key, wait if another process is holding it,
+// returns the locked file and whether it had to wait
+fn lock_interactive_flow(key: &str) -> (Option<File>, bool) {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    let mut lock_filename = cache_root().unwrap();
+    lock_filename.push(format!("flow-{:016x}.lock", hasher.finish()));
+
+    let lock_file = match OpenOptions::new().write(true).create(true).truncate(false).open(lock_filename.as_path()) {
+        Ok(f) => f,
+        Err(e) => {
+            print_warning!("Unable to create lock file '{}', error is {:#?}.", lock_filename.to_string_lossy(), e);
+            return (None, false);
+        }
+    };
+    if lock_file.try_lock_exclusive().is_ok() {
+        return (Some(lock_file), false);
+    }
+
+    eprintln!("Another tokengen process is signing in with the same profile, waiting for it to finish...");
+    match lock_file.lock_exclusive() {
+        Ok(_) => (Some(lock_file), true),
+        Err(e) => {
+            print_warning!("Unable to lock file '{}', error is {:#?}.", lock_filename.to_string_lossy(), e);
+            (None, true)
+        }
+    }
+}
+
+// Form fields set by the token flows, `ExtraParams` can't replace them
+const RESERVED_PARAMS: &[&str] = &[
+    "grant_type", "client_id", "client_secret", "client_assertion", "client_assertion_type", "scope", "resource",
+    "refresh_token", "device_code", "assertion", "requested_token_use", "claims", "username", "password",
+];
+
+fn is_reserved_param(name: &str) -> bool {
+    RESERVED_PARAMS.iter().any(|p| p.eq_ignore_ascii_case(name))
+}
+
+// Add `ExtraParams` to the form, the reserved ones are skipped with a warning
+fn add_extra_params<'a>(form: &mut HashMap<&'a str, &'a str>, extra_params: &'a HashMap<String, String>) {
+    for (k, v) in extra_params.iter() {
+        if is_reserved_param(k) {
+            print_warning!("'{}' in 'ExtraParams' is ignored, it's set by tokengen.", k);
+            continue;
+        }
+        form.insert(k, v);
+    }
+}
+
+// `ExtraParams` in the cache key, the tokens may differ with them, sorted so the order doesn't matter
+fn extra_params_key(extra_params: &HashMap<String, String>) -> String {
+    let mut params: Vec<String> = extra_params.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+    params.sort();
+    params.join("&")
+}
+
+// Merge the client capabilities into the claims request, i.e. `{"access_token":{"xms_cc":{"values":["cp1"]}}}`
+// tells AAD the client can handle claims challenges from Continuous Access Evaluation
+fn merge_client_capabilities(claims: &str, capabilities: &[String]) -> String {
+    if capabilities.is_empty() {
+        return claims.to_owned();
+    }
+    let mut value: Value = serde_json::from_str(claims).unwrap_or_else(|_| json!({}));
+    value["access_token"]["xms_cc"] = json!({ "values": capabilities });
+    value.to_string()
+}
+
+fn serve_stale(token: Token) -> Token {
+    print_warning!("AAD is unreachable, using the expired token which is still accepted until {}.",
+        Local.timestamp(token.get_ext_expires_on(), 0).format("%Y-%m-%d %H:%M:%S"));
+    token
+}
+
+// AAD accepts tokens and assertions at most 5 minutes off
+const MAX_CLOCK_SKEW: i64 = 300;
+
+static CLOCK_SKEW_WARNED: AtomicBool = AtomicBool::new(false);
+
+// Warn once if the local clock is off from AAD, the issue time on AAD's clock is `exp` minus `expires_in` of a token
+// just issued, a skewed clock makes the expiration checks wrong and the resources reject the tokens
+fn check_clock_skew(token: &str, expires_in: i64) {
+    let exp = match decode_claims(token).and_then(|c| c["exp"].as_i64()) {
+        Some(v) if expires_in > 0 => v,
+        _ => return
+    };
+    let skew = Utc::now().timestamp() - (exp - expires_in);
+    if skew.abs() > MAX_CLOCK_SKEW && !CLOCK_SKEW_WARNED.swap(true, Ordering::Relaxed) {
+        print_warning!("The local clock is {} second(s) {} AAD, tokens may be used after they expire, sync the clock.",
+            skew.abs(), if skew < 0 { "behind" } else { "ahead of" });
+    }
+}
+
+// Seconds taken off `expires_in` of new tokens, for the time the response took to arrive
+static EXPIRY_SKEW: AtomicI64 = AtomicI64::new(5);
+
+// Tokens with less than these seconds left are treated as expired, and refreshed before they're used
+static MIN_REMAINING: AtomicI64 = AtomicI64::new(60);
+
+pub fn set_expiry_skew(seconds: i64) {
+    EXPIRY_SKEW.store(seconds, Ordering::Relaxed);
+}
+
+pub fn set_min_remaining(seconds: i64) {
+    MIN_REMAINING.store(seconds, Ordering::Relaxed);
+}
+
+pub fn min_remaining() -> i64 {
+    MIN_REMAINING.load(Ordering::Relaxed)
+}
+
+// The expiration of a token issued just now for `expires_in` seconds
+pub(crate) fn expires_on_from(expires_in: i64) -> i64 {
+    Utc::now().timestamp() + expires_in - EXPIRY_SKEW.load(Ordering::Relaxed)
+}
+
 fn is_expired(expires_on: i64) -> bool {
-    let exp = DateTime::<Utc>::from_utc(NaiveDateTime::from_timestamp(expires_on, 0), Utc);
-    let duration = exp.signed_duration_since(Utc::now());
-    duration.num_minutes() < 1
+    expires_on - Utc::now().timestamp() < min_remaining()
 }
 
 pub trait AADToken {
     fn is_expired(&self) -> bool;
+    fn get_expires_on(&self) -> i64;
     fn get_token_string(&self, token_type: TokenType) -> String;
 }
 
+// A source of tokens, implemented by the `App` and `User` profiles, library users can add their own with `Profile::custom`
+pub trait TokenProvider: Send + Sync {
+    // Get a new token, may need user interaction
+    fn acquire(&self) -> Token;
+
+    // Renew the token without user interaction, `None` if it has to be acquired again
+    fn refresh(&self, token: &Token) -> Option<Token>;
+
+    // The key of the token in the cache
+    fn cache_key(&self) -> String;
+}
+
+impl TokenProvider for AppProfile {
+    fn acquire(&self) -> Token {
+        Token::App(self.get_token())
+    }
+
+    fn refresh(&self, _token: &Token) -> Option<Token> {
+        None
+    }
+
+    fn cache_key(&self) -> String {
+        self.get_key()
+    }
+}
+
+impl TokenProvider for UserProfile {
+    fn acquire(&self) -> Token {
+        Token::User(self.get_token())
+    }
+
+    fn refresh(&self, token: &Token) -> Option<Token> {
+        match token {
+            Token::User(t) => self.refresh_token(t).map(Token::User),
+            Token::App(_) => None
+        }
+    }
+
+    fn cache_key(&self) -> String {
+        self.get_key()
+    }
+}
+
+impl TokenProvider for PluginProfile {
+    fn acquire(&self) -> Token {
+        Token::User(self.get_token())
+    }
+
+    fn refresh(&self, token: &Token) -> Option<Token> {
+        match token {
+            Token::User(t) => self.refresh_token(t).map(Token::User),
+            Token::App(_) => None
+        }
+    }
+
+    fn cache_key(&self) -> String {
+        self.get_key()
+    }
+}
+
+// A named `TokenProvider` not defined in the configuration file
+#[derive(Clone)]
+pub struct CustomProfile {
+    pub name: String,
+    pub provider: Arc<dyn TokenProvider>,
+}
+
+impl fmt::Debug for CustomProfile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CustomProfile").field("name", &self.name).finish()
+    }
+}
+
+impl PartialEq for CustomProfile {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name && Arc::ptr_eq(&self.provider, &other.provider)
+    }
+}
+
 #[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
 pub enum Token {
     App(AppToken),
@@ -63,6 +614,42 @@ impl Default for Token {
     }
 }
 
+impl Token {
+    // The end of the extended lifetime, until when the token is still accepted if AAD is unreachable
+    fn get_ext_expires_on(&self) -> i64 {
+        match self {
+            Token::User(t) => t.ext_expires_on,
+            Token::App(t) => t.ext_expires_on
+        }
+    }
+
+    fn is_within_ext_lifetime(&self) -> bool {
+        !is_expired(self.get_ext_expires_on())
+    }
+
+    // Both tokens and the granted scopes in one document, the refresh token is never included
+    pub fn to_json(&self) -> Value {
+        let mut doc = json!({
+            "token_type": "Bearer",
+            "expires_on": self.get_expires_on(),
+        });
+        let scope = match self {
+            Token::User(t) => t.scope.clone(),
+            Token::App(_) => String::new()
+        };
+        for (name, value) in [
+            ("access_token", self.get_token_string(TokenType::Access)),
+            ("id_token", self.get_token_string(TokenType::Id)),
+            ("scope", scope),
+        ] {
+            if !value.is_empty() {
+                doc[name] = Value::String(value);
+            }
+        }
+        doc
+    }
+}
+
 impl AADToken for Token {
     fn is_expired(&self) -> bool {
         match self {
@@ -71,6 +658,13 @@ impl AADToken for Token {
         }
     }
 
+    fn get_expires_on(&self) -> i64 {
+        match self {
+            Token::User(t) => t.get_expires_on(),
+            Token::App(t) => t.get_expires_on()
+        }
+    }
+
     fn get_token_string(&self, token_type: TokenType) -> String {
         match self {
             Token::User(t) => t.get_token_string(token_type),
@@ -79,175 +673,701 @@ impl AADToken for Token {
     }
 }
 
+// The outcome of `Profile::renew_token`
+#[derive(Clone, Debug)]
+pub enum Renewal {
+    // The cached token is valid for the margin
+    Fresh,
+    Renewed(Box<Token>),
+    // No cached token can be refreshed without user interaction
+    NeedsSignIn,
+    // The request has failed, with the error
+    Failed(String),
+}
+
+// Profile fields given on the command line, empty values are ignored
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct ProfileOverrides {
+    pub client_id: String,
+    pub secret: String,
+    pub tenant: String,
+    pub authority: String,
+    pub resource: String,
+    pub scope: String,
+    pub login_hint: String,
+    pub domain_hint: String,
+    pub prompt: String,
+    pub azure_region: String,
+    pub account: String,
+    pub client_capabilities: Vec<String>,
+    pub token_endpoint_auth_method: String,
+    pub federated_credential: String,
+    pub auth_timeout: u64,
+    pub poll_interval: u64,
+    pub shared_cache: bool,
+    pub integrated_windows_auth: bool,
+    pub vs_code: bool,
+    pub browser: String,
+    pub resolve: HashMap<String, String>,
+    pub aws_role_arn: String,
+    pub gcp_audience: String,
+    pub gcp_service_account: String,
+}
+
 #[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "PascalCase")]
 #[serde(tag = "Type")]
 pub enum Profile {
     App(AppProfile),
     User(UserProfile),
+    Plugin(PluginProfile),
+    #[serde(skip)]
+    Custom(CustomProfile),
 }
 
 impl Profile {
-    fn load_cache() -> HashMap<String, Token> {
-        let mut cache_dir = cache_dir().unwrap();
-        cache_dir.push("tokengen");
-        match create_dir_all(cache_dir.as_path()) {
-            Ok(_) => (),
-            Err(e) => {
-                eprintln!("WARNING: Unable to create cache directory '{}', error is {:#?}.", cache_dir.to_string_lossy(), e);
-                return HashMap::new();
-            }
+    pub fn custom(name: &str, provider: Arc<dyn TokenProvider>) -> Profile {
+        Profile::Custom(CustomProfile { name: name.to_owned(), provider })
+    }
+
+    fn provider(&self) -> &dyn TokenProvider {
+        match self {
+            Profile::App(p) => p,
+            Profile::User(p) => p,
+            Profile::Plugin(p) => p,
+            Profile::Custom(p) => p.provider.as_ref()
         }
+    }
 
-        let mut cache_filename = cache_dir.clone();
-        cache_filename.push("cache.json");
-        let cache_file = match File::open(cache_filename.as_path()) {
-            Ok(f) => f,
-            Err(_) => {
-                return HashMap::new();
-            }
-        };
+    fn get_key(&self) -> String {
+        self.provider().cache_key()
+    }
 
-        match serde_json::from_reader(cache_file) {
-            Ok(v) => v,
-            Err(e) => {
-                eprintln!("WARNING: Unable to load cache file at '{}', error is {:#?}.", cache_filename.to_string_lossy(), e);
-                HashMap::new()
+    // The key of the cached token, `User` tokens of a picked account are keyed by the home account id,
+    // so tokens of different identities never mix up even if they share the same username
+    fn get_cache_key(&self) -> String {
+        match self {
+            Profile::User(p) if !p.account.is_empty() => {
+                // The whole cache is only read to find the account
+                token_cache::find_key(|k, t| match t {
+                    Token::User(t) => p.is_account_key(k) && t.is_account(&p.account),
+                    Token::App(_) => false
+                })
+                    // The token doesn't carry the account info, i.e. no `openid` in the scope
+                    .unwrap_or_else(|| p.get_account_key(&p.account.to_lowercase()))
             }
+            _ => self.get_key()
         }
     }
 
-    fn save_cache(cache: HashMap<String, Token>) {
-        let mut cache_dir = cache_dir().unwrap();
-        cache_dir.push("tokengen");
-        match create_dir_all(cache_dir.as_path()) {
-            Ok(_) => (),
-            Err(e) => {
-                eprintln!("WARNING: Unable to create cache directory '{}', error is {:#?}.", cache_dir.to_string_lossy(), e);
-                return;
+    // The entries to save for the token, `key` is the cache key of the profile
+    fn token_entries(&self, key: &str, token: Token) -> HashMap<String, Token> {
+        let mut entries = HashMap::new();
+        if let (Profile::User(p), Token::User(t)) = (self, &token) {
+            // Signed in through a tenant keyword, also cache it for the tenant actually signed in to
+            if let Some(tid) = t.get_tenant_id().filter(|_| is_tenant_keyword(&p.tenant)) {
+                entries.insert(UserProfile { tenant: tid, ..p.clone() }.get_key(), token.clone());
             }
-        }
-
-        let mut cache_filename = cache_dir.clone();
-        cache_filename.push("cache.json");
-        let cache_file = match File::create(cache_filename.as_path()) {
-            Ok(f) => f,
-            Err(e) => {
-                eprintln!("WARNING: Unable to create cache file at '{}', error is {:#?}.", cache_filename.to_string_lossy(), e);
-                return;
+            if let Some(id) = t.get_home_account_id() {
+                // Also cache it for the account, so it can be picked with `--account` later
+                entries.insert(p.get_account_key(&id), token.clone());
+                if !p.account.is_empty() {
+                    return entries;
+                }
             }
-        };
-
-        let output: HashMap<String, Token> = cache.into_iter().filter(|(_, v)|
-            !v.is_expired()
-        ).collect();
+        }
+        entries.insert(key.to_owned(), token);
+        entries
+    }
 
-        match serde_json::to_writer(cache_file, &output) {
-            Ok(_) => (),
-            Err(e) => {
-                eprintln!("WARNING: Unable to save cache to '{}', error is {:#?}.", cache_filename.to_string_lossy(), e);
-            }
-        };
+    pub fn get_name(&self) -> &str {
+        match self {
+            Profile::App(p) => &p.name,
+            Profile::User(p) => &p.name,
+            Profile::Plugin(p) => &p.name,
+            Profile::Custom(p) => &p.name
+        }
     }
 
-    fn get_key(&self) -> String {
+    pub fn get_aws_role_arn(&self) -> &str {
         match self {
-            Profile::App(p) => p.get_key(),
-            Profile::User(p) => p.get_key()
+            Profile::App(p) => &p.aws_role_arn,
+            Profile::User(p) => &p.aws_role_arn,
+            Profile::Plugin(_) | Profile::Custom(_) => ""
         }
     }
 
-    pub fn get_name(&self) -> &str {
+    // The workload identity pool provider and the service account to impersonate
+    pub fn get_gcp_federation(&self) -> (&str, &str) {
         match self {
-            Profile::App(p) => &p.name,
-            Profile::User(p) => &p.name
+            Profile::App(p) => (&p.gcp_audience, &p.gcp_service_account),
+            Profile::User(p) => (&p.gcp_audience, &p.gcp_service_account),
+            Profile::Plugin(_) | Profile::Custom(_) => ("", "")
         }
     }
 
     pub fn is_valid(&self) -> bool {
         match self {
             Profile::App(p) => p.is_valid(),
-            Profile::User(p) => p.is_valid()
+            Profile::User(p) => p.is_valid(),
+            Profile::Plugin(p) => p.is_valid(),
+            Profile::Custom(_) => true
         }
     }
 
-    pub fn refresh_token(&self, token: &Token) -> Option<Token> {
+    // Run `f` sending requests with the `Resolve` overrides of this profile, `f` gets the profile with the tenant
+    // discovered, so the discovery is only done when a request is sent
+    fn resolving<T>(&self, f: impl FnOnce(&Profile) -> T) -> T {
         match self {
-            Profile::App(_) => None,
-            Profile::User(p) => match token {
-                Token::User(t) => p.refresh_token(t).map(|t| Token::User(t)),
-                Token::App(_) => None
-            }
+            Profile::App(p) => with_resolve(&p.resolve, || f(&self.with_discovered_tenant())),
+            Profile::User(p) => with_resolve(&p.resolve, || f(&self.with_discovered_tenant())),
+            Profile::Plugin(_) | Profile::Custom(_) => f(self)
         }
     }
 
+    pub fn refresh_token(&self, token: &Token) -> Option<Token> {
+        self.resolving(|p| p.provider().refresh(token))
+    }
+
     pub fn get_token(&self) -> Token {
-        let mut cache = Profile::load_cache();
+        if let Profile::App(p) = self {
+            // Not cached, it'd be mixed up with the tokens of the app itself
+            if !p.assertion.is_empty() {
+                return Token::App(p.get_token());
+            }
+        }
 
-        match cache.get(&self.get_key()) {
-            Some(t) => {
-                if t.is_expired() {
-                    // Try to refresh this token
-                    match self.refresh_token(t) {
-                        Some(t) => {
-                            // Refreshed, save and return
-                            cache.insert(self.get_key(), t.clone());
-                            Profile::save_cache(cache);
-                            return t.clone();
-                        }
-                        // Failed to refresh
-                        None => ()
-                    }
-                } else {
-                    // Not expired
-                    return t.clone();
+        let key = self.get_cache_key();
+        match self.get_token_unattended(&key, token_cache::load(&key)) {
+            Some(t) => t,
+            None => self.sign_in(&key)
+        }
+    }
+
+    // Get the token without user interaction, the cached one if it's still valid, or refresh it, `App` tokens are
+    // acquired again, or the expired token within its extended lifetime if AAD is unreachable, i.e. during an outage.
+    // Failures are cached by `key`, None if a sign-in is needed
+    fn get_token_unattended(&self, key: &str, cached: Option<Token>) -> Option<Token> {
+        let mut stale = None;
+        if let Some(t) = cached {
+            // The cached token has been rejected if there is a claims challenge
+            if !t.is_expired() && !self.has_claims() {
+                return Some(t);
+            }
+            stale = Some(t.clone()).filter(|t| !self.has_claims() && t.is_within_ext_lifetime());
+            if !check_offline() || !check_failure(key) {
+                return None;
+            }
+            // Try to refresh this token
+            match with_outage_fallback(stale.is_some(), || with_failure_key(key, || self.refresh_token(&t))) {
+                (Some(t), _) => {
+                    // Refreshed, save and return
+                    token_cache::save(self.token_entries(key, t.clone()));
+                    return Some(t);
                 }
+                (None, true) => return Some(serve_stale(stale.unwrap())),
+                // Failed to refresh
+                (None, false) if has_failed() => return None,
+                (None, false) => ()
             }
-            // Not found in cache
-            None => ()
         }
 
-        let token = match self {
-            Profile::App(p) => Token::App(p.get_token()),
-            Profile::User(p) => Token::User(p.get_token())
+        if !check_offline() || !check_failure(key) {
+            return None;
+        }
+        // The other profiles need user interaction, which is not retried with the stale token, the user may be
+        // signing in on purpose
+        if !matches!(self, Profile::App(_)) {
+            return None;
+        }
+        let (token, outage) = with_outage_fallback(stale.is_some(), || with_failure_key(key, || self.resolving(|p| p.provider().acquire())));
+        if outage {
+            return Some(serve_stale(stale.unwrap()));
+        }
+        if has_failed() {
+            return None;
+        }
+        token_cache::save(self.token_entries(key, token.clone()));
+        Some(token)
+    }
+
+    // Sign in interactively and cache the token
+    fn sign_in(&self, key: &str) -> Token {
+        check_offline();
+        // Only one interactive sign-in for the same profile at a time, the lock is held until the token is saved
+        let _lock = match self {
+            Profile::App(_) => None,
+            Profile::User(_) | Profile::Plugin(_) | Profile::Custom(_) => {
+                let (lock, waited) = lock_interactive_flow(key);
+                if waited {
+                    // Another process has just finished, use its token if it succeeded
+                    if let Some(t) = token_cache::load(key).filter(|t| !t.is_expired()) {
+                        return t;
+                    }
+                }
+                lock
+            }
         };
 
+        let token = with_failure_key(key, || self.resolving(|p| p.provider().acquire()));
+
+        if let (Profile::User(p), Token::User(t)) = (self, &token) {
+            // The user may have picked another account on the sign-in page
+            if let Some(username) = t.get_username().filter(|_| !p.account.is_empty() && !t.is_account(&p.account)) {
+                print_error!("Signed in as '{}' instead of '{}'.", username, p.account);
+                exit(2);
+            }
+        }
+
         // Save and return
-        cache.insert(self.get_key(), token.clone());
-        Profile::save_cache(cache);
+        token_cache::save(self.token_entries(key, token.clone()));
         token
     }
 
+    // Refresh the cached token even if it's still valid, i.e. to pick up newly granted roles,
+    // `App` tokens are acquired again, returns None if there is no cached token to refresh
+    pub fn force_refresh(&self) -> Option<Token> {
+        let key = self.get_cache_key();
+        let token = match self {
+            Profile::App(_) => self.resolving(|p| p.provider().acquire()),
+            _ => token_cache::load(&key).and_then(|t| self.refresh_token(&t))?
+        };
+        token_cache::save(self.token_entries(&key, token.clone()));
+        Some(token)
+    }
+
+    // Get the cached token if it's still valid, never touches the network
+    pub fn get_cached_token(&self) -> Option<Token> {
+        token_cache::load(&self.get_cache_key()).filter(|t| !t.is_expired())
+    }
+
+    // The last token response of the cached token, even if it has expired
+    pub fn get_last_response(&self) -> Option<Value> {
+        token_cache::load(&self.get_cache_key()).map(|t| match t {
+            Token::App(t) => t.raw_response,
+            Token::User(t) => t.raw_response,
+        }).filter(|r| !r.is_null())
+    }
+
+    // Remove the cached token, returns false if there is none
+    pub fn remove_cached_token(&self) -> bool {
+        let key = self.get_cache_key();
+        failure_cache::remove(&key);
+        token_cache::remove(&[key])
+    }
+
+    // All cached tokens as JSON, without the refresh tokens, or in CBOR if `binary` is set
+    pub fn export_cache(binary: bool) -> Vec<u8> {
+        if binary {
+            token_cache::export_binary()
+        } else {
+            serde_json::to_vec_pretty(&token_cache::export()).unwrap_or_default()
+        }
+    }
+
+    pub fn clear_cache() {
+        discovery::clear();
+        failure_cache::clear();
+        token_cache::clear();
+    }
+
+    // Revoke the refresh token of the cached token on the server, `User` profiles only, without a revocation endpoint
+    // all sign-in sessions of the user are revoked if `revoke_sessions` is set, failed requests are returned as errors
+    pub fn revoke_cached_token(&self, revoke_sessions: bool) -> Result<(), String> {
+        let p = match self {
+            Profile::User(p) => p,
+            _ => return Err(format!("'{}' doesn't have refresh tokens to revoke", self.get_name()))
+        };
+        match token_cache::load(&self.get_cache_key()) {
+            Some(Token::User(t)) => match with_soft_failures(|| self.resolving(|d| match d {
+                Profile::User(d) => d.revoke(&t, revoke_sessions),
+                _ => p.revoke(&t, revoke_sessions)
+            })) {
+                (_, Some(e)) => Err(e),
+                (ret, None) => ret
+            },
+            _ => Err(String::from("There is no cached token"))
+        }
+    }
+
+    // Start a device code sign-in without waiting for it, returns the handle for `poll_sign_in` and the codes
+    // to show to the user, `User` profiles only
+    pub fn start_sign_in(&self) -> Result<Value, String> {
+        let p = match self {
+            Profile::User(p) => p,
+            _ => return Err(format!("'{}' doesn't sign in with a device code, 'User' profiles only.", self.get_name()))
+        };
+        let dcresp = self.resolving(|d| match d {
+            Profile::User(d) => d.start_device_code(),
+            _ => p.start_device_code()
+        })?;
+        let handle = new_request_id();
+        device_flow::save(&handle, &device_flow::PendingSignIn {
+            key: self.get_cache_key(),
+            device_code: dcresp.device_code.clone(),
+            expires_on: Utc::now().timestamp() + dcresp.expires_in as i64,
+        })?;
+        Ok(json!({
+            "handle": handle,
+            "user_code": dcresp.user_code,
+            "verification_uri": dcresp.verification_uri,
+            "verification_uri_complete": dcresp.verification_uri_complete,
+            "expires_in": dcresp.expires_in,
+            "interval": dcresp.interval,
+            "message": dcresp.message,
+        }))
+    }
+
+    // Poll the sign-in started by `start_sign_in` once, the token is cached as soon as the user has signed in,
+    // returns None while the user is still signing in
+    pub fn poll_sign_in(&self, handle: &str) -> Result<Option<Token>, String> {
+        let p = match self {
+            Profile::User(p) => p,
+            _ => return Err(format!("'{}' doesn't sign in with a device code, 'User' profiles only.", self.get_name()))
+        };
+        let pending = device_flow::load(handle)?;
+        if pending.key != self.get_cache_key() {
+            return Err(String::from("The sign-in has been started with another profile."));
+        }
+        if pending.expires_on <= Utc::now().timestamp() {
+            device_flow::remove(handle);
+            return Err(String::from("The device code has expired, start the sign-in again."));
+        }
+        let polled = self.resolving(|d| match d {
+            Profile::User(d) => d.poll_device_code(&pending.device_code),
+            _ => p.poll_device_code(&pending.device_code)
+        });
+        match polled {
+            Ok(DeviceCodePoll::Pending(_)) => Ok(None),
+            Ok(DeviceCodePoll::Done(t)) => {
+                device_flow::remove(handle);
+                let token = Token::User(t);
+                token_cache::save(self.token_entries(&pending.key, token.clone()));
+                Ok(Some(token))
+            }
+            Err(e) => {
+                // Declined, or expired on the server
+                device_flow::remove(handle);
+                Err(e)
+            }
+        }
+    }
+
+    // Cache a token response acquired elsewhere, i.e. on a connected machine for an air-gapped one, the expiration is
+    // `expires_on` or the `exp` claim of the access token, `expires_in` doesn't tell when the token was issued
+    pub fn import_token(&self, response: &str) -> Result<Token, String> {
+        let resp: Value = serde_json::from_str(response)
+            .map_err(|e| format!("The token response isn't valid JSON, error is {}.", e))?;
+        if let Some(e) = resp["error"].as_str() {
+            return Err(format!("The token response is an error, '{}'.", e));
+        }
+        let field = |name: &str| resp[name].as_str().unwrap_or_default().to_owned();
+        let access_token = field("access_token");
+        if access_token.is_empty() {
+            return Err(String::from("The token response has no access token."));
+        }
+        let expires_on = resp["expires_on"].as_i64()
+            .or_else(|| resp["expires_on"].as_str().and_then(|s| s.parse().ok()))
+            .or_else(|| decode_claims(&access_token).and_then(|c| c["exp"].as_i64()))
+            .ok_or("The expiration of the token is unknown, there is no 'expires_on' in the response or 'exp' claim in the access token.")?;
+        if is_expired(expires_on) {
+            return Err(format!("The token has expired or expires in less than {} second(s).", min_remaining()));
+        }
+        let token = match self {
+            Profile::App(_) => {
+                let mut t = AppToken::new(&access_token, expires_on);
+                t.raw_response = raw_response(response);
+                Token::App(t)
+            }
+            Profile::User(_) | Profile::Plugin(_) | Profile::Custom(_) => Token::User(UserToken {
+                scope: field("scope"),
+                id_token: field("id_token"),
+                access_token,
+                refresh_token: field("refresh_token"),
+                client_info: field("client_info"),
+                expires_on,
+                raw_response: raw_response(response),
+                ..Default::default()
+            })
+        };
+        // A token for another resource or account would be served in place of the right one
+        if let Some(e) = self.check_audience(&token) {
+            return Err(e);
+        }
+        if let (Profile::User(p), Token::User(t)) = (self, &token) {
+            if let Some(username) = t.get_username().filter(|_| !p.account.is_empty() && !t.is_account(&p.account)) {
+                return Err(format!("The token is for '{}' instead of '{}'.", username, p.account));
+            }
+        }
+        token_cache::save(self.token_entries(&self.get_cache_key(), token.clone()));
+        Ok(token)
+    }
+
+    pub fn sign_out(&self) {
+        match self {
+            Profile::App(_) | Profile::Plugin(_) | Profile::Custom(_) => print_warning!("'{}' doesn't have sign-in sessions.", self.get_name()),
+            Profile::User(_) => self.resolving(|p| if let Profile::User(p) = p { p.sign_out() })
+        }
+    }
+
+    // Get a new token without any user interaction, App tokens are always acquired silently,
+    // User tokens can only be refreshed from a cached one
+    fn get_token_silently(&self, cached: Option<&Token>) -> Option<Token> {
+        match self {
+            Profile::App(_) => Some(self.resolving(|p| p.provider().acquire())),
+            Profile::User(_) | Profile::Plugin(_) | Profile::Custom(_) => cached.and_then(|t| self.refresh_token(t)),
+        }
+    }
+
+    // Make sure the cached token stays valid for at least `margin` seconds without any user interaction
+    pub fn renew_token(&self, margin: i64) -> Renewal {
+        let key = self.get_cache_key();
+        let token = match token_cache::load(&key) {
+            Some(t) if t.get_expires_on() - Utc::now().timestamp() > margin => {
+                // Still fresh enough
+                return Renewal::Fresh;
+            }
+            cached => match with_soft_failures(|| if check_offline() { self.get_token_silently(cached.as_ref()) } else { None }) {
+                (_, Some(e)) => return Renewal::Failed(e),
+                (t, None) => t
+            }
+        };
+
+        match token {
+            Some(t) => {
+                token_cache::save(self.token_entries(&key, t.clone()));
+                Renewal::Renewed(Box::new(t))
+            }
+            None => Renewal::NeedsSignIn
+        }
+    }
+
+    // Get tokens for multiple profiles, requests not needing user interaction are sent concurrently
+    // with at most `parallelism` connections, interactive sign-ins still happen one by one
+    pub fn get_tokens(profiles: &[Profile], parallelism: usize) -> Vec<Token> {
+        // Finding the key of an account reads the whole cache, so it's only done once for each profile
+        let keys: Vec<String> = profiles.iter().map(|p| p.get_cache_key()).collect();
+        let cached: Vec<Option<Token>> = keys.iter().map(|k| token_cache::load(k)).collect();
+        let mut tokens: Vec<Option<Token>> = profiles.iter().zip(cached.iter())
+            .map(|(p, t)| t.clone().filter(|t| !t.is_expired() && !p.has_claims()))
+            .collect();
+
+        let jobs: VecDeque<(usize, Profile, String, Option<Token>)> = profiles.iter().zip(keys.iter()).zip(cached).enumerate()
+            .filter(|(i, _)| tokens[*i].is_none())
+            .map(|(i, ((p, k), t))| (i, p.clone(), k.clone(), t))
+            .collect();
+        let workers = parallelism.max(1).min(jobs.len());
+        let jobs = Arc::new(Mutex::new(jobs));
+        let (tx, rx) = channel();
+        let handles: Vec<_> = (0..workers).map(|_| {
+            let jobs = jobs.clone();
+            let tx = tx.clone();
+            thread::spawn(move || loop {
+                let job = jobs.lock().unwrap().pop_front();
+                match job {
+                    // Failed requests are reported by the main thread
+                    Some((i, p, key, cached)) => tx.send((i, with_soft_failures(|| p.get_token_unattended(&key, cached)))).unwrap(),
+                    None => break
+                }
+            })
+        }).collect();
+        drop(tx);
+        let mut failed = false;
+        for (i, (t, error)) in rx {
+            match error {
+                Some(e) => {
+                    print_error!("Failed to get the token of profile '{}', {}", profiles[i].get_name(), e);
+                    failed = true;
+                }
+                None => tokens[i] = t
+            }
+        }
+        for h in handles {
+            h.join().unwrap();
+        }
+        if failed {
+            exit(1);
+        }
+
+        profiles.iter().zip(keys.iter()).zip(tokens).map(|((p, k), t)| match t {
+            Some(t) => t,
+            // Needs user interaction
+            None => p.sign_in(k)
+        }).collect()
+    }
+
     // Override this profile
-    pub fn with_overrides(
-        &self,
-        client_id: &str,
-        secret: &str,
-        tenant: &str,
-        authority: &str,
-        resource: &str,
-        scope: &str,
-    ) -> Profile {
+    pub fn with_overrides(&self, o: &ProfileOverrides) -> Profile {
+        fn pick(value: &str, current: &str) -> String {
+            if value.is_empty() { current.to_owned() } else { value.to_owned() }
+        }
+        fn pick_list(value: &[String], current: &[String]) -> Vec<String> {
+            if value.is_empty() { current.to_vec() } else { value.to_vec() }
+        }
+        fn merge_map(value: &HashMap<String, String>, current: &HashMap<String, String>) -> HashMap<String, String> {
+            current.iter().chain(value.iter()).map(|(k, v)| (k.to_owned(), v.to_owned())).collect()
+        }
         match self {
             Profile::App(p) => {
                 Profile::App(AppProfile {
                     name: p.name.to_owned(),
-                    client_id: if client_id.is_empty() { p.client_id.to_owned() } else { client_id.to_owned() },
-                    secret: if secret.is_empty() { p.secret.to_owned() } else { secret.to_owned() },
-                    tenant: if tenant.is_empty() { p.tenant.to_owned() } else { tenant.to_owned() },
-                    authority: if authority.is_empty() { p.authority.to_owned() } else { authority.to_owned() },
-                    resource: if resource.is_empty() { p.resource.to_owned() } else { resource.to_owned() },
+                    client_id: pick(&o.client_id, &p.client_id),
+                    secret: pick(&o.secret, &p.secret),
+                    tenant: pick(&o.tenant, &p.tenant),
+                    authority: pick(&o.authority, &p.authority),
+                    resource: pick(&o.resource, &p.resource),
+                    azure_region: pick(&o.azure_region, &p.azure_region),
+                    client_capabilities: pick_list(&o.client_capabilities, &p.client_capabilities),
+                    token_endpoint_auth_method: pick(&o.token_endpoint_auth_method, &p.token_endpoint_auth_method),
+                    federated_credential: pick(&o.federated_credential, &p.federated_credential),
+                    resolve: merge_map(&o.resolve, &p.resolve),
+                    aws_role_arn: pick(&o.aws_role_arn, &p.aws_role_arn),
+                    gcp_audience: pick(&o.gcp_audience, &p.gcp_audience),
+                    gcp_service_account: pick(&o.gcp_service_account, &p.gcp_service_account),
+                    ..p.clone()
                 })
             }
             Profile::User(p) => {
                 Profile::User(UserProfile {
                     name: p.name.to_owned(),
-                    client_id: if client_id.is_empty() { p.client_id.to_owned() } else { client_id.to_owned() },
-                    tenant: if tenant.is_empty() { p.tenant.to_owned() } else { tenant.to_owned() },
-                    authority: if authority.is_empty() { p.authority.to_owned() } else { authority.to_owned() },
-                    scope: if scope.is_empty() { p.scope.to_owned() } else { scope.to_owned() },
+                    client_id: pick(&o.client_id, &p.client_id),
+                    secret: pick(&o.secret, &p.secret),
+                    tenant: pick(&o.tenant, &p.tenant),
+                    authority: pick(&o.authority, &p.authority),
+                    scope: pick(&o.scope, &p.scope),
+                    login_hint: pick(&o.login_hint, &p.login_hint),
+                    domain_hint: pick(&o.domain_hint, &p.domain_hint),
+                    prompt: pick(&o.prompt, &p.prompt),
+                    account: pick(&o.account, &p.account),
+                    client_capabilities: pick_list(&o.client_capabilities, &p.client_capabilities),
+                    auth_timeout: if o.auth_timeout > 0 { o.auth_timeout } else { p.auth_timeout },
+                    poll_interval: if o.poll_interval > 0 { o.poll_interval } else { p.poll_interval },
+                    shared_cache: o.shared_cache || p.shared_cache,
+                    integrated_windows_auth: o.integrated_windows_auth || p.integrated_windows_auth,
+                    vs_code: o.vs_code || p.vs_code,
+                    browser: pick(&o.browser, &p.browser),
+                    resolve: merge_map(&o.resolve, &p.resolve),
+                    aws_role_arn: pick(&o.aws_role_arn, &p.aws_role_arn),
+                    gcp_audience: pick(&o.gcp_audience, &p.gcp_audience),
+                    gcp_service_account: pick(&o.gcp_service_account, &p.gcp_service_account),
+                    ..p.clone()
                 })
             }
+            Profile::Plugin(_) | Profile::Custom(_) => self.clone()
+        }
+    }
+
+    // Request a token satisfying the claims challenge, i.e. from Continuous Access Evaluation
+    pub fn with_claims(&self, claims: &str) -> Profile {
+        match self {
+            Profile::App(p) => Profile::App(AppProfile { claims: claims.to_owned(), ..p.clone() }),
+            Profile::User(p) => Profile::User(UserProfile { claims: claims.to_owned(), ..p.clone() }),
+            Profile::Plugin(_) | Profile::Custom(_) => self.clone()
+        }
+    }
+
+    // Add scopes to the requested and the granted scopes of the cached token, the refresh token of the cached token
+    // is tried first so the interactive sign-in is only needed for consent, `User` profiles only
+    pub fn with_additional_scope(&self, scope: &str) -> Profile {
+        let p = match self {
+            Profile::User(p) => p,
+            _ => return self.clone()
+        };
+        let cached = match token_cache::load(&self.get_cache_key()) {
+            Some(Token::User(t)) => Some(t),
+            _ => None
+        };
+        let mut scopes: Vec<&str> = p.scope.split_whitespace().collect();
+        let granted = cached.as_ref().map(|t| t.scope.as_str()).unwrap_or_default();
+        for s in granted.split_whitespace().chain(scope.split_whitespace()) {
+            if !scopes.iter().any(|e| e.eq_ignore_ascii_case(s)) {
+                scopes.push(s);
+            }
+        }
+        Profile::User(UserProfile {
+            scope: scopes.join(" "),
+            seed_refresh_token: cached.as_ref().map(|t| t.refresh_token.clone()).unwrap_or_default(),
+            ..p.clone()
+        })
+    }
+
+    // Request Proof-of-Possession tokens, `User` profiles only
+    pub fn with_pop(&self) -> Profile {
+        match self {
+            Profile::User(p) => Profile::User(UserProfile { pop: true, ..p.clone() }),
+            _ => self.clone()
+        }
+    }
+
+    // Exchange the user assertion for a token on behalf of the user, `App` profiles only
+    pub fn with_assertion(&self, assertion: &str, assertion_type: &str) -> Profile {
+        match self {
+            Profile::App(p) => Profile::App(AppProfile {
+                assertion: assertion.to_owned(),
+                assertion_type: assertion_type.to_owned(),
+                ..p.clone()
+            }),
+            _ => {
+                print_error!("Assertions can only be used with 'App' profiles.");
+                exit(1);
+            }
+        }
+    }
+
+    fn has_claims(&self) -> bool {
+        match self {
+            Profile::App(p) => !p.claims.is_empty(),
+            Profile::User(p) => !p.claims.is_empty(),
+            Profile::Plugin(_) | Profile::Custom(_) => false
+        }
+    }
+
+    // Use the default authority if the authority is not set, External ID tenants are recognized by their host names,
+    // never touches the network, the tenant is discovered when a request is sent
+    pub fn with_default_authority(&self) -> Profile {
+        let (authority, tenant) = match self {
+            Profile::App(p) => (&p.authority, &p.tenant),
+            Profile::User(p) => (&p.authority, &p.tenant),
+            Profile::Plugin(_) | Profile::Custom(_) => return self.clone()
+        };
+        // External ID tenants can also be given by the host name only, i.e. "contoso.ciamlogin.com"
+        let ciam = split_ciam_authority(&format!("https://{}", tenant), "")
+            .or_else(|| split_ciam_authority(authority, tenant));
+        let info = match ciam {
+            Some(info) => info,
+            None if !authority.is_empty() => return self.clone(),
+            None => TenantInfo {
+                authority: DEFAULT_AUTHORITY.to_owned(),
+                tenant_id: tenant.to_owned(),
+            }
+        };
+        match self {
+            Profile::App(p) => Profile::App(AppProfile { authority: info.authority, tenant: info.tenant_id, ..p.clone() }),
+            Profile::User(p) => Profile::User(UserProfile { authority: info.authority, tenant: info.tenant_id, ..p.clone() }),
+            Profile::Plugin(_) | Profile::Custom(_) => self.clone()
+        }
+    }
+
+    // Resolve the authority of the cloud and the tenant id from the tenant domain name at the default authority,
+    // the cache key is still made of the tenant name, so the cached tokens can be found without the discovery
+    fn with_discovered_tenant(&self) -> Profile {
+        let (authority, tenant) = match self {
+            Profile::App(p) => (&p.authority, &p.tenant),
+            Profile::User(p) => (&p.authority, &p.tenant),
+            Profile::Plugin(_) | Profile::Custom(_) => return self.clone()
+        };
+        if authority.trim_end_matches('/') != DEFAULT_AUTHORITY || tenant.is_empty() || is_guid(tenant) || is_tenant_keyword(tenant) {
+            return self.clone();
+        }
+        let info = match discover_tenant(tenant) {
+            Some(info) => info,
+            // Try the global Azure cloud
+            None => return self.clone()
+        };
+        match self {
+            Profile::App(p) => Profile::App(AppProfile { authority: info.authority, tenant: info.tenant_id, ..p.clone() }),
+            Profile::User(p) => Profile::User(UserProfile { authority: info.authority, tenant: info.tenant_id, ..p.clone() }),
+            Profile::Plugin(_) | Profile::Custom(_) => self.clone()
         }
     }
 
@@ -268,53 +1388,75 @@ impl Profile {
                     secret: if p.secret.is_empty() { secret.to_owned() } else { p.secret.to_owned() },
                     tenant: if p.tenant.is_empty() { tenant.to_owned() } else { p.tenant.to_owned() },
                     authority: if p.authority.is_empty() { authority.to_owned() } else { p.authority.to_owned() },
-                    resource: p.resource.to_owned(),
+                    ..p.clone()
                 })
             }
             Profile::User(p) => {
+                // The refresh token of VS Code only works with its client id
+                let client_id = if p.vs_code { vscode::CLIENT_ID } else { client_id };
                 Profile::User(UserProfile {
                     name: p.name.to_owned(),
                     client_id: if p.client_id.is_empty() { client_id.to_owned() } else { p.client_id.to_owned() },
                     tenant: if p.tenant.is_empty() { tenant.to_owned() } else { p.tenant.to_owned() },
                     authority: if p.authority.is_empty() { authority.to_owned() } else { p.authority.to_owned() },
                     scope: if p.scope.is_empty() { scope.to_owned() } else { p.scope.to_owned() },
+                    ..p.clone()
                 })
             }
+            Profile::Plugin(_) | Profile::Custom(_) => self.clone()
         }
     }
 
 
-    pub fn create(
-        profile_type: &str,
-        client_id: &str,
-        secret: &str,
-        tenant: &str,
-        authority: &str,
-        resource: &str,
-        scope: &str,
-    ) -> Profile {
+    pub fn create(profile_type: &str, o: &ProfileOverrides) -> Profile {
         match profile_type {
             "App" => {
                 Profile::App(AppProfile {
                     name: String::from(""),
-                    client_id: client_id.to_string(),
-                    secret: secret.to_string(),
-                    tenant: tenant.to_string(),
-                    authority: authority.to_string(),
-                    resource: resource.to_string(),
+                    client_id: o.client_id.to_owned(),
+                    secret: o.secret.to_owned(),
+                    tenant: o.tenant.to_owned(),
+                    authority: o.authority.to_owned(),
+                    resource: o.resource.to_owned(),
+                    azure_region: o.azure_region.to_owned(),
+                    client_capabilities: o.client_capabilities.to_owned(),
+                    token_endpoint_auth_method: o.token_endpoint_auth_method.to_owned(),
+                    federated_credential: o.federated_credential.to_owned(),
+                    resolve: o.resolve.to_owned(),
+                    aws_role_arn: o.aws_role_arn.to_owned(),
+                    gcp_audience: o.gcp_audience.to_owned(),
+                    gcp_service_account: o.gcp_service_account.to_owned(),
+                    ..Default::default()
                 })
             }
             "User" => {
                 Profile::User(UserProfile {
                     name: String::from(""),
-                    client_id: client_id.to_string(),
-                    tenant: tenant.to_string(),
-                    authority: authority.to_string(),
-                    scope: scope.to_string(),
+                    client_id: o.client_id.to_owned(),
+                    secret: o.secret.to_owned(),
+                    tenant: o.tenant.to_owned(),
+                    authority: o.authority.to_owned(),
+                    scope: o.scope.to_owned(),
+                    login_hint: o.login_hint.to_owned(),
+                    domain_hint: o.domain_hint.to_owned(),
+                    prompt: o.prompt.to_owned(),
+                    account: o.account.to_owned(),
+                    client_capabilities: o.client_capabilities.to_owned(),
+                    auth_timeout: o.auth_timeout,
+                    poll_interval: o.poll_interval,
+                    shared_cache: o.shared_cache,
+                    integrated_windows_auth: o.integrated_windows_auth,
+                    vs_code: o.vs_code,
+                    browser: o.browser.to_owned(),
+                    resolve: o.resolve.to_owned(),
+                    aws_role_arn: o.aws_role_arn.to_owned(),
+                    gcp_audience: o.gcp_audience.to_owned(),
+                    gcp_service_account: o.gcp_service_account.to_owned(),
+                    ..Default::default()
                 })
             }
             _ => {
-                eprintln!("ERROR: Unknown profile type '{}'.", profile_type);
+                print_error!("Unknown profile type '{}'.", profile_type);
                 exit(3)
             }
         }
@@ -0,0 +1,49 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Desktop notifications when an interactive sign-in ends, the terminal waiting for it is often in the background,
+// sent with the tools of the OS, so nothing happens if they are missing
+static NOTIFY: AtomicBool = AtomicBool::new(false);
+
+pub fn set_notify(enabled: bool) {
+    NOTIFY.store(enabled, Ordering::Relaxed);
+}
+
+#[cfg(feature = "nogui")]
+pub fn notify(_title: &str, _body: &str) {
+}
+
+#[cfg(not(feature = "nogui"))]
+pub fn notify(title: &str, body: &str) {
+    use std::process::{Command, Stdio};
+
+    if !NOTIFY.load(Ordering::Relaxed) {
+        return;
+    }
+    let mut command = if cfg!(target_os = "windows") {
+        // A toast of PowerShell, other apps need to be registered to send toasts
+        let quote = |s: &str| format!("'{}'", s.replace('\'', "''"));
+        let script = format!(concat!(
+            "[Windows.UI.Notifications.ToastNotificationManager, Windows.UI.Notifications, ContentType = WindowsRuntime] > $null; ",
+            "$t = [Windows.UI.Notifications.ToastNotificationManager]::GetTemplateContent([Windows.UI.Notifications.ToastTemplateType]::ToastText02); ",
+            "$e = $t.GetElementsByTagName('text'); ",
+            "$e.Item(0).AppendChild($t.CreateTextNode({})) > $null; ",
+            "$e.Item(1).AppendChild($t.CreateTextNode({})) > $null; ",
+            "[Windows.UI.Notifications.ToastNotificationManager]::CreateToastNotifier(",
+            "'{{1AC14E77-02E7-4E5D-B744-2EB1AE5198B7}}\\WindowsPowerShell\\v1.0\\powershell.exe').Show(",
+            "[Windows.UI.Notifications.ToastNotification]::new($t))"), quote(title), quote(body));
+        let mut c = Command::new("powershell");
+        c.args(["-NoProfile", "-NonInteractive", "-Command", &script]);
+        c
+    } else if cfg!(target_os = "macos") {
+        // The texts are passed as arguments, so they don't need to be escaped
+        let mut c = Command::new("osascript");
+        c.args(["-e", "on run argv", "-e", "display notification (item 2 of argv) with title (item 1 of argv)", "-e", "end run", title, body]);
+        c
+    } else {
+        let mut c = Command::new("notify-send");
+        c.args(["--app-name=tokengen", title, body]);
+        c
+    };
+    // Not waited for, PowerShell takes a while to start
+    command.stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::null()).spawn().ok();
+}
@@ -0,0 +1,204 @@
+// Client assertions signed by a key on a smart card or HSM through its PKCS#11 module, i.e. YubiKey's `libykcs11`,
+// the key and the certificate are found by their `CKA_ID` and the private key never leaves the device
+#[cfg(feature = "pkcs11")]
+mod ffi {
+    use std::os::raw::{c_ulong, c_void};
+
+    pub type CkUlong = c_ulong;
+    pub type CkRv = CkUlong;
+    type Unused = *const c_void;
+
+    pub const CKR_OK: CkRv = 0;
+    pub const CKR_USER_ALREADY_LOGGED_IN: CkRv = 0x100;
+    pub const CKR_CRYPTOKI_ALREADY_INITIALIZED: CkRv = 0x191;
+    pub const CKF_SERIAL_SESSION: CkUlong = 0x4;
+    pub const CKU_USER: CkUlong = 1;
+    pub const CKA_CLASS: CkUlong = 0x0;
+    pub const CKA_VALUE: CkUlong = 0x11;
+    pub const CKA_ID: CkUlong = 0x102;
+    pub const CKO_CERTIFICATE: CkUlong = 1;
+    pub const CKO_PRIVATE_KEY: CkUlong = 3;
+    pub const CKM_SHA256_RSA_PKCS: CkUlong = 0x40;
+
+    // The structures are packed on Windows, https://docs.oasis-open.org/pkcs11/pkcs11-base/v2.40/os/pkcs11-base-v2.40-os.html#_Toc416959670
+    #[cfg_attr(target_os = "windows", repr(C, packed))]
+    #[cfg_attr(not(target_os = "windows"), repr(C))]
+    pub struct CkAttribute {
+        pub attr_type: CkUlong,
+        pub value: *mut c_void,
+        pub value_len: CkUlong,
+    }
+
+    #[cfg_attr(target_os = "windows", repr(C, packed))]
+    #[cfg_attr(not(target_os = "windows"), repr(C))]
+    pub struct CkMechanism {
+        pub mechanism: CkUlong,
+        pub parameter: *mut c_void,
+        pub parameter_len: CkUlong,
+    }
+
+    // Only the functions up to `C_Sign` are declared, the rest of the list is never accessed
+    #[cfg_attr(target_os = "windows", repr(C, packed))]
+    #[cfg_attr(not(target_os = "windows"), repr(C))]
+    pub struct FunctionList {
+        pub version: [u8; 2],
+        pub initialize: unsafe extern "C" fn(*mut c_void) -> CkRv,
+        pub finalize: unsafe extern "C" fn(*mut c_void) -> CkRv,
+        _get_info: Unused,
+        _get_function_list: Unused,
+        pub get_slot_list: unsafe extern "C" fn(u8, *mut CkUlong, *mut CkUlong) -> CkRv,
+        _slot_and_token_management: [Unused; 7],
+        pub open_session: unsafe extern "C" fn(CkUlong, CkUlong, *mut c_void, Unused, *mut CkUlong) -> CkRv,
+        pub close_session: unsafe extern "C" fn(CkUlong) -> CkRv,
+        _session_management: [Unused; 4],
+        pub login: unsafe extern "C" fn(CkUlong, CkUlong, *const u8, CkUlong) -> CkRv,
+        pub logout: unsafe extern "C" fn(CkUlong) -> CkRv,
+        _object_management: [Unused; 4],
+        pub get_attribute_value: unsafe extern "C" fn(CkUlong, CkUlong, *mut CkAttribute, CkUlong) -> CkRv,
+        _set_attribute_value: Unused,
+        pub find_objects_init: unsafe extern "C" fn(CkUlong, *mut CkAttribute, CkUlong) -> CkRv,
+        pub find_objects: unsafe extern "C" fn(CkUlong, *mut CkUlong, CkUlong, *mut CkUlong) -> CkRv,
+        pub find_objects_final: unsafe extern "C" fn(CkUlong) -> CkRv,
+        _encryption_and_digest: [Unused; 13],
+        pub sign_init: unsafe extern "C" fn(CkUlong, *mut CkMechanism, CkUlong) -> CkRv,
+        pub sign: unsafe extern "C" fn(CkUlong, *const u8, CkUlong, *mut u8, *mut CkUlong) -> CkRv,
+    }
+}
+
+#[cfg(feature = "pkcs11")]
+use ffi::*;
+
+#[cfg(feature = "pkcs11")]
+fn check(rv: CkRv, function: &str) -> Result<(), String> {
+    if rv == CKR_OK {
+        Ok(())
+    } else {
+        Err(format!("PKCS#11 function '{}' failed, error is {:#x}", function, rv))
+    }
+}
+
+// Key ids are hex, i.e. "01" or "01:02:03"
+#[cfg(feature = "pkcs11")]
+fn parse_key_id(key_id: &str) -> Option<Vec<u8>> {
+    let hex: String = key_id.chars().filter(|c| *c != ':' && !c.is_whitespace()).collect();
+    if hex.is_empty() || !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()).collect()
+}
+
+// Read the PIN from `TOKENGEN_PKCS11_PIN`, or prompt for it on the terminal
+#[cfg(feature = "pkcs11")]
+fn read_pin(slot: CkUlong) -> Result<String, String> {
+    let pin = match std::env::var("TOKENGEN_PKCS11_PIN") {
+        Ok(pin) => pin,
+        Err(_) => rpassword::read_password_from_tty(Some(&format!("PIN of the token in slot {}: ", slot)))
+            .map_err(|e| format!("Unable to read the PIN, error is {:#?}", e))?
+    };
+    crate::redact::register_secret(&pin);
+    Ok(pin)
+}
+
+#[cfg(feature = "pkcs11")]
+unsafe fn find_object(f: &FunctionList, session: CkUlong, class: CkUlong, id: &[u8]) -> Result<Option<CkUlong>, String> {
+    use std::mem::size_of;
+
+    let mut class = class;
+    let mut template = [
+        CkAttribute { attr_type: CKA_CLASS, value: &mut class as *mut _ as _, value_len: size_of::<CkUlong>() as CkUlong },
+        CkAttribute { attr_type: CKA_ID, value: id.as_ptr() as _, value_len: id.len() as CkUlong },
+    ];
+    check((f.find_objects_init)(session, template.as_mut_ptr(), template.len() as CkUlong), "C_FindObjectsInit")?;
+    let mut object = 0;
+    let mut count = 0;
+    let rv = (f.find_objects)(session, &mut object, 1, &mut count);
+    (f.find_objects_final)(session);
+    check(rv, "C_FindObjects")?;
+    Ok(if count > 0 { Some(object) } else { None })
+}
+
+#[cfg(feature = "pkcs11")]
+unsafe fn sign_in_session(f: &FunctionList, session: CkUlong, id: &[u8], client_id: &str, audience: &str) -> Result<String, String> {
+    use std::ptr::null_mut;
+    use sha1::{Digest, Sha1};
+    use crate::profile::client_assertion::{finish, signing_input};
+
+    // The thumbprint of the certificate stored with the key
+    let cert = find_object(f, session, CKO_CERTIFICATE, id)?
+        .ok_or_else(|| String::from("The certificate of the key is not found on the token"))?;
+    let mut value = CkAttribute { attr_type: CKA_VALUE, value: null_mut(), value_len: 0 };
+    check((f.get_attribute_value)(session, cert, &mut value, 1), "C_GetAttributeValue")?;
+    let mut der = vec![0u8; value.value_len as usize];
+    value.value = der.as_mut_ptr() as _;
+    check((f.get_attribute_value)(session, cert, &mut value, 1), "C_GetAttributeValue")?;
+    der.truncate(value.value_len as usize);
+    let thumbprint = Sha1::digest(&der);
+
+    let key = find_object(f, session, CKO_PRIVATE_KEY, id)?
+        .ok_or_else(|| String::from("The private key is not found on the token"))?;
+    let input = signing_input(client_id, audience, &thumbprint);
+    let mut mechanism = CkMechanism { mechanism: CKM_SHA256_RSA_PKCS, parameter: null_mut(), parameter_len: 0 };
+    check((f.sign_init)(session, &mut mechanism, key), "C_SignInit")?;
+    let mut signature = vec![0u8; 1024];
+    let mut size = signature.len() as CkUlong;
+    check((f.sign)(session, input.as_ptr(), input.len() as CkUlong, signature.as_mut_ptr(), &mut size), "C_Sign")?;
+    signature.truncate(size as usize);
+    Ok(finish(&input, &signature))
+}
+
+// Build a client assertion signed by the key with id `key_id` in `slot`, or in the first slot with a token
+#[cfg(feature = "pkcs11")]
+pub fn client_assertion(module: &str, slot: Option<u64>, key_id: &str, client_id: &str, audience: &str) -> Result<String, String> {
+    use std::ptr::{null, null_mut};
+    use libloading::{Library, Symbol};
+
+    let id = parse_key_id(key_id).ok_or_else(|| format!("Invalid PKCS#11 key id '{}', it should be in hex", key_id))?;
+    unsafe {
+        let library = Library::new(module)
+            .map_err(|e| format!("Unable to load PKCS#11 module '{}', error is {}", module, e))?;
+        let get_function_list: Symbol<unsafe extern "C" fn(*mut *const FunctionList) -> CkRv> = library.get(b"C_GetFunctionList\0")
+            .map_err(|e| format!("'{}' is not a PKCS#11 module, error is {}", module, e))?;
+        let mut list = null();
+        check(get_function_list(&mut list), "C_GetFunctionList")?;
+        let f = &*list;
+        let rv = (f.initialize)(null_mut());
+        if rv != CKR_CRYPTOKI_ALREADY_INITIALIZED {
+            check(rv, "C_Initialize")?;
+        }
+
+        let result = (|| {
+            let slot = match slot {
+                Some(s) => s as CkUlong,
+                None => {
+                    let mut slots = [0; 16];
+                    let mut count = slots.len() as CkUlong;
+                    check((f.get_slot_list)(1, slots.as_mut_ptr(), &mut count), "C_GetSlotList")?;
+                    if count == 0 {
+                        return Err(String::from("No PKCS#11 token is present"));
+                    }
+                    slots[0]
+                }
+            };
+            let mut session = 0;
+            check((f.open_session)(slot, CKF_SERIAL_SESSION, null_mut(), null(), &mut session), "C_OpenSession")?;
+            let pin = read_pin(slot)?;
+            let rv = (f.login)(session, CKU_USER, pin.as_ptr(), pin.len() as CkUlong);
+            let result = if rv == CKR_OK || rv == CKR_USER_ALREADY_LOGGED_IN {
+                let result = sign_in_session(f, session, &id, client_id, audience);
+                (f.logout)(session);
+                result
+            } else {
+                Err(format!("PKCS#11 function 'C_Login' failed, error is {:#x}, the PIN may be wrong", rv))
+            };
+            (f.close_session)(session);
+            result
+        })();
+        (f.finalize)(null_mut());
+        result
+    }
+}
+
+#[cfg(not(feature = "pkcs11"))]
+pub fn client_assertion(_module: &str, _slot: Option<u64>, _key_id: &str, _client_id: &str, _audience: &str) -> Result<String, String> {
+    Err(String::from("PKCS#11 is not supported, tokengen is built without the 'pkcs11' feature"))
+}
@@ -0,0 +1,105 @@
+use std::collections::HashMap;
+use std::io::{ErrorKind, Write};
+use std::process::{Command, exit, Stdio};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::profile::{expires_on_from, raw_response};
+use crate::profile::user_profile::UserToken;
+
+// Token sources shipped as external executables named `tokengen-provider-<name>` on PATH.
+// The request is a JSON object written to the stdin of the plugin:
+//     {"action": "acquire" or "refresh", "profile": "<profile name>", "settings": {...}, "refresh_token": "..."}
+// and the response is a JSON object read from its stdout, with the same fields as an OAuth token response:
+//     {"access_token": "...", "id_token": "...", "refresh_token": "...", "expires_in": 3600} or {"error": "..."}
+#[derive(Clone, PartialEq, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct PluginProfile {
+    pub name: String,
+    #[serde(default)]
+    pub provider: String,
+    // Passed to the plugin as is
+    #[serde(default)]
+    pub settings: Value,
+    // Keep the tokens apart from other profiles with the same parameters, the profile name is in the cache key
+    #[serde(default)]
+    pub isolated_cache: bool,
+    // Fields not known to `Plugin` profiles, reported by `profile validate`
+    #[serde(flatten)]
+    pub unknown_fields: HashMap<String, Value>,
+}
+
+impl PluginProfile {
+    fn get_program(&self) -> String {
+        format!("tokengen-provider-{}", self.provider)
+    }
+
+    // Run the plugin, its stderr is passed through so it can show sign-in instructions
+    fn call(&self, request: &Value) -> Result<UserToken, String> {
+        let program = self.get_program();
+        let mut child = Command::new(&program)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| match e.kind() {
+                ErrorKind::NotFound => format!("Provider plugin '{}' is not found on PATH", program),
+                _ => format!("Unable to run provider plugin '{}', error is {:#?}", program, e)
+            })?;
+        // Close stdin before waiting, the plugin may read it to the end
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(request.to_string().as_bytes())
+                .map_err(|e| format!("Unable to send the request to provider plugin '{}', error is {:#?}", program, e))?;
+        }
+        let output = child.wait_with_output()
+            .map_err(|e| format!("Provider plugin '{}' failed, error is {:#?}", program, e))?;
+        if !output.status.success() {
+            return Err(format!("Provider plugin '{}' failed, status is {}", program, output.status));
+        }
+        let mut token: UserToken = serde_json::from_slice(&output.stdout)
+            .map_err(|e| format!("Failed to decode the response of provider plugin '{}', error is {:#?}", program, e))?;
+        if !token.error.is_empty() {
+            return Err(format!("Provider plugin '{}' failed, error is {}", program, token.error));
+        }
+        if token.expires_on == 0 {
+            token.expires_on = expires_on_from(token.expires_in);
+        }
+        token.raw_response = raw_response(&String::from_utf8_lossy(&output.stdout));
+        Ok(token)
+    }
+
+    pub fn get_token(&self) -> UserToken {
+        let request = json!({
+            "action": "acquire",
+            "profile": self.name,
+            "settings": self.settings,
+        });
+        self.call(&request).map_err(|e| {
+            print_error!("{}.", e);
+            exit(2);
+        }).unwrap()
+    }
+
+    pub fn refresh_token(&self, token: &UserToken) -> Option<UserToken> {
+        if token.refresh_token.is_empty() {
+            return None;
+        }
+        let request = json!({
+            "action": "refresh",
+            "profile": self.name,
+            "settings": self.settings,
+            "refresh_token": token.refresh_token,
+        });
+        // Acquire a new token instead of failing
+        self.call(&request).map_err(|e| print_warning!("{}.", e)).ok()
+    }
+
+    pub fn is_valid(&self) -> bool {
+        !self.provider.is_empty()
+    }
+
+    pub fn get_key(&self) -> String {
+        let key = format!("Plugin:{}\t{}", self.provider, self.settings);
+        if self.isolated_cache { format!("{}:{}", self.name, key) } else { key }
+    }
+}
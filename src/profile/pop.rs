@@ -0,0 +1,142 @@
+use std::fs::read_to_string;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::Duration;
+
+use chacha20poly1305::aead::OsRng;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chrono::Utc;
+use rsa::pkcs1v15::SigningKey;
+use rsa::pkcs8::{DecodePrivateKey, EncodePrivateKey, LineEnding};
+use rsa::signature::{SignatureEncoding, Signer};
+use rsa::traits::PublicKeyParts;
+use rsa::RsaPrivateKey;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::permissions::{check_permissions, create_new_private_file, create_private_dir};
+use crate::profile::cache_root;
+
+// Proof-of-Possession tokens are bound to a key of the client, the resource only accepts them in signed HTTP requests
+// (SHR) signed with the key, https://datatracker.ietf.org/doc/html/draft-ietf-oauth-signed-http-request-03
+// One key is kept in the cache directory, so the cached tokens bound to it stay usable
+const FILENAME: &str = "pop_key.pem";
+
+const KEY_BITS: usize = 2048;
+
+fn encode(data: &[u8]) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+// The host with the port and the path of the URL, for `u` and `p` of the signed HTTP request
+fn host_and_path(url: &str) -> (&str, &str) {
+    let rest = url.split_once("://").map(|(_, r)| r).unwrap_or(url);
+    let rest = rest.split(['?', '#']).next().unwrap_or_default();
+    match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/")
+    }
+}
+
+fn key_filename() -> Option<PathBuf> {
+    let mut path = cache_root()?;
+    create_private_dir(&path).ok()?;
+    path.push(FILENAME);
+    Some(path)
+}
+
+fn read_key(filename: &Path) -> Result<RsaPrivateKey, String> {
+    let pem = read_to_string(filename).map_err(|e| format!("Unable to read the PoP key '{}', error is {}", filename.to_string_lossy(), e))?;
+    RsaPrivateKey::from_pkcs8_pem(&pem)
+        .map_err(|e| format!("Unable to load the PoP key '{}', remove it to create a new one, error is {}", filename.to_string_lossy(), e))
+}
+
+// The key of this machine, created on the first use
+fn load_key() -> Result<RsaPrivateKey, String> {
+    let filename = key_filename().ok_or("The cache directory is not available")?;
+    check_permissions(&filename);
+    if filename.exists() {
+        return read_key(&filename);
+    }
+    let key = RsaPrivateKey::new(&mut OsRng, KEY_BITS).map_err(|e| format!("Unable to create the PoP key, error is {}", e))?;
+    let pem = key.to_pkcs8_pem(LineEnding::LF).map_err(|e| format!("Unable to encode the PoP key, error is {}", e))?;
+    match create_new_private_file(&filename) {
+        Ok(mut f) => f.write_all(pem.as_bytes())
+            .map_err(|e| format!("Unable to save the PoP key to '{}', error is {}", filename.to_string_lossy(), e))?,
+        // Another process has created the key meanwhile, the tokens it gets are bound to that one
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => return read_created_key(&filename),
+        Err(e) => return Err(format!("Unable to save the PoP key to '{}', error is {}", filename.to_string_lossy(), e))
+    }
+    Ok(key)
+}
+
+// The other process may still be writing it
+fn read_created_key(filename: &Path) -> Result<RsaPrivateKey, String> {
+    for _ in 0..10 {
+        if let Ok(key) = read_key(filename) {
+            return Ok(key);
+        }
+        sleep(Duration::from_millis(100));
+    }
+    read_key(filename)
+}
+
+pub struct PopKey {
+    key: RsaPrivateKey,
+}
+
+impl PopKey {
+    pub fn load() -> Result<PopKey, String> {
+        load_key().map(|key| PopKey { key })
+    }
+
+    pub fn jwk(&self) -> Value {
+        json!({
+            "kty": "RSA",
+            "e": encode(&self.key.e().to_bytes_be()),
+            "n": encode(&self.key.n().to_bytes_be()),
+        })
+    }
+
+    // The JWK thumbprint, https://tools.ietf.org/html/rfc7638
+    pub fn kid(&self) -> String {
+        let jwk = self.jwk();
+        // The required members in lexicographic order, without whitespace
+        let canonical = format!(r#"{{"e":"{}","kty":"RSA","n":"{}"}}"#, jwk["e"].as_str().unwrap_or_default(), jwk["n"].as_str().unwrap_or_default());
+        encode(&Sha256::digest(canonical.as_bytes()))
+    }
+
+    // `req_cnf` of the token requests, binds the token to the key
+    pub fn req_cnf(&self) -> String {
+        encode(json!({ "kid": self.kid() }).to_string().as_bytes())
+    }
+
+    // The signed HTTP request carrying the access token, bound to the method and the URL if they are given
+    pub fn signed_http_request(&self, access_token: &str, method: &str, url: &str) -> String {
+        let mut nonce = [0u8; 16];
+        OsRng.fill_bytes(&mut nonce);
+        let header = json!({
+            "alg": "RS256",
+            "typ": "pop",
+            "kid": self.kid(),
+        });
+        let mut claims = json!({
+            "at": access_token,
+            "ts": Utc::now().timestamp(),
+            "nonce": nonce.iter().map(|b| format!("{:02x}", b)).collect::<String>(),
+            "cnf": { "jwk": self.jwk() },
+        });
+        if !method.is_empty() {
+            claims["m"] = json!(method.to_uppercase());
+        }
+        if !url.is_empty() {
+            let (host, path) = host_and_path(url);
+            claims["u"] = json!(host);
+            claims["p"] = json!(path);
+        }
+        let signing_input = format!("{}.{}", encode(header.to_string().as_bytes()), encode(claims.to_string().as_bytes()));
+        let signature = SigningKey::<Sha256>::new(self.key.clone()).sign(signing_input.as_bytes());
+        format!("{}.{}", signing_input, encode(&signature.to_bytes()))
+    }
+}
@@ -0,0 +1,121 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::PathBuf;
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+use crate::permissions::{check_permissions, create_private_dir, lock_file, write_private_file};
+use crate::profile::{cache_root, AADToken, Token};
+use crate::profile::user_profile::UserToken;
+
+// Refresh tokens are kept in their own file instead of the cached tokens, so the cache can be shared or looked into
+// while debugging without exposing long-lived credentials
+const FILENAME: &str = "refresh_tokens.json";
+
+// Refresh tokens not used for 90 days are expired by AAD, they are kept longer than the access tokens
+const DEFAULT_LIFETIME: i64 = 90 * 24 * 3600;
+
+#[derive(Clone, PartialEq, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct Entry {
+    refresh_token: String,
+    // The last time the refresh token was issued
+    saved_on: i64,
+}
+
+fn store_filename() -> Option<PathBuf> {
+    let mut path = cache_root()?;
+    create_private_dir(&path).ok()?;
+    path.push(FILENAME);
+    Some(path)
+}
+
+fn lock_store() -> Option<File> {
+    lock_file(&store_filename()?)
+}
+
+fn load() -> HashMap<String, Entry> {
+    let filename = match store_filename() {
+        Some(f) => f,
+        None => return HashMap::new()
+    };
+    check_permissions(filename.as_path());
+    let file = match File::open(filename.as_path()) {
+        Ok(f) => f,
+        Err(_) => return HashMap::new()
+    };
+    serde_json::from_reader(file).unwrap_or_else(|e| {
+        print_warning!("Unable to load refresh tokens at '{}', error is {:#?}.", filename.to_string_lossy(), e);
+        HashMap::new()
+    })
+}
+
+fn save(entries: &HashMap<String, Entry>) {
+    let filename = match store_filename() {
+        Some(f) => f,
+        None => return
+    };
+    let written = serde_json::to_vec(entries).map_err(|e| e.into())
+        .and_then(|data| write_private_file(filename.as_path(), &data));
+    if let Err(e) = written {
+        print_warning!("Unable to save refresh tokens to '{}', error is {:#?}.", filename.to_string_lossy(), e);
+    }
+}
+
+// Put the refresh token of `key` back into its cached token, if there is no cached token, i.e. the access token
+// expired and was removed, it's restored as an expired token which can only be refreshed
+pub fn restore(key: &str, token: Option<Token>) -> Option<Token> {
+    let entry = match load().remove(key) {
+        Some(e) => e,
+        None => return token
+    };
+    match token {
+        Some(Token::User(mut t)) => {
+            t.refresh_token = entry.refresh_token;
+            Some(Token::User(t))
+        }
+        Some(t) => Some(t),
+        None => Some(Token::User(UserToken { refresh_token: entry.refresh_token, ..Default::default() }))
+    }
+}
+
+// Move the refresh tokens out of the tokens into the store, returns the tokens to be saved in the cache, which are
+// the valid ones, the ones within the extended lifetime, and the expired ones still having a refresh token.
+// The refresh tokens of the other keys are kept unless they're in `removed` or older than `max_age` seconds
+pub fn take(tokens: HashMap<String, Token>, removed: &[String], max_age: i64) -> HashMap<String, Token> {
+    let lifetime = if max_age > 0 { max_age } else { DEFAULT_LIFETIME };
+    let _lock = lock_store();
+    let existing = load();
+    let now = Utc::now().timestamp();
+    let mut entries: HashMap<String, Entry> = existing.iter()
+        .filter(|(key, e)| !tokens.contains_key(*key) && !removed.contains(key) && now - e.saved_on < lifetime)
+        .map(|(key, e)| (key.clone(), e.clone()))
+        .collect();
+    let tokens = tokens.into_iter().map(|(key, token)| match token {
+        Token::User(mut t) if !t.refresh_token.is_empty() => {
+            let refresh_token = std::mem::take(&mut t.refresh_token);
+            let saved_on = match existing.get(&key) {
+                Some(e) if e.refresh_token == refresh_token => e.saved_on,
+                _ => now
+            };
+            let refreshable = now - saved_on < lifetime;
+            if refreshable {
+                entries.insert(key.clone(), Entry { refresh_token, saved_on });
+            }
+            (key, Token::User(t), refreshable)
+        }
+        _ => (key, token, false)
+    }).filter(|(_, token, refreshable)| *refreshable || !token.is_expired() || token.is_within_ext_lifetime())
+        .map(|(key, token, _)| (key, token))
+        .collect();
+    if entries != existing {
+        save(&entries);
+    }
+    tokens
+}
+
+pub fn clear() {
+    let _lock = lock_store();
+    save(&HashMap::new());
+}
@@ -0,0 +1,222 @@
+use std::collections::HashMap;
+use std::fs::{read, read_dir, remove_dir_all, remove_file, File, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+
+use crate::permissions::{check_permissions, create_private_dir, write_private_file};
+use crate::profile::{cache_policy, cache_root, cbor, refresh_tokens, Token};
+
+// Every cached token has its own file in `cache/`, named by the hash of its key, so getting a token only reads
+// and rewrites the one entry it needs, the whole cache is only read to look up an account or to evict tokens
+const DIRNAME: &str = "cache";
+
+// The entries are CBOR instead of JSON with `CacheFormat` set to `cbor`, both are read
+const JSON_EXTENSION: &str = "json";
+const CBOR_EXTENSION: &str = "cbor";
+
+// The single file cache of older versions, split into the entries on the first use
+const LEGACY_FILENAME: &str = "cache.json";
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    key: String,
+    token: Token,
+}
+
+fn entries_dir() -> Option<PathBuf> {
+    let root = match cache_root() {
+        Some(d) => d,
+        None => {
+            print_warning!("The cache directory is not available.");
+            return None;
+        }
+    };
+    let dir = root.join(DIRNAME);
+    if let Err(e) = create_private_dir(&dir) {
+        print_warning!("Unable to create cache directory '{}', error is {:#?}.", dir.to_string_lossy(), e);
+        return None;
+    }
+    migrate(&root.join(LEGACY_FILENAME), &dir);
+    Some(dir)
+}
+
+fn entry_filename(dir: &Path, key: &str, extension: &str) -> PathBuf {
+    let hash: String = Sha256::digest(key.as_bytes())[..16].iter().map(|b| format!("{:02x}", b)).collect();
+    dir.join(format!("{}.{}", hash, extension))
+}
+
+// The extension of the entries to write, and the one of the other format
+fn extensions() -> (&'static str, &'static str) {
+    if cache_policy().binary { (CBOR_EXTENSION, JSON_EXTENSION) } else { (JSON_EXTENSION, CBOR_EXTENSION) }
+}
+
+fn migrate(legacy: &Path, dir: &Path) {
+    let file = match File::open(legacy) {
+        Ok(f) => f,
+        Err(_) => return
+    };
+    match serde_json::from_reader::<_, HashMap<String, Token>>(file) {
+        Ok(cache) => {
+            // Older caches have the refresh tokens in the tokens
+            for (key, token) in refresh_tokens::take(cache, &[], cache_policy().refresh_token_max_age) {
+                write_entry(dir, key, token);
+            }
+        }
+        Err(e) => print_warning!("Unable to load cache file at '{}', error is {:#?}.", legacy.to_string_lossy(), e)
+    }
+    remove_file(legacy).ok();
+}
+
+fn read_entry(filename: &Path) -> Option<Entry> {
+    check_permissions(filename);
+    let data = read(filename).ok()?;
+    let entry = if filename.extension().map(|e| e == CBOR_EXTENSION).unwrap_or(false) {
+        cbor::decode(&data).and_then(|v| serde_json::from_value(v).map_err(|e| e.to_string()))
+    } else {
+        serde_json::from_slice(&data).map_err(|e| e.to_string())
+    };
+    entry.map_err(|e| {
+        print_warning!("Unable to load cache file at '{}', error is {}.", filename.to_string_lossy(), e);
+    }).ok()
+}
+
+fn write_entry(dir: &Path, key: String, token: Token) {
+    let (extension, other) = extensions();
+    let filename = entry_filename(dir, &key, extension);
+    // The entry in the other format is replaced
+    remove_file(entry_filename(dir, &key, other)).ok();
+    let entry = Entry { key, token };
+    let data = if extension == CBOR_EXTENSION {
+        serde_json::to_value(&entry).map(|v| cbor::encode(&v))
+    } else {
+        serde_json::to_vec(&entry)
+    };
+    // Replaced at once, processes reading it at the same time get the old or the new token
+    let written = data.map_err(|e| e.into()).and_then(|data| write_private_file(&filename, &data));
+    if let Err(e) = written {
+        print_warning!("Unable to save cache to '{}', error is {:#?}.", filename.to_string_lossy(), e);
+    }
+}
+
+// The last use of an entry is the modification time of its file, it's touched when the entry is read, so reading
+// a token never rewrites it
+fn touch(filename: &Path) {
+    if let Ok(f) = OpenOptions::new().write(true).open(filename) {
+        f.set_modified(SystemTime::now()).ok();
+    }
+}
+
+fn entry_files(dir: &Path) -> Vec<PathBuf> {
+    let files = match read_dir(dir) {
+        Ok(f) => f,
+        Err(_) => return vec![]
+    };
+    files.filter_map(|f| f.ok())
+        .map(|f| f.path())
+        .filter(|p| p.extension().map(|e| e == JSON_EXTENSION || e == CBOR_EXTENSION).unwrap_or(false))
+        .collect()
+}
+
+fn read_all(dir: &Path) -> HashMap<String, Token> {
+    entry_files(dir).iter()
+        .filter_map(|p| read_entry(p))
+        .map(|e| (e.key, e.token))
+        .collect()
+}
+
+// The cached token of the key
+pub fn load(key: &str) -> Option<Token> {
+    let dir = entries_dir()?;
+    let (extension, other) = extensions();
+    let token = [extension, other].iter()
+        .map(|ext| entry_filename(&dir, key, ext))
+        .find_map(|f| read_entry(&f).filter(|e| e.key == key).map(|e| (f, e.token)))
+        .map(|(f, token)| {
+            touch(&f);
+            token
+        });
+    refresh_tokens::restore(key, token)
+}
+
+// The key of a cached token `matches`, the refresh tokens are not read
+pub fn find_key(matches: impl Fn(&str, &Token) -> bool) -> Option<String> {
+    read_all(&entries_dir()?).into_iter().find(|(k, t)| matches(k, t)).map(|(k, _)| k)
+}
+
+// All cached tokens as JSON for inspection, keyed by the cache keys, without the refresh tokens
+pub fn export() -> Value {
+    let cache = match entries_dir() {
+        Some(dir) => read_all(&dir),
+        None => HashMap::new()
+    };
+    let mut keys: Vec<&String> = cache.keys().collect();
+    keys.sort();
+    Value::Object(keys.into_iter().map(|k| (k.clone(), serde_json::to_value(&cache[k]).unwrap_or_default())).collect::<Map<_, _>>())
+}
+
+// Same as `export`, in CBOR
+pub fn export_binary() -> Vec<u8> {
+    cbor::encode(&export())
+}
+
+// Save the tokens, the other entries are not touched
+pub fn save(tokens: HashMap<String, Token>) {
+    let dir = match entries_dir() {
+        Some(d) => d,
+        None => return
+    };
+    // Refresh tokens outlive the access tokens, they are saved separately
+    let policy = cache_policy();
+    let keys: Vec<String> = tokens.keys().cloned().collect();
+    let mut output = refresh_tokens::take(tokens, &[], policy.refresh_token_max_age);
+    for key in keys {
+        match output.remove(&key) {
+            Some(token) => write_entry(&dir, key, token),
+            // Expired without a refresh token
+            None => {
+                remove_file(entry_filename(&dir, &key, JSON_EXTENSION)).ok();
+                remove_file(entry_filename(&dir, &key, CBOR_EXTENSION)).ok();
+            }
+        }
+    }
+    evict(&dir, policy.max_entries);
+}
+
+// Remove the tokens and their refresh tokens, returns false if none of them was cached
+pub fn remove(keys: &[String]) -> bool {
+    let dir = match entries_dir() {
+        Some(d) => d,
+        None => return false
+    };
+    // A refresh token may be left without its expired token
+    let removed = keys.iter().filter(|k| load(k).is_some()).count() > 0;
+    for key in keys {
+        remove_file(entry_filename(&dir, key, JSON_EXTENSION)).ok();
+        remove_file(entry_filename(&dir, key, CBOR_EXTENSION)).ok();
+    }
+    refresh_tokens::take(HashMap::new(), keys, cache_policy().refresh_token_max_age);
+    removed
+}
+
+pub fn clear() {
+    if let Some(dir) = entries_dir() {
+        remove_dir_all(&dir).ok();
+    }
+    refresh_tokens::clear();
+}
+
+// Keep the `max_entries` most recently used tokens, the whole cache is only read once there are too many entries
+fn evict(dir: &Path, max_entries: usize) {
+    let mut files = entry_files(dir);
+    if max_entries == 0 || files.len() <= max_entries {
+        return;
+    }
+    let last_used = |f: &PathBuf| f.metadata().and_then(|m| m.modified()).unwrap_or(SystemTime::UNIX_EPOCH);
+    files.sort_by_key(|f| std::cmp::Reverse(last_used(f)));
+    let evicted: Vec<String> = files.iter().skip(max_entries).filter_map(|f| read_entry(f)).map(|e| e.key).collect();
+    remove(&evicted);
+}
@@ -1,26 +1,95 @@
 use std::{thread, time};
 use std::collections::HashMap;
+#[cfg(not(feature = "nogui"))]
+use std::{env, fs, path::Path, process::Command};
 use std::process::exit;
+use std::sync::atomic::Ordering;
 
-use chrono::Utc;
 #[cfg(not(feature = "nogui"))]
 use clipboard::{ClipboardContext, ClipboardProvider};
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 #[cfg(not(feature = "nogui"))]
 use webbrowser::{Browser, open_browser};
 
-use crate::profile::{AADToken, is_expired, send_request, TokenType};
+use crate::color::bold;
+use crate::profile::aad_error::{explain_error, is_rejected_grant};
+use crate::profile::{AADToken, add_extra_params, check_clock_skew, DEBUG_HTTP, expires_on_from, extra_params_key, is_expired, merge_client_capabilities, plain_secret, raw_response, send_request, send_request_ignoring, TokenType, with_failure_key, with_soft_failures};
+use crate::profile::http::{http_client, resolve_url, HttpResponse};
+use crate::profile::identity_service;
+use crate::profile::iwa;
+use crate::profile::jwt::decode_claims;
+use crate::profile::notify::notify;
+use crate::profile::discovery::discover_endpoints;
+use crate::profile::pop::PopKey;
+use crate::profile::validate::missing_scopes;
+use crate::profile::vscode;
+
+// Revoking the sign-in sessions through Microsoft Graph needs this permission consented to the app
+const REVOKE_SESSIONS_SCOPE: &str = "User.RevokeSessions.All";
+
+// Microsoft Graph of the cloud of the authority
+fn graph_endpoint(authority: &str) -> &'static str {
+    if authority.contains("microsoftonline.us") {
+        "https://graph.microsoft.us"
+    } else if authority.contains("chinacloudapi.cn") {
+        "https://microsoftgraph.chinacloudapi.cn"
+    } else {
+        "https://graph.microsoft.com"
+    }
+}
 
 #[derive(Clone, PartialEq, Debug, Default, Serialize, Deserialize)]
 #[serde(default)]
 pub struct UserToken {
-    error: String,
-    scope: String,
-    id_token: String,
-    access_token: String,
-    refresh_token: String,
-    expires_in: i64,
-    expires_on: i64,
+    pub(crate) error: String,
+    pub(crate) scope: String,
+    pub(crate) id_token: String,
+    pub(crate) access_token: String,
+    pub(crate) refresh_token: String,
+    pub(crate) client_info: String,
+    pub(crate) expires_in: i64,
+    pub(crate) expires_on: i64,
+    // The extended lifetime, the token is still accepted by the resources during AAD outages
+    pub(crate) ext_expires_in: i64,
+    pub(crate) ext_expires_on: i64,
+    // The token response as is, only kept with `KeepRawResponse`
+    #[serde(skip_serializing_if = "Value::is_null")]
+    pub(crate) raw_response: Value,
+}
+
+impl UserToken {
+    // The username of the signed-in account, needs `openid` in the scope
+    pub fn get_username(&self) -> Option<String> {
+        let claims = decode_claims(&self.id_token)?;
+        claims.get("preferred_username")
+            .or_else(|| claims.get("upn"))
+            .and_then(|v| v.as_str())
+            .map(|s| s.to_owned())
+    }
+
+    // The home account id "{uid}.{utid}", the user object id and tenant id in the home tenant of the account
+    pub fn get_home_account_id(&self) -> Option<String> {
+        let info: Value = base64::decode_config(&self.client_info, base64::URL_SAFE_NO_PAD).ok()
+            .and_then(|v| serde_json::from_slice(&v).ok())
+            // Fallback to the id token, it's the same for accounts in their home tenant
+            .or_else(|| decode_claims(&self.id_token).map(|c| json!({ "uid": c["oid"], "utid": c["tid"] })))?;
+        match (info["uid"].as_str(), info["utid"].as_str()) {
+            (Some(uid), Some(utid)) => Some(format!("{}.{}", uid, utid)),
+            _ => None
+        }
+    }
+
+    // The tenant the account signed in to, needs `openid` in the scope
+    pub fn get_tenant_id(&self) -> Option<String> {
+        decode_claims(&self.id_token)?["tid"].as_str().map(|s| s.to_owned())
+    }
+
+    // The account can be picked by either the username or the home account id
+    pub fn is_account(&self, account: &str) -> bool {
+        self.get_username().iter().chain(self.get_home_account_id().iter())
+            .any(|v| v.eq_ignore_ascii_case(account))
+    }
 }
 
 impl AADToken for UserToken {
@@ -28,6 +97,10 @@ impl AADToken for UserToken {
         is_expired(self.expires_on)
     }
 
+    fn get_expires_on(&self) -> i64 {
+        self.expires_on
+    }
+
     fn get_token_string(&self, token_type: TokenType) -> String {
         match token_type {
             TokenType::Access => &self.access_token,
@@ -38,126 +111,653 @@ impl AADToken for UserToken {
     }
 }
 
-#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[derive(Clone, PartialEq, Debug, Default, Serialize, Deserialize)]
 #[serde(rename_all = "PascalCase")]
 pub struct UserProfile {
     pub name: String,
     #[serde(default)]
     pub client_id: String,
+    // Only for confidential clients, i.e. web app registrations
+    #[serde(default)]
+    pub secret: String,
     #[serde(default)]
     pub tenant: String,
     #[serde(default)]
     pub authority: String,
     #[serde(default)]
     pub scope: String,
+    #[serde(default)]
+    pub login_hint: String,
+    #[serde(default)]
+    pub domain_hint: String,
+    #[serde(default)]
+    pub prompt: String,
+    #[serde(default)]
+    pub account: String,
+    #[serde(default)]
+    pub extra_params: HashMap<String, String>,
+    #[serde(default)]
+    pub client_capabilities: Vec<String>,
+    #[serde(default)]
+    pub auth_timeout: u64,
+    #[serde(default)]
+    pub poll_interval: u64,
+    #[serde(default)]
+    pub shared_cache: bool,
+    // The browser to sign in with, i.e. `firefox` or a command line, `none` to only print the URL and the code
+    #[serde(default)]
+    pub browser: String,
+    // Alternate addresses of the hosts, i.e. to send the requests to a test server or a private cloud
+    #[serde(default)]
+    pub resolve: HashMap<String, String>,
+    // The AWS role assumed with the token by `--format aws-credential-process`
+    #[serde(default)]
+    pub aws_role_arn: String,
+    // The workload identity pool provider exchanging the token by the `gcp-*` formats,
+    // `//iam.googleapis.com/projects/<number>/locations/global/workloadIdentityPools/<pool>/providers/<provider>`
+    #[serde(default)]
+    pub gcp_audience: String,
+    // The GCP service account to impersonate with the federated token, optional
+    #[serde(default)]
+    pub gcp_service_account: String,
+    // Used as is instead of the ones under the authority, i.e. for STS deployments and emulators with other paths
+    #[serde(default)]
+    pub token_endpoint: String,
+    #[serde(default)]
+    pub device_code_endpoint: String,
+    // Keep the tokens apart from other profiles with the same parameters, the profile name is in the cache key
+    #[serde(default)]
+    pub isolated_cache: bool,
+    // Sign in with the Windows account through AD FS before falling back to the device code, Windows only
+    #[serde(default)]
+    pub integrated_windows_auth: bool,
+    // Use the sign-in of the Azure Account extension of VS Code before falling back to the device code,
+    // the client id is the one of VS Code
+    #[serde(default)]
+    pub vs_code: bool,
+    // Fields not known to `User` profiles, reported by `profile validate`
+    #[serde(flatten)]
+    pub unknown_fields: HashMap<String, Value>,
+    #[serde(skip)]
+    pub claims: String,
+    // Request Proof-of-Possession tokens bound to the PoP key
+    #[serde(skip)]
+    pub pop: bool,
+    // Tried before signing in, i.e. the refresh token of the token with fewer scopes
+    #[serde(skip)]
+    pub seed_refresh_token: String,
 }
 
 #[derive(Clone, Default, PartialEq, Debug, Serialize, Deserialize)]
-struct DevCodeResp {
-    device_code: String,
-    user_code: String,
-    verification_uri: String,
-    expires_in: u64,
-    interval: u64,
-    message: String,
+pub(crate) struct DevCodeResp {
+    pub device_code: String,
+    pub user_code: String,
+    pub verification_uri: String,
+    // The URI with the code embedded, not returned by all the servers
+    #[serde(default)]
+    pub verification_uri_complete: String,
+    pub expires_in: u64,
+    pub interval: u64,
+    pub message: String,
+}
+
+// The result of one poll of the device code sign-in
+pub(crate) enum DeviceCodePoll {
+    // The error returned while the user is signing in, `authorization_pending` or `slow_down`
+    Pending(String),
+    Done(UserToken),
+}
+
+// A spinner with the time left and the last poll status on stderr while waiting for the device code sign-in,
+// only on terminals and not with `--debug-http`, so the output isn't mixed up
+struct PollProgress {
+    enabled: bool,
+    expires_at: time::Instant,
+    status: String,
+    frame: usize,
+    width: usize,
+}
+
+impl PollProgress {
+    fn new(expires_in: u64) -> Self {
+        PollProgress {
+            enabled: atty::is(atty::Stream::Stderr) && !DEBUG_HTTP.load(Ordering::Relaxed),
+            expires_at: time::Instant::now() + time::Duration::from_secs(expires_in),
+            status: String::from("waiting"),
+            frame: 0,
+            width: 0,
+        }
+    }
+
+    fn draw(&mut self) {
+        const FRAMES: [char; 4] = ['|', '/', '-', '\\'];
+        let left = self.expires_at.saturating_duration_since(time::Instant::now()).as_secs();
+        let line = format!("{} Waiting for the sign-in, the code expires in {}:{:02}, last poll: {}",
+            FRAMES[self.frame % FRAMES.len()], left / 60, left % 60, self.status);
+        self.frame += 1;
+        // Pad over the longer previous line
+        eprint!("\r{:width$}", line, width = self.width);
+        self.width = line.len();
+    }
+
+    // Sleep until the next poll, redrawing the line a few times per second
+    fn wait(&mut self, duration: time::Duration) {
+        if !self.enabled {
+            thread::sleep(duration);
+            return;
+        }
+        let until = time::Instant::now() + duration;
+        loop {
+            self.draw();
+            let left = until.saturating_duration_since(time::Instant::now());
+            if left.is_zero() {
+                break;
+            }
+            thread::sleep(left.min(time::Duration::from_millis(250)));
+        }
+    }
+
+    fn clear(&self) {
+        if self.enabled && self.width > 0 {
+            eprint!("\r{:width$}\r", "", width = self.width);
+        }
+    }
+}
+
+// Highlight the code in the sign-in message
+fn device_code_message(dcresp: &DevCodeResp) -> String {
+    dcresp.message.replace(&dcresp.user_code, &bold(&dcresp.user_code))
 }
 
 #[cfg(feature = "nogui")]
-fn device_code_login(dcresp: &DevCodeResp) {
-    eprintln!("{}", dcresp.message)
+fn device_code_login(dcresp: &DevCodeResp, _browser: &str) {
+    eprintln!("{}", device_code_message(dcresp))
+}
+
+// No local browser or clipboard to use, i.e. in SSH sessions, containers, or WSL without a browser configured
+#[cfg(not(feature = "nogui"))]
+pub fn is_remote_session() -> bool {
+    let has_env = |name: &str| env::var_os(name).is_some_and(|v| !v.is_empty());
+    if has_env("SSH_CONNECTION") || has_env("SSH_CLIENT") || has_env("SSH_TTY") {
+        return true;
+    }
+    if cfg!(target_os = "linux") {
+        let is_wsl = fs::read_to_string("/proc/version")
+            .map(|v| v.to_lowercase().contains("microsoft"))
+            .unwrap_or_default();
+        if is_wsl {
+            return !has_env("BROWSER");
+        }
+        let is_container = Path::new("/.dockerenv").exists()
+            || Path::new("/run/.containerenv").exists()
+            || has_env("KUBERNETES_SERVICE_HOST");
+        return is_container || !(has_env("DISPLAY") || has_env("WAYLAND_DISPLAY"));
+    }
+    false
+}
+
+// The browser can be `default`, `none`, `firefox`, `chrome`, `edge`, or a command line, the URL is appended to it,
+// or replaces `{}` in it
+#[cfg(not(feature = "nogui"))]
+fn launch_browser(browser: &str, url: &str) -> bool {
+    let (program, args): (&str, Vec<&str>) = match browser.to_lowercase().as_str() {
+        "" | "default" => return open_browser(Browser::Default, url).is_ok(),
+        "none" => return false,
+        "firefox" if cfg!(target_os = "windows") || cfg!(target_os = "macos") => {
+            return open_browser(Browser::Firefox, url).is_ok();
+        }
+        "chrome" if cfg!(target_os = "windows") || cfg!(target_os = "macos") => {
+            return open_browser(Browser::Chrome, url).is_ok();
+        }
+        "edge" if cfg!(target_os = "windows") => ("cmd", vec!["/C", "start", "", "msedge", url]),
+        "edge" if cfg!(target_os = "macos") => ("open", vec!["-a", "Microsoft Edge", url]),
+        "firefox" => ("firefox", vec![url]),
+        "chrome" => ("google-chrome", vec![url]),
+        "edge" => ("microsoft-edge", vec![url]),
+        _ => {
+            let mut parts = browser.split_whitespace();
+            let program = parts.next().unwrap_or_default();
+            let mut args: Vec<&str> = parts.map(|a| if a == "{}" { url } else { a }).collect();
+            if !browser.split_whitespace().any(|a| a == "{}") {
+                args.push(url);
+            }
+            (program, args)
+        }
+    };
+    Command::new(program).args(args).spawn().is_ok()
 }
 
 #[cfg(not(feature = "nogui"))]
-fn device_code_login(dcresp: &DevCodeResp) {
-    ClipboardProvider::new().map(|mut ctx: ClipboardContext| {
+fn device_code_login(dcresp: &DevCodeResp, browser: &str) {
+    if is_remote_session() || browser.eq_ignore_ascii_case("none") {
+        eprintln!("{}", device_code_message(dcresp));
+        return;
+    }
+    // No need to paste the code if it's embedded in the URI
+    if !dcresp.verification_uri_complete.is_empty() && launch_browser(browser, &dcresp.verification_uri_complete) {
+        return;
+    }
+    let copied = ClipboardProvider::new().and_then(|mut ctx: ClipboardContext| {
         ctx.set_contents(dcresp.user_code.clone())
-    }).map(|_| {
-        open_browser(Browser::Default, &dcresp.verification_uri)
-    }).map(|_| ()).unwrap_or(
-        eprintln!("{}", dcresp.message)
-    );
+    }).is_ok();
+    if !(copied && launch_browser(browser, &dcresp.verification_uri)) {
+        eprintln!("{}", device_code_message(dcresp))
+    }
+}
+
+#[cfg(feature = "nogui")]
+fn open_url(url: &str, _browser: &str) {
+    eprintln!("Open {} in the browser to continue.", url)
+}
+
+#[cfg(not(feature = "nogui"))]
+fn open_url(url: &str, browser: &str) {
+    if is_remote_session() || !launch_browser(browser, url) {
+        eprintln!("Open {} in the browser to continue.", url)
+    }
 }
 
 impl UserProfile {
     pub fn get_token(&self) -> UserToken {
-        // TODO: Support secret client, now this program supports public client only
-
-        // https://docs.microsoft.com/en-us/azure/active-directory/develop/v2-oauth2-device-code
-        let url = format!("{}/{}/oauth2/v2.0/devicecode", self.authority, self.tenant);
+        if self.shared_cache && self.prompt.is_empty() {
+            // Signed in by another tool sharing the cache
+            let account = if self.account.is_empty() { &self.login_hint } else { &self.account };
+            let token = identity_service::find_refresh_token(self, account)
+                .and_then(|rt| self.refresh_token_softly(rt, "the shared cache"));
+            if let Some(t) = token {
+                return t;
+            }
+        }
+        if !self.seed_refresh_token.is_empty() {
+            // Fails if the new scopes need consent
+            if let Some(t) = self.refresh_token_softly(self.seed_refresh_token.clone(), "'SeedRefreshToken'") {
+                return t;
+            }
+        }
 
-        let mut form: HashMap<&str, &str> = HashMap::new();
-        form.insert("client_id", &self.client_id);
-        form.insert("scope", &self.scope);
+        if self.vs_code && self.prompt.is_empty() {
+            // Signed in with the Azure Account extension
+            let token = vscode::find_refresh_token(&self.authority)
+                .and_then(|rt| self.refresh_token_softly(rt, "the VS Code sign-in"));
+            match token {
+                Some(t) => return t,
+                None => print_warning!("No usable VS Code sign-in found, signing in with the device code instead.")
+            }
+        }
 
-        let resp = send_request(&url, &form, false);
+        if self.integrated_windows_auth && self.prompt.is_empty() {
+            match self.get_token_iwa() {
+                Ok(t) => return t,
+                Err(e) => print_warning!("Integrated Windows Authentication failed, {}, signing in with the device code instead.", e)
+            }
+        }
 
-        let dcresp: DevCodeResp = resp.json().map_err(|e| {
-            eprintln!("ERROR: Failed to decode response, error is {:#?}.", e);
+        let dcresp = self.start_device_code().map_err(|e| {
+            print_error!("{}", e);
             exit(2)
         }).unwrap();
 
-        let url = format!("{}/{}/oauth2/v2.0/token", self.authority, self.tenant);
+        device_code_login(&dcresp, &self.browser);
+
+        let timeout = if self.auth_timeout > 0 { self.auth_timeout.min(dcresp.expires_in) } else { dcresp.expires_in };
+        let deadline = time::Instant::now() + time::Duration::from_secs(timeout);
+        let mut interval = if self.poll_interval > 0 { self.poll_interval } else { dcresp.interval.max(1) };
+        let mut progress = PollProgress::new(dcresp.expires_in);
+        while time::Instant::now() < deadline {
+            let status = match self.poll_device_code(&dcresp.device_code) {
+                Ok(DeviceCodePoll::Done(token)) => {
+                    progress.clear();
+                    let account = token.get_username().map(|u| format!(" as {}", u)).unwrap_or_default();
+                    notify("tokengen", &format!("Signed in{}, the token has been issued.", account));
+                    return token;
+                }
+                Ok(DeviceCodePoll::Pending(status)) => status,
+                Err(e) => {
+                    progress.clear();
+                    notify("tokengen", "The sign-in has failed.");
+                    print_error!("{}", e);
+                    exit(2);
+                }
+            };
+            if status == "slow_down" {
+                interval += 5;
+            }
+            progress.status = status;
+            // Don't sleep past the deadline
+            let remaining = deadline.saturating_duration_since(time::Instant::now());
+            progress.wait(remaining.min(time::Duration::from_secs(interval)));
+        }
+
+        progress.clear();
+        notify("tokengen", "The sign-in has timed out, the code is no longer valid.");
+        print_error!("Failed to get token, time out.");
+        exit(2);
+    }
 
+    // Request the device code and the user code, the sign-in is completed by polling with the device code
+    pub(crate) fn start_device_code(&self) -> Result<DevCodeResp, String> {
+        self.send_device_code_request(false).json().map_err(|e| format!("Failed to decode response, error is {:#?}.", e))
+    }
+
+    // Poll the token endpoint once with the device code
+    pub(crate) fn poll_device_code(&self, device_code: &str) -> Result<DeviceCodePoll, String> {
+        let url = self.get_token_endpoint();
+
+        let secret = plain_secret(&self.secret);
         let mut form: HashMap<&str, &str> = HashMap::new();
         form.insert("grant_type", "urn:ietf:params:oauth:grant-type:device_code");
         form.insert("client_id", &self.client_id);
-        form.insert("device_code", &dcresp.device_code);
+        form.insert("device_code", device_code);
+        if !secret.is_empty() {
+            form.insert("client_secret", &secret);
+        }
+        // Return the home account id in `client_info`
+        form.insert("client_info", "1");
+        let req_cnf = self.req_cnf();
+        if self.pop {
+            form.insert("token_type", "pop");
+            form.insert("req_cnf", &req_cnf);
+        }
+        add_extra_params(&mut form, &self.extra_params);
 
-        device_code_login(&dcresp);
+        let resp = send_request(&url, &form, true);
+        let mut token: UserToken = resp.json().map_err(|e| format!("Failed to decode response, error is {:#?}.", e))?;
+        if token.error.is_empty() {
+            self.complete_token(&mut token, &resp.body);
+            Ok(DeviceCodePoll::Done(token))
+        } else if token.error == "authorization_pending" || token.error == "slow_down" {
+            Ok(DeviceCodePoll::Pending(token.error))
+        } else {
+            Err(format!("Failed to get token, error is {}", explain_error(&resp.body).unwrap_or(token.error)))
+        }
+    }
 
-        for _ in 1..=dcresp.expires_in {
-            let resp = send_request(&url, &form, true);
-            let mut token: UserToken = resp.json().map_err(|e| {
-                eprintln!("ERROR: Failed to decode response, error is {:#?}.", e);
-                exit(2);
-            }).unwrap();
-            if token.error.is_empty() {
-                token.expires_on = Utc::now().timestamp() + token.expires_in - 5;   // Some seconds passed
-                return token;
-            } else if token.error != "authorization_pending" {
-                eprintln!("ERROR: Failed to get token, error is {}.", token.error);
-                exit(2);
-            }
-            thread::sleep(time::Duration::from_secs(dcresp.interval));
+    // https://docs.microsoft.com/en-us/azure/active-directory/develop/v2-oauth2-device-code
+    pub fn send_device_code_request(&self, ignore_error: bool) -> HttpResponse {
+        let url = self.get_device_code_endpoint();
+        let claims = merge_client_capabilities(&self.claims, &self.client_capabilities);
+
+        let mut form: HashMap<&str, &str> = HashMap::new();
+        form.insert("client_id", &self.client_id);
+        form.insert("scope", &self.scope);
+        if !claims.is_empty() {
+            form.insert("claims", &claims);
+        }
+        if !self.login_hint.is_empty() {
+            form.insert("login_hint", &self.login_hint);
+        } else if !self.account.is_empty() {
+            form.insert("login_hint", &self.account);
+        }
+        if !self.domain_hint.is_empty() {
+            // Skip the home realm discovery page for federated domains
+            form.insert("domain_hint", &self.domain_hint);
         }
+        if !self.prompt.is_empty() {
+            form.insert("prompt", &self.prompt);
+        }
+        add_extra_params(&mut form, &self.extra_params);
 
-        eprintln!("ERROR: Failed to get token, time out.");
-        exit(2);
+        send_request(&url, &form, ignore_error)
+    }
+
+    // Refresh a refresh token from another source, the device code sign-in follows if it fails, so failed requests
+    // are warnings and not cached as failures of the profile
+    fn refresh_token_softly(&self, refresh_token: String, source: &str) -> Option<UserToken> {
+        let token = UserToken { refresh_token, ..Default::default() };
+        match with_failure_key("", || with_soft_failures(|| self.refresh_token(&token))) {
+            (_, Some(e)) => {
+                print_warning!("Unable to refresh the token from {}, {}", source, e);
+                None
+            }
+            (t, None) => t
+        }
     }
 
     pub fn refresh_token(&self, token: &UserToken) -> Option<UserToken> {
+        if token.refresh_token.is_empty() {
+            // No `offline_access` in the scope
+            return None;
+        }
+
         // https://docs.microsoft.com/en-us/azure/active-directory/develop/v2-oauth2-auth-code-flow#refresh-the-access-token
-        let url = format!("{}/{}/oauth2/v2.0/token", self.authority, self.tenant);
+        let url = self.get_token_endpoint();
+        let claims = merge_client_capabilities(&self.claims, &self.client_capabilities);
 
+        let secret = plain_secret(&self.secret);
         let mut form: HashMap<&str, &str> = HashMap::new();
         form.insert("client_id", &self.client_id);
         form.insert("scope", &self.scope);
         form.insert("refresh_token", &token.refresh_token);
+        if !secret.is_empty() {
+            form.insert("client_secret", &secret);
+        }
         form.insert("grant_type", "refresh_token");
+        form.insert("client_info", "1");
+        if !claims.is_empty() {
+            form.insert("claims", &claims);
+        }
+        let req_cnf = self.req_cnf();
+        if self.pop {
+            form.insert("token_type", "pop");
+            form.insert("req_cnf", &req_cnf);
+        }
+        add_extra_params(&mut form, &self.extra_params);
 
-        let resp = send_request(&url, &form, false);
+        // The refresh token may have expired or been revoked, sign in again instead of failing,
+        // other errors, i.e. a wrong client secret, are not fixed by signing in
+        let resp = send_request_ignoring(&url, &form, is_rejected_grant);
 
         let mut token: UserToken = match resp.json() {
             Ok(v) => v,
             Err(e) => {
-                eprintln!("WARNING: Failed to refresh token, error is {:#?}.", e);
+                print_warning!("Failed to refresh token, error is {:#?}.", e);
                 return None;
             }
         };
+        if !token.error.is_empty() {
+            print_warning!("Failed to refresh token, error is {}", explain_error(&resp.body).unwrap_or(token.error));
+            return None;
+        }
 
-        token.expires_on = Utc::now().timestamp() + token.expires_in - 5;   // Some seconds passed
+        self.complete_token(&mut token, &resp.body);
         Some(token)
     }
 
+    // Set the expiration of the token just issued and check it, `body` is the token response
+    fn complete_token(&self, token: &mut UserToken, body: &str) {
+        token.expires_on = expires_on_from(token.expires_in);
+        if token.ext_expires_in > 0 {
+            token.ext_expires_on = expires_on_from(token.ext_expires_in);
+        }
+        check_clock_skew(&token.access_token, token.expires_in);
+        self.check_granted_scopes(token);
+        token.raw_response = raw_response(body);
+        // MSAL only caches bearer tokens, a PoP token would be used as one by the other tools
+        if self.shared_cache && !self.pop {
+            identity_service::save_token(self, token);
+        }
+    }
+
+    // Integrated Windows Authentication, the SAML assertion of the Windows user is exchanged for the tokens
+    fn get_token_iwa(&self) -> Result<UserToken, String> {
+        if !cfg!(windows) {
+            return Err(String::from("it's only supported on Windows"));
+        }
+        let username = [&self.account, &self.login_hint].iter()
+            .find(|u| !u.is_empty())
+            .map(|u| u.to_string())
+            .or_else(iwa::windows_username)
+            .ok_or("the Windows account is not a domain account")?;
+        let (grant_type, assertion) = iwa::get_assertion(&self.authority, &username)?;
+
+        let secret = plain_secret(&self.secret);
+        let mut form: HashMap<&str, &str> = HashMap::new();
+        form.insert("grant_type", grant_type);
+        form.insert("assertion", &assertion);
+        form.insert("client_id", &self.client_id);
+        form.insert("scope", &self.scope);
+        if !secret.is_empty() {
+            form.insert("client_secret", &secret);
+        }
+        form.insert("client_info", "1");
+        let claims = merge_client_capabilities(&self.claims, &self.client_capabilities);
+        if !claims.is_empty() {
+            form.insert("claims", &claims);
+        }
+        let req_cnf = self.req_cnf();
+        if self.pop {
+            form.insert("token_type", "pop");
+            form.insert("req_cnf", &req_cnf);
+        }
+        add_extra_params(&mut form, &self.extra_params);
+
+        let resp = send_request(&self.get_token_endpoint(), &form, true);
+        let mut token: UserToken = resp.json().map_err(|e| format!("unable to decode the token response, error is {}", e))?;
+        if !token.error.is_empty() {
+            return Err(explain_error(&resp.body).unwrap_or(token.error));
+        }
+        self.complete_token(&mut token, &resp.body);
+        Ok(token)
+    }
+
+    // `req_cnf` binding the token to the PoP key, empty for bearer tokens
+    fn req_cnf(&self) -> String {
+        if !self.pop {
+            return String::new();
+        }
+        match PopKey::load() {
+            Ok(key) => key.req_cnf(),
+            Err(e) => {
+                print_error!("{}.", e);
+                exit(1);
+            }
+        }
+    }
+
+    // The token is issued even if some scopes are not consented to, which only shows up as 403 from the resource later
+    fn check_granted_scopes(&self, token: &UserToken) {
+        if token.scope.is_empty() {
+            return;
+        }
+        let missing = missing_scopes(&self.scope, &token.scope);
+        if !missing.is_empty() {
+            print_warning!("The token doesn't have {}, granted scopes are '{}'. Consent to them or ask the admin to grant them to the app.",
+                bold(&missing.iter().map(|s| format!("'{}'", s)).collect::<Vec<_>>().join(", ")), token.scope);
+        }
+    }
+
+    pub fn sign_out(&self) {
+        // https://docs.microsoft.com/en-us/azure/active-directory/develop/v2-protocols-oidc#send-a-sign-out-request
+        let url = discover_endpoints(&self.authority, &self.tenant, true)
+            .map(|e| e.end_session_endpoint)
+            .filter(|e| !e.is_empty())
+            .unwrap_or_else(|| format!("{}/{}/oauth2/v2.0/logout", self.authority, self.tenant));
+        open_url(&url, &self.browser);
+    }
+
+    // Invalidate the refresh token on the server, with the revocation endpoint (RFC 7009) in the OpenID configuration if
+    // there is one. AAD doesn't have it, with `revoke_sessions` all sign-in sessions of the user are revoked through
+    // Microsoft Graph instead, which also signs out the other devices and needs `User.RevokeSessions.All` consented to the app
+    pub fn revoke(&self, token: &UserToken, revoke_sessions: bool) -> Result<(), String> {
+        if token.refresh_token.is_empty() {
+            return Err(String::from("There is no refresh token to revoke"));
+        }
+        let revocation_endpoint = discover_endpoints(&self.authority, &self.tenant, true)
+            .map(|e| e.revocation_endpoint)
+            .filter(|e| !e.is_empty());
+        if let Some(url) = revocation_endpoint {
+            let secret = plain_secret(&self.secret);
+            let mut form: HashMap<&str, &str> = HashMap::new();
+            form.insert("token", &token.refresh_token);
+            form.insert("token_type_hint", "refresh_token");
+            form.insert("client_id", &self.client_id);
+            if !secret.is_empty() {
+                form.insert("client_secret", &secret);
+            }
+            let resp = send_request(&url, &form, true);
+            return if resp.is_success() {
+                Ok(())
+            } else {
+                Err(format!("The revocation endpoint returned status {}, {}", resp.status, resp.body.trim()))
+            };
+        }
+
+        // Only on request, it signs the user out of every device and app, not only this one
+        if !revoke_sessions {
+            return Err(String::from("The authority doesn't have a revocation endpoint, use '--revoke-sessions' to revoke all sign-in sessions of the user instead"));
+        }
+        print_warning!("Revoking all sign-in sessions of the user, the other devices and apps are signed out too.");
+        let graph = graph_endpoint(&self.authority);
+        let profile = UserProfile {
+            scope: format!("{}/{}", graph, REVOKE_SESSIONS_SCOPE),
+            claims: String::new(),
+            pop: false,
+            shared_cache: false,
+            ..self.clone()
+        };
+        let graph_token = profile.refresh_token(token)
+            .ok_or_else(|| format!("Unable to get a Microsoft Graph token, '{}' may not be consented to", REVOKE_SESSIONS_SCOPE))?;
+        let url = resolve_url(&format!("{}/v1.0/me/revokeSignInSessions", graph));
+        let headers = [(String::from("Authorization"), format!("Bearer {}", graph_token.access_token))];
+        let resp = http_client().post_json(&url, &json!({}), &headers).map_err(|e| format!("Unable to reach Microsoft Graph, error is {}", e))?;
+        if resp.is_success() {
+            Ok(())
+        } else {
+            Err(format!("Microsoft Graph returned status {}, {}", resp.status, explain_error(&resp.body).unwrap_or_else(|| resp.body.trim().to_owned())))
+        }
+    }
+
+    pub fn get_token_endpoint(&self) -> String {
+        if !self.token_endpoint.is_empty() {
+            return self.token_endpoint.clone();
+        }
+        discover_endpoints(&self.authority, &self.tenant, true)
+            .map(|e| e.token_endpoint)
+            .unwrap_or_else(|| format!("{}/{}/oauth2/v2.0/token", self.authority, self.tenant))
+    }
+
+    pub fn get_device_code_endpoint(&self) -> String {
+        if !self.device_code_endpoint.is_empty() {
+            return self.device_code_endpoint.clone();
+        }
+        discover_endpoints(&self.authority, &self.tenant, true)
+            .map(|e| e.device_authorization_endpoint)
+            .filter(|e| !e.is_empty())
+            .unwrap_or_else(|| format!("{}/{}/oauth2/v2.0/devicecode", self.authority, self.tenant))
+    }
+
+    // Both endpoints are set, the authority and the tenant are not used
+    pub(crate) fn has_endpoints(&self) -> bool {
+        !self.token_endpoint.is_empty() && !self.device_code_endpoint.is_empty()
+    }
+
     pub fn is_valid(&self) -> bool {
         !(self.client_id.is_empty()
-            || self.authority.is_empty()
-            || self.tenant.is_empty()
+            || (!self.has_endpoints() && (self.authority.is_empty() || self.tenant.is_empty()))
             || self.scope.is_empty())
     }
 
+    // The key of the token of the account signed in last
     pub fn get_key(&self) -> String {
-        format!("User:{}\t{}\t{}\t{}", self.client_id, self.tenant, self.authority, self.scope)
+        // The token endpoint replaces the authority
+        let authority = if self.token_endpoint.is_empty() { &self.authority } else { &self.token_endpoint };
+        let key = format!("User:{}\t{}\t{}\t{}", self.client_id, self.tenant, authority, self.scope);
+        let key = if self.extra_params.is_empty() { key } else { format!("{}\t{}", key, extra_params_key(&self.extra_params)) };
+        // PoP tokens are useless as bearer tokens, they're cached apart
+        let key = if self.pop { format!("{}\tPoP", key) } else { key };
+        if self.isolated_cache { format!("{}:{}", self.name, key) } else { key }
+    }
+
+    pub fn get_account_key(&self, account: &str) -> String {
+        format!("{}\t{}", self.get_key(), account)
+    }
+
+    pub fn is_account_key(&self, key: &str) -> bool {
+        match key.strip_prefix(&format!("{}\t", self.get_key())) {
+            // Not the PoP tokens of the same profile
+            Some(rest) => self.pop || (rest != "PoP" && !rest.starts_with("PoP\t")),
+            None => false
+        }
     }
 }
 
@@ -0,0 +1,235 @@
+use std::collections::HashMap;
+
+use crate::profile::Profile;
+use crate::profile::aad_error::explain_error;
+use crate::profile::{AADToken, is_reserved_param, Token, TokenType};
+use crate::profile::discovery::{is_guid, is_tenant_keyword, is_valid_tenant};
+use crate::profile::federated;
+use crate::profile::vscode;
+use crate::profile::jwt::decode_claims;
+use crate::profile::http::HttpResponse;
+
+// The hint for the fields belonging to the other profile type
+fn unknown_field_hint(name: &str) -> &'static str {
+    match name {
+        "Resource" => ", it's only used by 'App' profiles, use 'Scope' instead",
+        "Scope" => ", it's only used by 'User' profiles, use 'Resource' instead",
+        _ => ""
+    }
+}
+
+// Scopes without a resource are permissions of Microsoft Graph
+const GRAPH_RESOURCE: &str = "https://graph.microsoft.com";
+const GRAPH_APP_ID: &str = "00000003-0000-0000-c000-000000000000";
+const OIDC_SCOPES: &[&str] = &["openid", "profile", "email", "offline_access"];
+
+// The app ids of first-party resources, which are the audience of their tokens instead of the resource URI
+const WELL_KNOWN_APPS: &[(&str, &[&str])] = &[
+    (GRAPH_APP_ID, &[GRAPH_RESOURCE]),
+    ("00000002-0000-0000-c000-000000000000", &["https://graph.windows.net"]),
+    ("797f4846-ba00-4fd7-ba43-dac1f8f63013", &["https://management.azure.com", "https://management.core.windows.net"]),
+    ("cfa8b339-82a2-471a-a3c9-0fc0be7a4093", &["https://vault.azure.net"]),
+    ("e406a681-f3d4-42a8-90b6-c2b029497af1", &["https://storage.azure.com"]),
+    ("022907d3-0f1b-48f7-badc-1ba6abab6d66", &["https://database.windows.net"]),
+    ("499b84ac-1321-427f-aa17-267ca6975798", &["https://app.vssps.visualstudio.com"]),
+];
+
+// The app id and the resource URIs of a well-known resource, given either of them
+fn well_known_app(resource: &str) -> Option<(&'static str, &'static [&'static str])> {
+    WELL_KNOWN_APPS.iter().copied().find(|(id, uris)| *id == resource || uris.contains(&resource))
+}
+
+// The resource of the scope, i.e. "https://management.azure.com" of "https://management.azure.com/.default"
+fn scope_resource(scope: &str) -> Option<String> {
+    if OIDC_SCOPES.contains(&scope) {
+        return None;
+    }
+    let resource = match (scope.split_once("://"), scope.rsplit_once('/')) {
+        // No permission, i.e. "https://management.azure.com"
+        (Some((_, rest)), _) if !rest.contains('/') => scope,
+        (_, Some((resource, _))) => resource,
+        _ => GRAPH_RESOURCE
+    };
+    Some(resource.trim_end_matches('/').to_lowercase())
+}
+
+// Resource URIs without a permission, i.e. "https://graph.microsoft.com" instead of "https://graph.microsoft.com/.default"
+fn is_resource_uri(scope: &str) -> bool {
+    match scope.split_once("://") {
+        Some((_, rest)) => !rest.split('/').skip(1).any(|s| !s.is_empty()),
+        None => false
+    }
+}
+
+// The requested scopes not in the granted scopes of the token response, AAD drops the scopes not consented to
+// without an error. OIDC scopes are not always returned, and `.default` gets whatever has been consented to
+pub(crate) fn missing_scopes(requested: &str, granted: &str) -> Vec<String> {
+    // Microsoft Graph permissions are returned without the resource, i.e. "User.Read"
+    let normalize = |s: &str| {
+        let s = s.to_lowercase();
+        [format!("{}/", GRAPH_RESOURCE), format!("{}/", GRAPH_APP_ID)].iter()
+            .find_map(|prefix| s.strip_prefix(prefix.as_str()).map(|p| p.to_owned()))
+            .unwrap_or(s)
+    };
+    let granted: Vec<String> = granted.split_whitespace().map(normalize).collect();
+    requested.split_whitespace()
+        .filter(|s| !OIDC_SCOPES.contains(s) && !s.ends_with("/.default") && !is_resource_uri(s))
+        .filter(|s| !granted.contains(&normalize(s)))
+        .map(|s| s.to_owned())
+        .collect()
+}
+
+// The first line of the AAD error description and the hint, i.e. "AADSTS7000215: Invalid client secret provided. The client secret is wrong, ..."
+fn describe_error(resp: &HttpResponse) -> String {
+    match explain_error(&resp.body) {
+        Some(s) => s.replace('\n', " "),
+        None => format!("status is {}", resp.status)
+    }
+}
+
+// The parameters in `ExtraParams` replacing the ones set by the token flows, they're ignored
+fn check_extra_params(extra_params: &HashMap<String, String>) -> Vec<String> {
+    let mut names: Vec<&String> = extra_params.keys().filter(|k| is_reserved_param(k)).collect();
+    names.sort();
+    names.iter().map(|n| format!("'{}' in 'ExtraParams' is ignored, it's set by tokengen.", n)).collect()
+}
+
+impl Profile {
+    // Check the profile for missing and suspicious values, returns the errors and the warnings
+    pub fn validate(&self) -> (Vec<String>, Vec<String>) {
+        let mut errors = vec![];
+        let mut warnings = vec![];
+        let (required, unknown_fields) = match self {
+            Profile::App(p) => {
+                if !["", "client_secret_post", "client_secret_basic"].contains(&p.token_endpoint_auth_method.as_str()) {
+                    errors.push(format!("Unknown 'TokenEndpointAuthMethod' '{}'.", p.token_endpoint_auth_method));
+                }
+                if is_tenant_keyword(&p.tenant) {
+                    warnings.push(format!("'Tenant' '{}' is not a real tenant, the client credentials flow needs the tenant id or the domain name.", p.tenant));
+                }
+                if !["", "CurrentUser", "LocalMachine"].contains(&p.certificate_store.as_str()) {
+                    errors.push(format!("Unknown 'CertificateStore' '{}', use 'CurrentUser' or 'LocalMachine'.", p.certificate_store));
+                }
+                if !p.certificate.is_empty() && !cfg!(target_os = "windows") {
+                    errors.push(String::from("'Certificate' is only supported on Windows."));
+                }
+                if !p.pkcs11_module.is_empty() && !cfg!(feature = "pkcs11") {
+                    errors.push(String::from("'Pkcs11Module' is not supported, tokengen is built without the 'pkcs11' feature."));
+                }
+                if !p.pkcs11_module.is_empty() && !p.certificate.is_empty() {
+                    warnings.push(String::from("'Certificate' is ignored, the key on the PKCS#11 token is used instead."));
+                }
+                if !p.federated_credential.is_empty() && !federated::SOURCES.contains(&p.federated_credential.to_lowercase().as_str()) {
+                    errors.push(format!("Unknown 'FederatedCredential' '{}', use one of {}.", p.federated_credential, federated::SOURCES.join(", ")));
+                }
+                let has_certificate = !p.certificate.is_empty() || !p.pkcs11_module.is_empty() || !p.federated_credential.is_empty();
+                if has_certificate && p.token_endpoint_auth_method == "client_secret_basic" {
+                    warnings.push(String::from("'TokenEndpointAuthMethod' is ignored, the certificate is used instead of the secret."));
+                }
+                warnings.extend(check_extra_params(&p.extra_params));
+                if p.resource.ends_with("/.default") {
+                    warnings.push(format!("'Resource' '{}' looks like a scope, remove the '/.default' suffix.", p.resource));
+                }
+                let mut required = vec![("ClientId", &p.client_id)];
+                // The certificate replaces the secret
+                if !has_certificate {
+                    required.push(("Secret", &p.secret));
+                }
+                if !p.pkcs11_module.is_empty() {
+                    required.push(("Pkcs11KeyId", &p.pkcs11_key_id));
+                }
+                // The token endpoint replaces the authority and the tenant
+                if p.token_endpoint.is_empty() {
+                    required.extend(vec![("Tenant", &p.tenant), ("Authority", &p.authority)]);
+                }
+                required.push(("Resource", &p.resource));
+                (required, &p.unknown_fields)
+            }
+            Profile::User(p) => {
+                for s in p.scope.split_whitespace().filter(|s| is_resource_uri(s)) {
+                    warnings.push(format!("Scope '{}' has no permission, did you mean '{}/.default'?", s, s.trim_end_matches('/')));
+                }
+                if !p.scope.is_empty() && !p.scope.split_whitespace().any(|s| s == "offline_access") {
+                    warnings.push(String::from("No 'offline_access' in 'Scope', the token cannot be refreshed."));
+                }
+                if p.integrated_windows_auth && !cfg!(windows) {
+                    warnings.push(String::from("'IntegratedWindowsAuth' only works on Windows, the device code is used instead."));
+                }
+                if p.vs_code && p.client_id != vscode::CLIENT_ID {
+                    warnings.push(format!("'VsCode' only works with the client id of VS Code, '{}', remove 'ClientId' to use it.", vscode::CLIENT_ID));
+                }
+                warnings.extend(check_extra_params(&p.extra_params));
+                if p.token_endpoint.is_empty() != p.device_code_endpoint.is_empty() {
+                    warnings.push(String::from("Only one of 'TokenEndpoint' and 'DeviceCodeEndpoint' is set, the other one is under the authority."));
+                }
+                let mut required = vec![("ClientId", &p.client_id)];
+                if !p.has_endpoints() {
+                    required.extend(vec![("Tenant", &p.tenant), ("Authority", &p.authority)]);
+                }
+                required.push(("Scope", &p.scope));
+                (required, &p.unknown_fields)
+            }
+            Profile::Plugin(p) => (vec![("Provider", &p.provider)], &p.unknown_fields),
+            // Not from the configuration file
+            Profile::Custom(_) => return (errors, warnings)
+        };
+        for (name, value) in required {
+            if value.is_empty() {
+                errors.push(format!("'{}' is missing.", name));
+            } else if name == "Tenant" && !is_valid_tenant(value) {
+                warnings.push(format!("'Tenant' '{}' is not a tenant id, a domain name, or one of 'common', 'organizations' and 'consumers'.", value));
+            }
+        }
+        let mut names: Vec<&String> = unknown_fields.keys().collect();
+        names.sort();
+        for name in names {
+            warnings.push(format!("Unknown field '{}'{}.", name, unknown_field_hint(name)));
+        }
+        (errors, warnings)
+    }
+
+    // The `aud` claim of the access token doesn't match the requested resource or scope,
+    // returns the description of the mismatch
+    pub fn check_audience(&self, token: &Token) -> Option<String> {
+        let mut expected: Vec<String> = match self {
+            Profile::App(p) => vec![p.resource.trim_end_matches('/').to_lowercase()],
+            Profile::User(p) => p.scope.split_whitespace().filter_map(scope_resource).collect(),
+            Profile::Plugin(_) | Profile::Custom(_) => vec![]
+        };
+        expected.retain(|r| !r.is_empty());
+        let claims = decode_claims(&token.get_token_string(TokenType::Access))?;
+        let aud = claims["aud"].as_str()?.trim_end_matches('/').to_lowercase();
+        if expected.is_empty() || expected.contains(&aud) {
+            return None;
+        }
+        // The well-known resources match by their app id and all their URIs
+        let known: Vec<_> = expected.iter().filter_map(|r| well_known_app(r)).collect();
+        if known.iter().any(|(id, uris)| *id == aud || uris.contains(&aud.as_str())) {
+            return None;
+        }
+        // The app id of other resources can't be told from the resource URI, a first-party app id is still a mismatch
+        let unknown_app_id = is_guid(&aud) && well_known_app(&aud).is_none();
+        if unknown_app_id && known.is_empty() && !expected.iter().any(|r| is_guid(r)) {
+            return None;
+        }
+        Some(format!("The audience of the token is '{}', but '{}' is requested.", aud, expected[0]))
+    }
+
+    // Try the profile against AAD without any user interaction, `User` profiles only request a device code
+    pub fn test_live(&self) -> Result<(), String> {
+        let resp = match self.resolving(|p| match p {
+            Profile::App(p) => Some(p.send_token_request(true)),
+            Profile::User(p) => Some(p.send_device_code_request(true)),
+            // Plugins may need user interaction
+            Profile::Plugin(_) | Profile::Custom(_) => None
+        }) {
+            Some(r) => r,
+            None => return Ok(())
+        };
+        if resp.is_success() {
+            Ok(())
+        } else {
+            Err(describe_error(&resp))
+        }
+    }
+}
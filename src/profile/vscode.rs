@@ -0,0 +1,23 @@
+use crate::profile::keyring::find_keytar_password;
+
+// The sign-in of the Azure Account extension of VS Code, its refresh token is in the OS keyring, saved by `keytar`
+// under the name of the cloud, it's only accepted from the client id of VS Code,
+// the same as `VisualStudioCodeCredential` of the Azure Identity libraries
+pub const CLIENT_ID: &str = "aebc6443-996d-45c2-90f0-388ff96faa56";
+
+const SERVICE: &str = "VS Code Azure";
+
+// The `azure.cloud` setting of the extension for the cloud of the authority
+fn cloud_name(authority: &str) -> &'static str {
+    if authority.contains("microsoftonline.us") {
+        "AzureUSGovernment"
+    } else if authority.contains("chinacloudapi.cn") {
+        "AzureChinaCloud"
+    } else {
+        "AzureCloud"
+    }
+}
+
+pub fn find_refresh_token(authority: &str) -> Option<String> {
+    find_keytar_password(SERVICE, cloud_name(authority)).filter(|t| !t.is_empty())
+}
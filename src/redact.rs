@@ -0,0 +1,141 @@
+use std::sync::Mutex;
+
+use serde_json::Value;
+
+// Print an error or warning message to stderr, with secrets redacted
+#[macro_export]
+macro_rules! print_error {
+    ($($arg:tt)*) => {
+        eprintln!("{} {}", $crate::color::red("ERROR:"), $crate::redact::redact_text(&format!($($arg)*)))
+    }
+}
+
+#[macro_export]
+macro_rules! print_warning {
+    ($($arg:tt)*) => {
+        eprintln!("{} {}", $crate::color::yellow("WARNING:"), $crate::redact::redact_text(&format!($($arg)*)))
+    }
+}
+
+// Form fields, JSON fields and headers carrying secrets or tokens
+const SECRET_FIELDS: &[&str] = &[
+    "client_secret",
+    "client_assertion",
+    "assertion",
+    "access_token",
+    "id_token",
+    "refresh_token",
+    "device_code",
+    "code",
+    "password",
+    "authorization",
+    "set-cookie",
+];
+
+// Secret values known at runtime, i.e. client secrets from the configuration file or the command line
+static SECRETS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+pub fn register_secret(secret: &str) {
+    // Too short to be a real secret, replacing it would garble the messages
+    if secret.len() >= 8 {
+        SECRETS.lock().unwrap().push(secret.to_owned());
+    }
+}
+
+pub fn is_secret(name: &str) -> bool {
+    SECRET_FIELDS.iter().any(|f| f.eq_ignore_ascii_case(name))
+}
+
+pub fn redact_value(value: &str) -> String {
+    format!("<redacted {} chars>", value.len())
+}
+
+fn redact_json_value(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (k, v) in map.iter_mut() {
+                match v {
+                    Value::String(s) if is_secret(k) => *s = redact_value(s),
+                    _ => redact_json_value(v),
+                }
+            }
+        }
+        Value::Array(a) => a.iter_mut().for_each(redact_json_value),
+        _ => ()
+    }
+}
+
+// Redact secret fields in a JSON document, non-JSON text is returned as is
+pub fn redact_json(text: &str) -> String {
+    match serde_json::from_str::<Value>(text) {
+        Ok(mut v) => {
+            redact_json_value(&mut v);
+            v.to_string()
+        }
+        Err(_) => text.to_owned()
+    }
+}
+
+fn is_value_char(c: char) -> bool {
+    !(c.is_whitespace() || "&,;\"'\\})".contains(c))
+}
+
+// Redact JWTs, i.e. "eyJ..." strings
+fn redact_jwts(text: &str) -> String {
+    let mut ret = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(pos) = rest.find("eyJ") {
+        ret.push_str(&rest[..pos]);
+        let len = rest[pos..].find(|c: char| !(c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.'))
+            .unwrap_or(rest.len() - pos);
+        ret.push_str(&redact_value(&rest[pos..pos + len]));
+        rest = &rest[pos + len..];
+    }
+    ret.push_str(rest);
+    ret
+}
+
+// Redact the value following a secret field name, covers `name=value`, `"name": "value"` and `name: "value"`
+fn redact_field(text: &str, name: &str) -> String {
+    let mut ret = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(pos) = rest.find(name) {
+        let end = pos + name.len();
+        let at_boundary = !rest[..pos].ends_with(|c: char| c.is_alphanumeric() || c == '_');
+        ret.push_str(&rest[..end]);
+        rest = &rest[end..];
+        if !at_boundary {
+            continue;
+        }
+
+        // Skip the closing quote, the separator and the opening quote
+        let after_name = rest.trim_start_matches(['"', '\\']);
+        let after_sep = match after_name.strip_prefix('=').or_else(|| after_name.strip_prefix(':')) {
+            Some(s) => s,
+            None => continue
+        };
+        let value = after_sep.trim_start_matches(['"', '\\', ' ']);
+        let value_len = value.find(|c| !is_value_char(c)).unwrap_or(value.len());
+        // Short values are status codes and alike, not secrets
+        if value_len < 8 || value.starts_with("<redacted") {
+            continue;
+        }
+        ret.push_str(&rest[..rest.len() - value.len()]);
+        ret.push_str(&redact_value(&value[..value_len]));
+        rest = &value[value_len..];
+    }
+    ret.push_str(rest);
+    ret
+}
+
+// Redact everything looks like a secret in an arbitrary message
+pub fn redact_text(text: &str) -> String {
+    let mut ret = redact_jwts(text);
+    for name in SECRET_FIELDS {
+        ret = redact_field(&ret, name);
+    }
+    for secret in SECRETS.lock().unwrap().iter() {
+        ret = ret.replace(secret.as_str(), &redact_value(secret));
+    }
+    ret
+}
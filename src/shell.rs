@@ -0,0 +1,32 @@
+// Quoting and splitting of command lines
+
+// A value single quoted for the shell, so nothing in it is expanded, `shell` can be `fish`, `powershell`, or
+// anything else for POSIX shells like bash
+pub fn quote(shell: &str, value: &str) -> String {
+    match shell {
+        "fish" => format!("'{}'", value.replace('\\', "\\\\").replace('\'', "\\'")),
+        "powershell" => format!("'{}'", value.replace('\'', "''")),
+        _ => format!("'{}'", value.replace('\'', "'\\''")),
+    }
+}
+
+// Split the arguments of an alias like a shell does, with single and double quotes but without escapes
+pub fn split_args(text: &str) -> Vec<String> {
+    let mut args = vec![];
+    let mut arg: Option<String> = None;
+    let mut quote = None;
+    for c in text.chars() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), c) => arg.get_or_insert_with(String::new).push(c),
+            (None, '"') | (None, '\'') => {
+                quote = Some(c);
+                arg.get_or_insert_with(String::new);
+            }
+            (None, c) if c.is_whitespace() => args.extend(arg.take()),
+            (None, c) => arg.get_or_insert_with(String::new).push(c),
+        }
+    }
+    args.extend(arg);
+    args
+}
@@ -0,0 +1,185 @@
+use std::io;
+use std::time::Duration;
+
+use chrono::{Local, TimeZone, Utc};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::widgets::{Block, Borders, Cell, Paragraph, Row, Table, TableState};
+use ratatui::{DefaultTerminal, Frame};
+use serde_json::Value;
+
+use tokengen::profile::{AADToken, decode_claims, Profile, Token, TokenType};
+
+const HELP: &str = "↑/↓ select  a acquire  r refresh  c copy  d decode access/id token  q quit";
+
+struct Dashboard {
+    profiles: Vec<Profile>,
+    // The valid cached tokens of the profiles
+    tokens: Vec<Option<Token>>,
+    state: TableState,
+    // Decode the id token instead of the access token
+    id_token: bool,
+    status: String,
+}
+
+fn profile_type(profile: &Profile) -> &'static str {
+    match profile {
+        Profile::App(_) => "App",
+        Profile::User(_) => "User",
+        Profile::Plugin(_) => "Plugin",
+        Profile::Custom(_) => "Custom",
+    }
+}
+
+// `1h 02m` until the expiration
+fn describe_ttl(expires_on: i64) -> String {
+    let ttl = expires_on - Utc::now().timestamp();
+    if ttl <= 0 {
+        String::from("expired")
+    } else if ttl >= 3600 {
+        format!("{}h {:02}m", ttl / 3600, ttl % 3600 / 60)
+    } else {
+        format!("{}m {:02}s", ttl / 60, ttl % 60)
+    }
+}
+
+#[cfg(feature = "nogui")]
+fn copy(_text: &str) -> Result<(), String> {
+    Err(String::from("Clipboard is not supported by this build."))
+}
+
+#[cfg(not(feature = "nogui"))]
+fn copy(text: &str) -> Result<(), String> {
+    use clipboard::{ClipboardContext, ClipboardProvider};
+
+    ClipboardProvider::new()
+        .and_then(|mut ctx: ClipboardContext| ctx.set_contents(text.to_owned()))
+        .map_err(|e| format!("Unable to copy the token to the clipboard, error is {}.", e))
+}
+
+impl Dashboard {
+    fn reload(&mut self) {
+        self.tokens = self.profiles.iter().map(|p| p.get_cached_token()).collect();
+    }
+
+    fn selected(&self) -> usize {
+        self.state.selected().unwrap_or_default()
+    }
+
+    fn token_type(&self) -> TokenType {
+        if self.id_token { TokenType::Id } else { TokenType::Access }
+    }
+
+    fn render(&mut self, frame: &mut Frame) {
+        let [main, help] = Layout::vertical([Constraint::Min(3), Constraint::Length(2)]).areas(frame.area());
+        let [list, claims] = Layout::horizontal([Constraint::Percentage(45), Constraint::Percentage(55)]).areas(main);
+
+        let rows: Vec<Row> = self.profiles.iter().zip(self.tokens.iter()).map(|(p, t)| {
+            let (expires, style) = match t {
+                Some(t) => (
+                    format!("{} ({})", Local.timestamp(t.get_expires_on(), 0).format("%H:%M:%S"), describe_ttl(t.get_expires_on())),
+                    Style::default().fg(Color::Green),
+                ),
+                None => (String::from("no valid token"), Style::default().fg(Color::DarkGray)),
+            };
+            Row::new(vec![Cell::from(p.get_name().to_owned()), Cell::from(profile_type(p)), Cell::from(expires).style(style)])
+        }).collect();
+        let table = Table::new(rows, [Constraint::Fill(2), Constraint::Length(6), Constraint::Fill(3)])
+            .header(Row::new(vec!["Profile", "Type", "Expires"]).style(Style::default().add_modifier(Modifier::BOLD)))
+            .block(Block::default().borders(Borders::ALL).title(" Profiles "))
+            .row_highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(table, list, &mut self.state);
+
+        let token = self.tokens.get(self.selected()).and_then(|t| t.as_ref()).map(|t| t.get_token_string(self.token_type()));
+        let text = match token.as_deref().map(decode_claims) {
+            None => String::from("No valid cached token, press 'a' to acquire one."),
+            Some(Some(Value::Object(claims))) => {
+                let width = claims.keys().map(|k| k.len()).max().unwrap_or_default();
+                claims.iter().map(|(name, value)| {
+                    let text = match (name.as_str(), value) {
+                        ("iat" | "nbf" | "exp" | "auth_time", Value::Number(n)) => {
+                            format!("{} ({})", n, Local.timestamp(n.as_i64().unwrap_or_default(), 0).format("%Y-%m-%d %H:%M:%S"))
+                        }
+                        (_, Value::String(s)) => s.to_owned(),
+                        _ => value.to_string()
+                    };
+                    format!("{:width$}  {}", name, text, width = width)
+                }).collect::<Vec<_>>().join("\n")
+            }
+            Some(_) => String::from("The token is not a JWT, there are no claims to show."),
+        };
+        let title = if self.id_token { " Id token claims " } else { " Access token claims " };
+        frame.render_widget(Paragraph::new(text).block(Block::default().borders(Borders::ALL).title(title)), claims);
+
+        frame.render_widget(Paragraph::new(format!("{}\n{}", self.status, HELP)), help);
+    }
+
+    // Sign-ins and errors print to the terminal, so the dashboard is left while the profile is working
+    fn suspended<T>(terminal: &mut DefaultTerminal, f: impl FnOnce() -> T) -> io::Result<T> {
+        ratatui::restore();
+        let ret = f();
+        *terminal = ratatui::try_init()?;
+        terminal.clear()?;
+        Ok(ret)
+    }
+
+    fn handle_key(&mut self, terminal: &mut DefaultTerminal, key: KeyCode) -> io::Result<bool> {
+        let i = self.selected();
+        match key {
+            KeyCode::Char('q') | KeyCode::Esc => return Ok(false),
+            KeyCode::Up | KeyCode::Char('k') => self.state.select(Some(i.saturating_sub(1))),
+            KeyCode::Down | KeyCode::Char('j') => self.state.select(Some((i + 1).min(self.profiles.len().saturating_sub(1)))),
+            KeyCode::Char('d') => self.id_token = !self.id_token,
+            KeyCode::Char('a') => {
+                let p = self.profiles[i].clone();
+                Dashboard::suspended(terminal, || p.get_token())?;
+                self.status = format!("Profile '{}' is ready.", p.get_name());
+            }
+            KeyCode::Char('r') => {
+                let p = self.profiles[i].clone();
+                self.status = match Dashboard::suspended(terminal, || p.force_refresh())? {
+                    Some(_) => format!("Profile '{}' has been refreshed.", p.get_name()),
+                    None => format!("Profile '{}' has no cached token to refresh, press 'a' to sign in.", p.get_name()),
+                };
+            }
+            KeyCode::Char('c') => {
+                self.status = match self.tokens[i].as_ref() {
+                    Some(t) => match copy(&t.get_token_string(self.token_type())) {
+                        Ok(_) => String::from("Token has been copied to the clipboard."),
+                        Err(e) => e,
+                    },
+                    None => String::from("No valid cached token to copy."),
+                };
+            }
+            _ => (),
+        }
+        self.reload();
+        Ok(true)
+    }
+}
+
+// A dashboard of the profiles and their cached tokens, redrawn every second so the countdowns are live
+pub fn run(profiles: Vec<Profile>) -> io::Result<()> {
+    let mut dashboard = Dashboard {
+        profiles,
+        tokens: vec![],
+        state: TableState::default().with_selected(Some(0)),
+        id_token: false,
+        status: String::new(),
+    };
+    dashboard.reload();
+    let mut terminal = ratatui::try_init()?;
+    let result = (|| loop {
+        terminal.draw(|frame| dashboard.render(frame))?;
+        if event::poll(Duration::from_secs(1))? {
+            if let Event::Key(key) = event::read()? {
+                if key.kind == KeyEventKind::Press && !dashboard.handle_key(&mut terminal, key.code)? {
+                    return Ok(());
+                }
+            }
+        }
+    })();
+    ratatui::restore();
+    result
+}
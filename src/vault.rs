@@ -0,0 +1,58 @@
+use std::process::exit;
+
+use serde_json::{json, Value};
+
+use tokengen::profile::http_client;
+use tokengen::redact::register_secret;
+
+// Where and how to log in to Vault, `address` and `namespace` default to `VAULT_ADDR` and `VAULT_NAMESPACE`
+pub struct VaultLogin<'a> {
+    pub address: &'a str,
+    pub namespace: &'a str,
+    pub mount: &'a str,
+    pub role: &'a str,
+}
+
+// The message of a Vault error response, i.e. `{"errors":["role \"dev\" could not be found"]}`
+fn describe_error(body: &str) -> String {
+    serde_json::from_str::<Value>(body).ok()
+        .and_then(|v| v["errors"].as_array().map(|e| e.iter().filter_map(|s| s.as_str()).collect::<Vec<_>>().join(", ")))
+        .filter(|s| !s.is_empty())
+        .unwrap_or_else(|| body.trim().to_owned())
+}
+
+// Exchange the AAD token with the JWT auth method of Vault, returns the Vault token and its lease duration in seconds
+pub fn login(vault: &VaultLogin, jwt: &str) -> (String, u64) {
+    let url = format!("{}/v1/auth/{}/login", vault.address.trim_end_matches('/'), vault.mount.trim_matches('/'));
+    // Vault uses the default role of the mount if no role is given
+    let mut body = json!({ "jwt": jwt });
+    if !vault.role.is_empty() {
+        body["role"] = json!(vault.role);
+    }
+    let mut headers = vec![];
+    if !vault.namespace.is_empty() {
+        headers.push((String::from("X-Vault-Namespace"), vault.namespace.to_owned()));
+    }
+    let resp = match http_client().post_json(&url, &body, &headers) {
+        Ok(r) => r,
+        Err(e) => {
+            print_error!("Unable to reach Vault at '{}', error is {}.", vault.address, e);
+            exit(1);
+        }
+    };
+    if !resp.is_success() {
+        print_error!("Vault rejected the token, status {}, error is {}", resp.status, describe_error(&resp.body));
+        exit(2);
+    }
+    let auth = resp.json::<Value>().map(|v| v["auth"].clone()).unwrap_or_default();
+    match auth["client_token"].as_str() {
+        Some(t) => {
+            register_secret(t);
+            (t.to_owned(), auth["lease_duration"].as_u64().unwrap_or_default())
+        }
+        None => {
+            print_error!("Vault didn't return a token, the response is {}", resp.body);
+            exit(2);
+        }
+    }
+}
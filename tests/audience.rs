@@ -0,0 +1,47 @@
+// The audience of the token is checked against the requested resource
+use serde_json::json;
+
+use tokengen::profile::{AppProfile, AppToken, Profile, Token, UserProfile};
+
+const GRAPH_APP_ID: &str = "00000003-0000-0000-c000-000000000000";
+const ARM_APP_ID: &str = "797f4846-ba00-4fd7-ba43-dac1f8f63013";
+
+fn token(aud: &str) -> Token {
+    let claims = base64::encode_config(json!({ "aud": aud }).to_string(), base64::URL_SAFE_NO_PAD);
+    Token::App(AppToken::new(&format!("eyJhbGciOiJub25lIn0.{}.", claims), 4102444800))
+}
+
+fn app(resource: &str) -> Profile {
+    Profile::App(AppProfile { resource: resource.to_owned(), ..Default::default() })
+}
+
+fn user(scope: &str) -> Profile {
+    Profile::User(UserProfile { scope: scope.to_owned(), ..Default::default() })
+}
+
+#[test]
+fn matching_audience() {
+    assert!(app("https://management.azure.com").check_audience(&token("https://management.azure.com/")).is_none());
+    assert!(app("https://management.azure.com/").check_audience(&token("https://management.core.windows.net/")).is_none());
+    assert!(app("https://management.azure.com").check_audience(&token(ARM_APP_ID)).is_none());
+    assert!(app(ARM_APP_ID).check_audience(&token("https://management.azure.com")).is_none());
+    assert!(user("openid offline_access User.Read").check_audience(&token(GRAPH_APP_ID)).is_none());
+    assert!(user("https://graph.microsoft.com/.default").check_audience(&token("https://graph.microsoft.com")).is_none());
+    // The app id of other resources can't be told from the URI
+    assert!(app("api://contoso-api").check_audience(&token("6a0c6b5e-5d1e-4b4a-9f55-0c1b7a1d2e3f")).is_none());
+    // Nothing to check against
+    assert!(user("openid offline_access").check_audience(&token(GRAPH_APP_ID)).is_none());
+}
+
+#[test]
+fn mismatched_audience() {
+    // A Graph token used for ARM
+    let e = app("https://management.azure.com").check_audience(&token(GRAPH_APP_ID)).unwrap();
+    assert!(e.contains(GRAPH_APP_ID) && e.contains("https://management.azure.com"), "{}", e);
+    assert!(user("https://management.azure.com/user_impersonation").check_audience(&token("https://graph.microsoft.com")).is_some());
+    assert!(user("User.Read").check_audience(&token(ARM_APP_ID)).is_some());
+    // Another app than the well-known one
+    assert!(app("https://vault.azure.net").check_audience(&token("6a0c6b5e-5d1e-4b4a-9f55-0c1b7a1d2e3f")).is_some());
+    assert!(app("6a0c6b5e-5d1e-4b4a-9f55-0c1b7a1d2e3f").check_audience(&token("0f1e2d3c-4b5a-6978-8796-a5b4c3d2e1f0")).is_some());
+    assert!(app("api://contoso-api").check_audience(&token("api://fabrikam-api")).is_some());
+}
@@ -0,0 +1,37 @@
+// Cached tokens are evicted by `CachePolicy`
+use std::{fs, thread};
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::Utc;
+
+use tokengen::profile::{AppToken, CachePolicy, Profile, set_cache_policy, Token};
+
+mod common;
+use common::{with_temp_cache, StubProvider};
+
+#[test]
+fn least_recently_used_tokens_are_evicted() {
+    with_temp_cache("evict", |cache_dir| {
+        set_cache_policy(CachePolicy { max_entries: 2, ..Default::default() });
+
+        let now = Utc::now().timestamp();
+        let profile = |key: &'static str, expires_on: i64| {
+            Profile::custom(key, Arc::new(StubProvider::new(key, move |_| Token::App(AppToken::new(key, expires_on)))))
+        };
+        // `used` expires first, but it's read again after `unused` is cached
+        for (key, expires_on) in [("used", now + 600), ("unused", now + 3600), ("used", now + 600), ("new", now + 1800)] {
+            profile(key, expires_on).get_token();
+            thread::sleep(Duration::from_millis(20));
+        }
+        // One file per cached token
+        let entries: Vec<String> = fs::read_dir(cache_dir.join("cache")).unwrap()
+            .map(|f| fs::read_to_string(f.unwrap().path()).unwrap())
+            .collect();
+        assert_eq!(entries.len(), 2);
+        let cache = entries.concat();
+        assert!(cache.contains("Custom:used"));
+        assert!(!cache.contains("Custom:unused"));
+        assert!(cache.contains("Custom:new"));
+    });
+}
@@ -0,0 +1,56 @@
+// The CBOR codec of the binary cache format
+use serde_json::{json, Value};
+
+use tokengen::profile::{decode_cbor, encode_cbor};
+
+fn round_trip(value: Value) {
+    assert_eq!(decode_cbor(&encode_cbor(&value)).unwrap(), value);
+}
+
+#[test]
+fn values_round_trip() {
+    for value in [json!(null), json!(true), json!(false), json!(""), json!("token"), json!("jeton €"), json!([]), json!({})] {
+        round_trip(value);
+    }
+    for n in [0u64, 23, 24, 255, 256, 65535, 65536, u32::MAX as u64, u32::MAX as u64 + 1, u64::MAX] {
+        round_trip(json!(n));
+    }
+    for n in [-1i64, -24, -25, -256, -257, i64::MIN] {
+        round_trip(json!(n));
+    }
+    round_trip(json!(0.5));
+    round_trip(json!(-1.25e10));
+    round_trip(json!("x".repeat(70000)));
+    round_trip(json!({
+        "key": "AppToken:client:https://contoso.com/api",
+        "token": {"App": {"access_token": "eyJ0eXAiOiJKV1QifQ", "expires_on": 4102444800i64, "scope": ["a", "b"], "raw": null}},
+    }));
+}
+
+#[test]
+fn known_encodings() {
+    // https://www.rfc-editor.org/rfc/rfc8949#appendix-A
+    assert_eq!(encode_cbor(&json!(10)), [0x0a]);
+    assert_eq!(encode_cbor(&json!(1000)), [0x19, 0x03, 0xe8]);
+    assert_eq!(encode_cbor(&json!(-100)), [0x38, 0x63]);
+    assert_eq!(encode_cbor(&json!("a")), [0x61, 0x61]);
+    assert_eq!(encode_cbor(&json!([1, [2, 3]])), [0x82, 0x01, 0x82, 0x02, 0x03]);
+    assert_eq!(encode_cbor(&json!({"a": 1})), [0xa1, 0x61, 0x61, 0x01]);
+    // A 32-bit float written by other encoders
+    assert_eq!(decode_cbor(&[0xfa, 0x47, 0xc3, 0x50, 0x00]).unwrap(), json!(100000.0));
+}
+
+#[test]
+fn invalid_data() {
+    assert!(decode_cbor(&[]).is_err());
+    // Truncated string and map
+    assert!(decode_cbor(&[0x63, 0x61, 0x62]).is_err());
+    assert!(decode_cbor(&[0xa1, 0x61, 0x61]).is_err());
+    assert!(decode_cbor(&[0x01, 0x02]).is_err());
+    // Byte strings and non-string keys are not supported
+    assert!(decode_cbor(&[0x41, 0x00]).is_err());
+    assert!(decode_cbor(&[0xa1, 0x01, 0x02]).is_err());
+    assert!(decode_cbor(&[0x62, 0xff, 0xfe]).is_err());
+    // JSON is not CBOR
+    assert!(decode_cbor(br#"{"key": "value"}"#).is_err());
+}
@@ -0,0 +1,57 @@
+// Helpers shared by the integration tests, each test binary only uses some of them
+#![allow(dead_code)]
+use std::{env, fs};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use tokengen::profile::{Token, TokenProvider};
+
+// Run `f` with the cache in a temporary directory, keeps the tests away from the real cache
+pub fn with_temp_cache<T>(name: &str, f: impl FnOnce(&Path) -> T) -> T {
+    let cache_dir = env::temp_dir().join(format!("tokengen-test-{}-{}", name, std::process::id()));
+    env::set_var("TOKENGEN_CACHE_DIR", &cache_dir);
+    let ret = f(&cache_dir);
+    fs::remove_dir_all(&cache_dir).ok();
+    ret
+}
+
+type Acquire = Box<dyn Fn(usize) -> Token + Send + Sync>;
+type Refresh = Box<dyn Fn(&Token) -> Option<Token> + Send + Sync>;
+
+// A provider issuing the tokens of `acquire`, which gets the number of the acquisition, and never refreshing them
+// unless `with_refresh` is used, the tokens are cached by the name
+pub struct StubProvider {
+    name: String,
+    acquire: Acquire,
+    refresh: Refresh,
+    pub acquired: AtomicUsize,
+}
+
+impl StubProvider {
+    pub fn new(name: &str, acquire: impl Fn(usize) -> Token + Send + Sync + 'static) -> Self {
+        StubProvider {
+            name: name.to_owned(),
+            acquire: Box::new(acquire),
+            refresh: Box::new(|_| None),
+            acquired: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn with_refresh(self, refresh: impl Fn(&Token) -> Option<Token> + Send + Sync + 'static) -> Self {
+        StubProvider { refresh: Box::new(refresh), ..self }
+    }
+}
+
+impl TokenProvider for StubProvider {
+    fn acquire(&self) -> Token {
+        (self.acquire)(self.acquired.fetch_add(1, Ordering::SeqCst) + 1)
+    }
+
+    fn refresh(&self, token: &Token) -> Option<Token> {
+        (self.refresh)(token)
+    }
+
+    fn cache_key(&self) -> String {
+        format!("Custom:{}-{}", self.name, std::process::id())
+    }
+}
@@ -0,0 +1,38 @@
+// Processes and threads saving tokens at the same time keep each other's refresh tokens
+use std::{fs, thread};
+use std::sync::Arc;
+
+use chrono::Utc;
+use serde_json::json;
+
+use tokengen::profile::{Profile, Token, UserToken};
+
+mod common;
+use common::{with_temp_cache, StubProvider};
+
+fn sign_in(id: usize) -> Token {
+    let token: UserToken = serde_json::from_value(json!({
+        "access_token": format!("at-{}", id),
+        "refresh_token": format!("rt-{}", id),
+        "expires_on": Utc::now().timestamp() + 3600,
+    })).unwrap();
+    Token::User(token)
+}
+
+#[test]
+fn concurrent_sign_ins() {
+    with_temp_cache("concurrent", |cache_dir| {
+        let handles: Vec<_> = (0..8).map(|id| thread::spawn(move || {
+            let name = format!("concurrent-{}", id);
+            Profile::custom(&name, Arc::new(StubProvider::new(&name, move |_| sign_in(id)))).get_token();
+        })).collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+
+        let stored = fs::read_to_string(cache_dir.join("refresh_tokens.json")).unwrap();
+        for id in 0..8 {
+            assert!(stored.contains(&format!("\"rt-{}\"", id)), "rt-{} is lost", id);
+        }
+    });
+}
@@ -0,0 +1,29 @@
+// Custom token providers added by library users
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+
+use chrono::Utc;
+
+use tokengen::profile::{AADToken, AppToken, Profile, Token, TokenType};
+
+mod common;
+use common::{with_temp_cache, StubProvider};
+
+#[test]
+fn custom_provider_token_is_cached() {
+    with_temp_cache("custom", |_| {
+        let provider = Arc::new(StubProvider::new("counting", |n| {
+            Token::App(AppToken::new(&format!("token-{}", n), Utc::now().timestamp() + 3600))
+        }));
+        let profile = Profile::custom("counting", provider.clone());
+        assert_eq!(profile.get_name(), "counting");
+
+        let first = profile.get_token();
+        let second = profile.get_token();
+
+        assert_eq!(first.get_token_string(TokenType::Access), "token-1");
+        assert_eq!(second.get_token_string(TokenType::Access), "token-1");
+        assert_eq!(provider.acquired.load(Ordering::SeqCst), 1);
+        assert!(profile.remove_cached_token());
+    });
+}
@@ -0,0 +1,56 @@
+// The token flows through a custom `HttpClient`, without any server
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+
+use tokengen::profile::{AADToken, AppProfile, HttpClient, HttpResponse, set_http_client, TokenType};
+
+mod common;
+use common::with_temp_cache;
+
+#[derive(Default)]
+struct FakeClient {
+    requests: Mutex<Vec<(String, HashMap<String, String>)>>,
+}
+
+impl HttpClient for FakeClient {
+    fn post_form(&self, url: &str, form: &HashMap<&str, &str>, _headers: &[(String, String)]) -> Result<HttpResponse, Box<dyn Error>> {
+        let form = form.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        self.requests.lock().unwrap().push((url.to_owned(), form));
+        Ok(HttpResponse {
+            status: 200,
+            headers: vec![],
+            body: String::from(r#"{"access_token": "fake-token", "expires_on": "4102444800"}"#),
+        })
+    }
+
+    fn get(&self, _url: &str) -> Result<HttpResponse, Box<dyn Error>> {
+        Err("Unexpected GET request".into())
+    }
+}
+
+#[test]
+fn custom_http_client() {
+    with_temp_cache("http", |_| {
+        let client = Arc::new(FakeClient::default());
+        set_http_client(client.clone());
+
+        let profile = AppProfile {
+            name: String::from("app"),
+            client_id: String::from("client"),
+            secret: String::from("Passw0rdxyz"),
+            tenant: String::from("contoso.com"),
+            authority: String::from("https://login.microsoftonline.com"),
+            resource: String::from("https://contoso.com/api"),
+            ..Default::default()
+        };
+        let token = profile.get_token();
+
+        assert_eq!(token.get_token_string(TokenType::Access), "fake-token");
+        let requests = client.requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].0, "https://login.microsoftonline.com/contoso.com/oauth2/token");
+        assert_eq!(requests[0].1["grant_type"], "client_credentials");
+        assert_eq!(requests[0].1["resource"], "https://contoso.com/api");
+    });
+}
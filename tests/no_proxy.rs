@@ -0,0 +1,19 @@
+// Hosts excluded from the proxy by `NoProxy` or `NO_PROXY`
+use tokengen::profile::is_no_proxy;
+
+#[test]
+fn no_proxy_matching() {
+    let no_proxy = "localhost, .contoso.com,*.fabrikam.net,example.org,10.0.0.0/8,::1";
+    assert!(is_no_proxy("localhost:8080", no_proxy));
+    assert!(is_no_proxy("login.contoso.com", no_proxy));
+    assert!(is_no_proxy("contoso.com", no_proxy));
+    assert!(is_no_proxy("a.fabrikam.net:443", no_proxy));
+    assert!(is_no_proxy("sub.example.org", no_proxy));
+    assert!(is_no_proxy("10.1.2.3", no_proxy));
+    assert!(is_no_proxy("[::1]:443", no_proxy));
+    assert!(!is_no_proxy("login.microsoftonline.com", no_proxy));
+    assert!(!is_no_proxy("notcontoso.com", no_proxy));
+    assert!(!is_no_proxy("11.0.0.1", no_proxy));
+    assert!(is_no_proxy("anything", "*"));
+    assert!(!is_no_proxy("anything", ""));
+}
@@ -0,0 +1,44 @@
+// Nothing is sent to the network with `--offline`, whatever the command
+use std::fs;
+use std::process::Command;
+
+use httpmock::MockServer;
+use serde_json::json;
+
+#[test]
+fn warm_offline_sends_no_request() {
+    let server = MockServer::start();
+    // Any request
+    let requests = server.mock(|_, then| {
+        then.status(200).json_body(json!({"access_token": "app-token", "expires_in": 3600}));
+    });
+
+    // Keep the test away from the real configuration and cache
+    let home = std::env::temp_dir().join(format!("tokengen-offline-{}", std::process::id()));
+    let config_dir = home.join("tokengen");
+    fs::create_dir_all(&config_dir).unwrap();
+    fs::write(config_dir.join("config.json"), json!({
+        "Profiles": [{
+            "Name": "offline",
+            "Type": "App",
+            "ClientId": "00000000-0000-0000-0000-000000000001",
+            "Secret": "not-a-secret",
+            "Tenant": "offline.contoso.com",
+            "Authority": server.base_url(),
+            "Resource": "https://management.azure.com/",
+        }]
+    }).to_string()).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_tokengen"))
+        .args(["warm", "--offline", "--profiles", "offline"])
+        .env("XDG_CONFIG_HOME", &home)
+        .env("HOME", &home)
+        .env("TOKENGEN_CACHE_DIR", home.join("cache"))
+        .output()
+        .unwrap();
+    fs::remove_dir_all(&home).ok();
+
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("offline mode"), "{}", String::from_utf8_lossy(&output.stderr));
+    requests.assert_hits(0);
+}
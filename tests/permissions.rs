@@ -0,0 +1,33 @@
+// The configuration and cache directories and files are only accessible by the current user
+#![cfg(unix)]
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use tokengen::permissions::{create_private_dir, shared_mode, write_private_file};
+
+fn mode(path: &Path) -> u32 {
+    fs::metadata(path).unwrap().permissions().mode() & 0o777
+}
+
+#[test]
+fn private_directories_and_files() {
+    let root = std::env::temp_dir().join(format!("tokengen-permissions-{}", std::process::id()));
+    let dir = root.join("tokens");
+    create_private_dir(&dir).unwrap();
+    assert_eq!(mode(&root), 0o700);
+    assert_eq!(mode(&dir), 0o700);
+
+    let filename = dir.join("token.json");
+    write_private_file(&filename, b"{}").unwrap();
+    assert_eq!(mode(&filename), 0o600);
+    assert_eq!(shared_mode(&filename), None);
+
+    // Existing directories are left untouched
+    fs::set_permissions(&root, fs::Permissions::from_mode(0o755)).unwrap();
+    create_private_dir(&dir).unwrap();
+    assert_eq!(mode(&root), 0o755);
+    assert_eq!(shared_mode(&root), Some(0o755));
+
+    fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,53 @@
+// Refresh tokens are kept out of the cached tokens
+use std::fs;
+use std::sync::{Arc, Mutex};
+
+use chrono::Utc;
+use serde_json::json;
+
+use tokengen::profile::{AADToken, Profile, Token, TokenType, UserToken};
+
+mod common;
+use common::{with_temp_cache, StubProvider};
+
+// Expired, so every `get_token` has to refresh
+fn user_token(access_token: &str, refresh_token: &str) -> Token {
+    let token: UserToken = serde_json::from_value(json!({
+        "access_token": access_token,
+        "refresh_token": refresh_token,
+        "expires_on": Utc::now().timestamp() - 60,
+    })).unwrap();
+    Token::User(token)
+}
+
+#[test]
+fn refresh_tokens_are_stored_separately() {
+    with_temp_cache("rt", |dir| {
+        let read = |name: &str| fs::read_to_string(dir.join(name)).unwrap_or_default();
+
+        let refreshed_with = Arc::new(Mutex::new(vec![]));
+        let refreshed = refreshed_with.clone();
+        let provider = StubProvider::new("expired", |_| user_token("at-1", "rt-1")).with_refresh(move |token| {
+            let refresh_token = serde_json::to_value(token).unwrap()["User"]["refresh_token"].as_str()?.to_owned();
+            refreshed.lock().unwrap().push(refresh_token);
+            Some(user_token("at-2", "rt-2"))
+        });
+        let profile = Profile::custom("expired", Arc::new(provider));
+
+        assert_eq!(profile.get_token().get_token_string(TokenType::Access), "at-1");
+        let cached = || fs::read_dir(dir.join("cache")).unwrap()
+            .map(|f| fs::read_to_string(f.unwrap().path()).unwrap())
+            .collect::<String>();
+        assert!(cached().contains("at-1"));
+        assert!(!cached().contains("rt-1"));
+        assert!(read("refresh_tokens.json").contains("rt-1"));
+
+        // The expired token is still refreshed with the stored refresh token
+        assert_eq!(profile.get_token().get_token_string(TokenType::Access), "at-2");
+        assert_eq!(*refreshed_with.lock().unwrap(), vec!["rt-1"]);
+        assert!(read("refresh_tokens.json").contains("rt-2"));
+
+        assert!(profile.remove_cached_token());
+        assert!(!read("refresh_tokens.json").contains("rt-2"));
+    });
+}
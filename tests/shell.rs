@@ -0,0 +1,56 @@
+// Values quoted for the shells and the arguments of aliases split like a shell does
+use std::process::Command;
+
+use tokengen::shell::{quote, split_args};
+
+const VALUES: &[&str] = &["", "eyJ0eXAi.eyJhdWQi.c2ln", "it's", "C:\\Users\\someone\\", "\\'", "a b\tc\nd", "$HOME `id` $(id) \"x\""];
+
+#[test]
+fn quote_bash() {
+    assert_eq!(quote("bash", "it's"), "'it'\\''s'");
+    assert_eq!(quote("bash", "C:\\temp\\"), "'C:\\temp\\'");
+    assert_eq!(quote("bash", "a b\tc"), "'a b\tc'");
+    assert_eq!(quote("bash", ""), "''");
+    // The shell gets back the same value
+    if Command::new("sh").arg("-c").arg("true").status().is_err() {
+        return;
+    }
+    for v in VALUES {
+        let output = Command::new("sh").arg("-c").arg(format!("printf %s {}", quote("sh", v))).output().unwrap();
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), *v);
+    }
+}
+
+#[test]
+fn quote_fish() {
+    assert_eq!(quote("fish", "it's"), "'it\\'s'");
+    assert_eq!(quote("fish", "C:\\temp\\"), "'C:\\\\temp\\\\'");
+    assert_eq!(quote("fish", "\\'"), "'\\\\\\''");
+    assert_eq!(quote("fish", "a b\tc"), "'a b\tc'");
+    assert_eq!(quote("fish", "$HOME"), "'$HOME'");
+}
+
+#[test]
+fn quote_powershell() {
+    assert_eq!(quote("powershell", "it's"), "'it''s'");
+    // Backslashes and backticks are literal in single quoted strings
+    assert_eq!(quote("powershell", "C:\\temp\\"), "'C:\\temp\\'");
+    assert_eq!(quote("powershell", "`$env:HOME"), "'`$env:HOME'");
+    assert_eq!(quote("powershell", "a b\tc"), "'a b\tc'");
+}
+
+#[test]
+fn split_alias_args() {
+    assert_eq!(split_args("-p  contoso\t-k a "), ["-p", "contoso", "-k", "a"]);
+    assert_eq!(split_args("-p 'Contoso Prod' --scope \"User.Read openid\""), ["-p", "Contoso Prod", "--scope", "User.Read openid"]);
+    // Quotes in the other kind of quotes are kept, and quoted parts join the argument around them
+    assert_eq!(split_args("--claims '{\"acrs\":\"c1\"}' -p \"it's\""), ["--claims", "{\"acrs\":\"c1\"}", "-p", "it's"]);
+    assert_eq!(split_args("--name=\"a b\"c"), ["--name=a bc"]);
+    // No escapes, backslashes are kept
+    assert_eq!(split_args("-o C:\\temp\\out.env"), ["-o", "C:\\temp\\out.env"]);
+    // Empty quoted arguments are kept, an unterminated quote runs to the end
+    assert_eq!(split_args("-p '' --prompt \"\""), ["-p", "", "--prompt", ""]);
+    assert_eq!(split_args("-p 'a b"), ["-p", "a b"]);
+    assert!(split_args("").is_empty());
+    assert!(split_args(" \t ").is_empty());
+}